@@ -0,0 +1,136 @@
+// Compares case-insensitive search by case folding, as ci_search does now, with the lowercasing
+// implementation it replaced, which allocated a lowercased copy of every line. The line counts
+// show the difference folding makes: lowercasing never finds "Straße" for STRASSE, or "İstanbul"
+// for istanbul.
+//
+// Usage: cargo bench --bench ci_search -- [LINES] [ROUNDS]
+//
+// Each query is searched for in a generated log of LINES lines (default 200000), ROUNDS times
+// (default 5), and the fastest round is reported.
+
+use std::env;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use chapter12_command_line_program as minigrep;
+use minigrep::fold::CaseFolded;
+
+// The previous ci_search, kept here for comparison.
+fn lowercase_ci_search<'a>(query: &str, contents: &'a str) -> Vec<&'a str> {
+    let query = query.to_lowercase();
+    contents
+        .lines()
+        .filter(|line| line.to_lowercase().contains(query.as_str()))
+        .collect()
+}
+
+fn folded_ci_search<'a>(query: &str, contents: &'a str) -> Vec<&'a str> {
+    let query = CaseFolded::new(query);
+    contents
+        .lines()
+        .filter(|line| query.is_match(line))
+        .collect()
+}
+
+fn main() {
+    // `cargo bench` passes `--bench` along, which is not meant for us.
+    let args: Vec<usize> = env::args()
+        .skip(1)
+        .filter_map(|arg| arg.parse().ok())
+        .collect();
+    let lines = args.first().copied().unwrap_or(200_000);
+    let rounds = args.get(1).copied().unwrap_or(5).max(1);
+
+    let contents = generate_log(lines);
+    let megabytes = contents.len() as f64 / 1_000_000.0;
+    println!(
+        "{} lines, {:.1} MB, best of {} rounds\n",
+        lines, megabytes, rounds
+    );
+    println!(
+        "{:<12} {:<22} {:>8} {:>10} {:>10}",
+        "query", "implementation", "lines", "time", "MB/s"
+    );
+
+    for query in [
+        "TIMEOUT",
+        "user=alice",
+        "STRASSE",
+        "istanbul",
+        "no such text",
+    ] {
+        type Search = for<'a> fn(&str, &'a str) -> Vec<&'a str>;
+        let implementations: [(&str, Search); 2] = [
+            ("to_lowercase per line", lowercase_ci_search),
+            ("case folding", folded_ci_search),
+        ];
+        for (name, search) in implementations {
+            let mut found = 0;
+            let best = (0..rounds)
+                .map(|_| {
+                    let start = Instant::now();
+                    found = black_box(search(black_box(query), black_box(&contents))).len();
+                    start.elapsed()
+                })
+                .min()
+                .unwrap_or(Duration::ZERO);
+            println!(
+                "{:<12} {:<22} {:>8} {:>8.1}ms {:>10.0}",
+                query,
+                name,
+                found,
+                best.as_secs_f64() * 1000.0,
+                megabytes / best.as_secs_f64()
+            );
+        }
+    }
+
+    // The whole public API, which also works out where every hit is.
+    let start = Instant::now();
+    let found = black_box(minigrep::ci_search(black_box("timeout"), &contents)).len();
+    println!(
+        "\nminigrep::ci_search with match positions: {} lines in {:.1}ms",
+        found,
+        start.elapsed().as_secs_f64() * 1000.0
+    );
+}
+
+// Mostly ASCII log lines, with some non-ASCII text mixed in.
+fn generate_log(lines: usize) -> String {
+    let users = ["alice", "bob", "Jürgen", "Zoë", "Ólafur", "Ayşe"];
+    let places = [
+        "Berlin",
+        "Straße des 17. Juni",
+        "İstanbul",
+        "Reykjavík",
+        "São Paulo",
+    ];
+    let messages = [
+        "request completed",
+        "request TIMEOUT after 30s",
+        "cache miss for key",
+        "connection reset by peer",
+        "Größe überschritten",
+    ];
+
+    let mut log = String::new();
+    // A fixed xorshift sequence keeps the input the same from run to run.
+    let mut state = 0x2545_F491_4F6C_DD1D_u64;
+    for i in 0..lines {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        let pick = |n: usize, shift: u32| (state >> shift) as usize % n;
+        log.push_str(&format!(
+            "2024-03-{:02}T12:{:02}:{:02} INFO [worker-{}] user={} location=\"{}\" {}\n",
+            1 + i % 28,
+            pick(60, 3),
+            pick(60, 11),
+            pick(16, 19),
+            users[pick(users.len(), 27)],
+            places[pick(places.len(), 35)],
+            messages[pick(messages.len(), 43)],
+        ));
+    }
+    log
+}
@@ -0,0 +1,332 @@
+use std::fmt;
+
+use crate::color::ColorChoice;
+
+// Hand-rolled parsing of the command line, in the style of grep:
+//
+//   minigrep [OPTIONS] PATTERN [FILE...]
+//
+// Short flags can be combined (`-in` is `-i -n`), and `--` ends the options so that a pattern
+// starting with a dash can be searched for. Options with a value take it either attached
+// (`--include=*.rs`) or as the next argument (`--include *.rs`).
+
+pub const USAGE: &str = "Usage: minigrep [OPTIONS] PATTERN [FILE...]
+
+Options:
+  -i, --ignore-case         match regardless of case
+  -s, --case-sensitive      match case exactly, even if CASE_INSENSITIVE is set
+  -E, --regex               treat PATTERN as a regular expression
+  -v, --invert-match        select lines that do not match
+  -w, --word-regexp         only match whole words
+  -x, --line-regexp         only match whole lines
+  -n, --line-number         prefix each line with its line number
+  -b, --byte-offset         prefix each line with its byte offset in the file
+  -c, --count               print only the number of selected lines
+  -l, --files-with-matches  print only the names of files with selected lines
+  -L, --files-without-match print only the names of files without selected lines
+  -m, --max-count NUM       stop reading a file after NUM selected lines
+  -q, --quiet, --silent     print nothing; only the exit status tells if a line was selected
+  -A, --after-context NUM   print NUM lines after each selected line
+  -B, --before-context NUM  print NUM lines before each selected line
+  -C, --context NUM         print NUM lines before and after each selected line
+      --color[=WHEN]        highlight matches, file names and line numbers; WHEN is auto (the
+                            default, color only on a terminal), always or never
+  -H, --with-filename       prefix each line with its file name
+      --no-filename         never prefix lines with the file name
+  -r, --recursive           search directories recursively
+      --include GLOB        only search files whose name matches GLOB
+      --exclude GLOB        skip files whose name matches GLOB
+      --exclude-dir GLOB    skip directories whose name matches GLOB
+      --no-ignore           search files ignored by .gitignore too
+  -h, --help                print this help
+  -V, --version             print the version
+
+With no FILE, read standard input, or the current directory with -r. A FILE of - is standard
+input.
+
+Exit status is 0 if a line was selected (with -L, if a file was listed), 1 if not and 2 if
+there was an error, unless -q was given and a line was selected.
+
+Environment:
+  CASE_INSENSITIVE          if set, match regardless of case unless -s is given";
+
+// Everything the command line asked for. `Config::new` turns this into a ready-to-run `Config`.
+#[derive(Debug, Default, PartialEq)]
+pub struct Args {
+    pub pattern: String,
+    // Empty when no FILE was given.
+    pub paths: Vec<String>,
+    // `None` leaves the choice to the CASE_INSENSITIVE environment variable.
+    pub ignore_case: Option<bool>,
+    pub regex: bool,
+    pub invert_match: bool,
+    pub word_regexp: bool,
+    pub line_regexp: bool,
+    pub line_number: bool,
+    pub byte_offset: bool,
+    pub count: bool,
+    pub files_with_matches: bool,
+    pub files_without_match: bool,
+    pub max_count: Option<usize>,
+    pub quiet: bool,
+    // -A and -B take precedence over -C, whatever the order they are given in.
+    pub after_context: Option<usize>,
+    pub before_context: Option<usize>,
+    pub context: Option<usize>,
+    pub color: ColorChoice,
+    // `None` prefixes lines with the file name only when there is more than one file to search.
+    pub with_filename: Option<bool>,
+    pub recursive: bool,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub exclude_dir: Vec<String>,
+    pub no_ignore: bool,
+}
+
+// Why parsing stopped without a search to run. `Help` and `Version` are not failures, but they
+// end the program all the same.
+#[derive(Debug, PartialEq)]
+pub enum ArgsError {
+    Help,
+    Version,
+    Usage(String),
+}
+
+impl fmt::Display for ArgsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArgsError::Help => write!(f, "{}", USAGE),
+            ArgsError::Version => write!(f, "minigrep {}", env!("CARGO_PKG_VERSION")),
+            ArgsError::Usage(message) => write!(
+                f,
+                "{}\nUsage: minigrep [OPTIONS] PATTERN [FILE...]\nTry 'minigrep --help' for more information.",
+                message
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ArgsError {}
+
+// Short flags and the long options they stand for.
+const SHORT: &[(char, &str)] = &[
+    ('i', "ignore-case"),
+    ('s', "case-sensitive"),
+    ('E', "regex"),
+    ('v', "invert-match"),
+    ('w', "word-regexp"),
+    ('x', "line-regexp"),
+    ('n', "line-number"),
+    ('b', "byte-offset"),
+    ('c', "count"),
+    ('l', "files-with-matches"),
+    ('L', "files-without-match"),
+    ('m', "max-count"),
+    ('q', "quiet"),
+    ('A', "after-context"),
+    ('B', "before-context"),
+    ('C', "context"),
+    ('H', "with-filename"),
+    ('r', "recursive"),
+    ('h', "help"),
+    ('V', "version"),
+];
+
+// Long options that take a value.
+const WITH_VALUE: &[&str] = &[
+    "max-count",
+    "after-context",
+    "before-context",
+    "context",
+    "include",
+    "exclude",
+    "exclude-dir",
+];
+
+// Long options whose value is optional, so it can only be given attached.
+const OPTIONAL_VALUE: &[&str] = &["color", "colour"];
+
+// Parse the arguments after the program name.
+pub fn parse(args: &[String]) -> Result<Args, ArgsError> {
+    let mut parsed = Args::default();
+    let mut positional = Vec::new();
+    let mut options_done = false;
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        if options_done || arg == "-" || !arg.starts_with('-') {
+            positional.push(arg.clone());
+        } else if arg == "--" {
+            options_done = true;
+        } else if let Some(long) = arg.strip_prefix("--") {
+            let (name, attached) = match long.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (long, None),
+            };
+            let value = if WITH_VALUE.contains(&name) {
+                match attached {
+                    Some(value) => Some(value),
+                    None => Some(next_value(&mut args, name)?),
+                }
+            } else if OPTIONAL_VALUE.contains(&name) {
+                attached
+            } else if attached.is_some() {
+                return Err(usage(&format!("option '--{}' doesn't take a value", name)));
+            } else {
+                None
+            };
+            apply(&mut parsed, name, value)?;
+        } else {
+            for (i, flag) in arg.char_indices().skip(1) {
+                let name = SHORT
+                    .iter()
+                    .find(|(short, _)| *short == flag)
+                    .map(|(_, name)| *name)
+                    .ok_or_else(|| usage(&format!("unknown option '-{}'", flag)))?;
+                if WITH_VALUE.contains(&name) {
+                    // The rest of the argument is the value, or the next argument if nothing is left.
+                    let value = match &arg[i + flag.len_utf8()..] {
+                        "" => next_value(&mut args, name)?,
+                        rest => rest.to_string(),
+                    };
+                    apply(&mut parsed, name, Some(value))?;
+                    break;
+                }
+                apply(&mut parsed, name, None)?;
+            }
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    parsed.pattern = positional.next().ok_or_else(|| usage("missing PATTERN"))?;
+    parsed.paths = positional.collect();
+    Ok(parsed)
+}
+
+fn next_value<'a>(
+    args: &mut impl Iterator<Item = &'a String>,
+    name: &str,
+) -> Result<String, ArgsError> {
+    args.next()
+        .cloned()
+        .ok_or_else(|| usage(&format!("option '--{}' needs a value", name)))
+}
+
+// `value` is always `Some` for the options in WITH_VALUE, may be for those in OPTIONAL_VALUE and
+// is `None` for the rest.
+fn apply(args: &mut Args, name: &str, value: Option<String>) -> Result<(), ArgsError> {
+    match name {
+        "ignore-case" => args.ignore_case = Some(true),
+        "case-sensitive" => args.ignore_case = Some(false),
+        "regex" => args.regex = true,
+        "invert-match" => args.invert_match = true,
+        "word-regexp" => args.word_regexp = true,
+        "line-regexp" => args.line_regexp = true,
+        "line-number" => args.line_number = true,
+        "byte-offset" => args.byte_offset = true,
+        "count" => args.count = true,
+        "files-with-matches" => args.files_with_matches = true,
+        "files-without-match" => args.files_without_match = true,
+        "max-count" => args.max_count = Some(number(name, value)?),
+        "quiet" | "silent" => args.quiet = true,
+        "after-context" => args.after_context = Some(number(name, value)?),
+        "before-context" => args.before_context = Some(number(name, value)?),
+        "context" => args.context = Some(number(name, value)?),
+        "color" | "colour" => {
+            let when = value.as_deref().unwrap_or("auto");
+            args.color = ColorChoice::parse(when)
+                .ok_or_else(|| usage(&format!("invalid argument '{}' for '--{}'", when, name)))?;
+        }
+        "with-filename" => args.with_filename = Some(true),
+        "no-filename" => args.with_filename = Some(false),
+        "recursive" => args.recursive = true,
+        "include" => args.include.extend(value),
+        "exclude" => args.exclude.extend(value),
+        "exclude-dir" => args.exclude_dir.extend(value),
+        "no-ignore" => args.no_ignore = true,
+        "help" => return Err(ArgsError::Help),
+        "version" => return Err(ArgsError::Version),
+        _ => return Err(usage(&format!("unknown option '--{}'", name))),
+    }
+    Ok(())
+}
+
+fn number(name: &str, value: Option<String>) -> Result<usize, ArgsError> {
+    let value = value.unwrap_or_default();
+    value
+        .parse()
+        .map_err(|_| usage(&format!("invalid number '{}' for '--{}'", value, name)))
+}
+
+fn usage(message: &str) -> ArgsError {
+    ArgsError::Usage(format!("minigrep: {}", message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_strs(args: &[&str]) -> Result<Args, ArgsError> {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        parse(&args)
+    }
+
+    #[test]
+    fn combined_flags_and_terminator() {
+        let args = parse_strs(&["-invm3", "--count", "--", "-pattern", "poem.txt"]).unwrap();
+
+        assert_eq!(Some(true), args.ignore_case);
+        assert!(args.line_number && args.invert_match && args.count);
+        assert_eq!(Some(3), args.max_count);
+        assert!(!args.recursive);
+        assert_eq!("-pattern", args.pattern);
+        assert_eq!(vec!["poem.txt"], args.paths);
+    }
+
+    #[test]
+    fn reports_usage_errors() {
+        assert_eq!(Err(ArgsError::Help), parse_strs(&["-ih", "x", "y"]));
+        assert!(
+            matches!(parse_strs(&["-Z", "x", "y"]), Err(ArgsError::Usage(m)) if m.contains("'-Z'"))
+        );
+        assert!(
+            matches!(parse_strs(&["-n"]), Err(ArgsError::Usage(m)) if m.contains("missing PATTERN"))
+        );
+        assert!(matches!(
+            parse_strs(&["--color=sometimes", "x", "y"]),
+            Err(ArgsError::Usage(_))
+        ));
+        assert!(matches!(
+            parse_strs(&["--count=3", "x", "y"]),
+            Err(ArgsError::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn options_with_values() {
+        let args = parse_strs(&[
+            "-rHB2",
+            "--color",
+            "--include=*.rs",
+            "--include",
+            "*.toml",
+            "--exclude-dir",
+            "target",
+            "x",
+            "src",
+            "Cargo.toml",
+        ])
+        .unwrap();
+
+        assert_eq!(vec!["*.rs", "*.toml"], args.include);
+        assert_eq!(vec!["target"], args.exclude_dir);
+        assert_eq!(Some(2), args.before_context);
+        assert_eq!(ColorChoice::Auto, args.color);
+        assert_eq!(Some(true), args.with_filename);
+        assert_eq!(vec!["src", "Cargo.toml"], args.paths);
+        assert!(matches!(
+            parse_strs(&["x", "y", "--exclude"]),
+            Err(ArgsError::Usage(m)) if m.contains("needs a value")
+        ));
+    }
+}
@@ -0,0 +1,92 @@
+use std::env;
+use std::fmt::Display;
+use std::io;
+use std::io::prelude::*;
+use std::io::IsTerminal;
+use std::ops::Range;
+
+// ANSI colors for highlighting output, the same ones grep uses by default.
+
+pub const MATCH: &str = "\x1b[1;31m";
+pub const FILENAME: &str = "\x1b[35m";
+pub const LINE_NUMBER: &str = "\x1b[32m";
+pub const SEPARATOR: &str = "\x1b[36m";
+const RESET: &str = "\x1b[0m";
+
+// What `--color` asked for.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ColorChoice {
+    // Color only when writing to a terminal, and NO_COLOR is not set.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    pub fn parse(text: &str) -> Option<ColorChoice> {
+        match text {
+            "auto" => Some(ColorChoice::Auto),
+            "always" => Some(ColorChoice::Always),
+            "never" => Some(ColorChoice::Never),
+            _ => None,
+        }
+    }
+
+    pub fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Auto => io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none(),
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+// Write `text`, in `color` if `enabled`.
+pub fn write(
+    out: &mut impl Write,
+    enabled: bool,
+    color: &str,
+    text: impl Display,
+) -> io::Result<()> {
+    if enabled {
+        write!(out, "{}{}{}", color, text, RESET)
+    } else {
+        write!(out, "{}", text)
+    }
+}
+
+// Write `line` with the byte ranges in `hits` highlighted.
+pub fn highlight(
+    out: &mut impl Write,
+    enabled: bool,
+    line: &str,
+    hits: &[Range<usize>],
+) -> io::Result<()> {
+    if !enabled {
+        return write!(out, "{}", line);
+    }
+    let mut written = 0;
+    for hit in hits.iter().filter(|hit| !hit.is_empty()) {
+        write!(out, "{}", &line[written..hit.start])?;
+        write(out, true, MATCH, &line[hit.clone()])?;
+        written = hit.end;
+    }
+    write!(out, "{}", &line[written..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn highlights_hits() {
+        let mut out = Vec::new();
+        highlight(&mut out, true, "a rust, trust", &[2..6, 7..7, 9..13]).unwrap();
+
+        assert_eq!(
+            "a \x1b[1;31mrust\x1b[0m, t\x1b[1;31mrust\x1b[0m",
+            String::from_utf8(out).unwrap()
+        );
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::VecDeque;
+
+// Context lines around the selected ones, for -A, -B and -C.
+//
+// Lines are fed in one at a time, so only the last `before` lines are ever held. Ranges of
+// context that touch or overlap are merged, and a separator marks each gap between them.
+
+// One line of output once context is taken into account.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ContextLine<T> {
+    // A selected line and its number.
+    Match(usize, T),
+    // A line shown for context and its number.
+    Context(usize, T),
+    // Printed as `--` between groups of lines that are not adjacent.
+    Separator,
+}
+
+pub struct Context<T> {
+    before: usize,
+    after: usize,
+    // The last `before` unselected lines, in case a selected line follows.
+    buffered: VecDeque<(usize, T)>,
+    // How many more lines to show after the last selected one.
+    after_left: usize,
+    last_shown: Option<usize>,
+}
+
+impl<T> Context<T> {
+    pub fn new(before: usize, after: usize) -> Context<T> {
+        Context {
+            before,
+            after,
+            buffered: VecDeque::with_capacity(before),
+            after_left: 0,
+            last_shown: None,
+        }
+    }
+
+    // Whether `push` would use the line, so callers can skip preparing lines that would only be
+    // dropped.
+    pub fn is_needed(&self, selected: bool) -> bool {
+        selected || self.after_left > 0 || self.before > 0
+    }
+
+    // Whether more lines are due after the last selected one.
+    pub fn is_after_pending(&self) -> bool {
+        self.after_left > 0
+    }
+
+    // Feed the next line, numbered from 1, and get back the lines that are now ready to show.
+    pub fn push(&mut self, number: usize, line: T, selected: bool) -> Vec<ContextLine<T>> {
+        let mut ready = Vec::new();
+
+        if selected {
+            let first = self.buffered.front().map_or(number, |(n, _)| *n);
+            let has_context = self.before > 0 || self.after > 0;
+            if has_context && self.last_shown.is_some_and(|last| first > last + 1) {
+                ready.push(ContextLine::Separator);
+            }
+            ready.extend(
+                self.buffered
+                    .drain(..)
+                    .map(|(n, line)| ContextLine::Context(n, line)),
+            );
+            ready.push(ContextLine::Match(number, line));
+            self.after_left = self.after;
+            self.last_shown = Some(number);
+        } else if self.after_left > 0 {
+            ready.push(ContextLine::Context(number, line));
+            self.after_left -= 1;
+            self.last_shown = Some(number);
+        } else if self.before > 0 {
+            if self.buffered.len() == self.before {
+                self.buffered.pop_front();
+            }
+            self.buffered.push_back((number, line));
+        }
+
+        ready
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_overlapping_ranges() {
+        let mut context = Context::new(1, 1);
+        let selected = [false, true, false, true, false, false, false, true];
+        let shown: Vec<_> = selected
+            .iter()
+            .enumerate()
+            .flat_map(|(i, &selected)| context.push(i + 1, (), selected))
+            .collect();
+
+        assert_eq!(
+            vec![
+                ContextLine::Context(1, ()),
+                ContextLine::Match(2, ()),
+                ContextLine::Context(3, ()),
+                ContextLine::Match(4, ()),
+                ContextLine::Context(5, ()),
+                ContextLine::Separator,
+                ContextLine::Context(7, ()),
+                ContextLine::Match(8, ()),
+            ],
+            shown
+        );
+    }
+}
@@ -0,0 +1,190 @@
+use std::ops::Range;
+
+// Case-insensitive matching by Unicode case folding.
+//
+// Lowercasing is not enough to compare text regardless of case: the German `ß` lowercases to
+// itself, so "STRASSE" never matches "straße", and the Turkish `İ` lowercases to an `i` with a
+// combining dot above, so "istanbul" never matches "İSTANBUL". Folding maps every character to
+// the lowercase form of its uppercase form instead, which turns `ß` into "ss", the final `ς` into
+// `σ`, `ſ` into `s` and ligatures like `ﬁ` into their letters. There are two exceptions: `İ`
+// folds to a plain `i`, like `I` does, so a search does not have to know where the text came
+// from, and the capital `ẞ` folds to "ss" like the `ß` it lowercases to.
+//
+// A hit has to start and end on character boundaries of the line, so "s" does not match half of
+// a `ß`.
+
+// The case folding of one character. No character folds to more than three.
+#[derive(Debug, Clone)]
+pub struct Fold {
+    chars: [char; 3],
+    len: u8,
+    next: u8,
+}
+
+impl Iterator for Fold {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        if self.next == self.len {
+            return None;
+        }
+        self.next += 1;
+        Some(self.chars[usize::from(self.next - 1)])
+    }
+}
+
+pub fn fold(c: char) -> Fold {
+    let mut folded = Fold {
+        chars: [c.to_ascii_lowercase(), '\0', '\0'],
+        len: 1,
+        next: 0,
+    };
+    if c.is_ascii() {
+        return folded;
+    }
+    match c {
+        'İ' => {
+            folded.chars[0] = 'i';
+            return folded;
+        }
+        'ẞ' => {
+            folded.chars = ['s', 's', '\0'];
+            folded.len = 2;
+            return folded;
+        }
+        _ => {}
+    }
+
+    folded.len = 0;
+    for c in c.to_uppercase().flat_map(char::to_lowercase) {
+        folded.chars[usize::from(folded.len)] = c;
+        folded.len += 1;
+    }
+    folded
+}
+
+// A query folded once up front, so matching a line never allocates.
+#[derive(Debug, Clone)]
+pub struct CaseFolded {
+    query: Vec<char>,
+    // The folded query as bytes, if it is all ASCII, to compare runs of ASCII text with directly.
+    ascii: Option<Vec<u8>>,
+}
+
+impl CaseFolded {
+    pub fn new(query: &str) -> CaseFolded {
+        let query: Vec<char> = query.chars().flat_map(fold).collect();
+        let ascii = query
+            .iter()
+            .all(char::is_ascii)
+            .then(|| query.iter().map(|&c| c as u8).collect());
+        CaseFolded { query, ascii }
+    }
+
+    pub fn is_match(&self, line: &str) -> bool {
+        self.find_at(line, 0).is_some()
+    }
+
+    // Byte range of the first hit starting at or after `start`. An empty query matches once, at
+    // the start of the line.
+    pub fn find_at(&self, line: &str, start: usize) -> Option<Range<usize>> {
+        let Some(&first) = self.query.first() else {
+            return (start == 0).then_some(0..0);
+        };
+        // ASCII characters only ever fold to ASCII, so a hit can only start at one of the two
+        // cases of an ASCII first character, or at the start of a non-ASCII character. 0xFF never
+        // appears in UTF-8 at all.
+        let (lower, upper) = if first.is_ascii() {
+            (first as u8, (first as u8).to_ascii_uppercase())
+        } else {
+            (0xFF, 0xFF)
+        };
+        let bytes = line.as_bytes();
+        let mut from = start;
+        while let Some(offset) = bytes[from..]
+            .iter()
+            .position(|&b| b == lower || b == upper || b >= 0xC0)
+        {
+            let at = from + offset;
+            from = at + 1;
+            if !bytes[at].is_ascii() && first.is_ascii() {
+                // Only a handful of non-ASCII characters can start a hit then.
+                match line[at..].chars().next() {
+                    Some(c) if folds_to_ascii(c) => {}
+                    _ => continue,
+                }
+            }
+            // Runs of ASCII text can be compared with an ASCII query without decoding them.
+            if let (true, Some(query)) = (bytes[at].is_ascii(), &self.ascii) {
+                match bytes.get(at..at + query.len()) {
+                    Some(window) if window.eq_ignore_ascii_case(query) => {
+                        return Some(at..at + query.len())
+                    }
+                    Some(window) if window.is_ascii() => continue,
+                    _ => {}
+                }
+            }
+            if let Some(end) = self.match_at(line, at) {
+                return Some(at..end);
+            }
+        }
+        None
+    }
+
+    // The end of a hit starting right at `at`, if there is one.
+    fn match_at(&self, line: &str, at: usize) -> Option<usize> {
+        let mut matched = 0;
+        for (i, c) in line[at..].char_indices() {
+            for folded in fold(c) {
+                // A query that runs out halfway through a character does not match it.
+                if self.query.get(matched) != Some(&folded) {
+                    return None;
+                }
+                matched += 1;
+            }
+            if matched == self.query.len() {
+                return Some(at + i + c.len_utf8());
+            }
+        }
+        None
+    }
+}
+
+// The non-ASCII characters whose folding starts with an ASCII character, so the others can be
+// skipped without folding them. A test checks this against `fold` for every character.
+fn folds_to_ascii(c: char) -> bool {
+    matches!(
+        c,
+        'ß' | 'İ' | 'ı' | 'ſ' | 'ǰ' | 'ẖ'..='ẚ' | 'ẞ' | '\u{212A}' | 'ﬀ'..='ﬆ'
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn folds_special_cases() {
+        let hit = |query: &str, line: &str| CaseFolded::new(query).find_at(line, 0);
+
+        assert_eq!(Some(4..11), hit("STRASSE", "Die Straße"));
+        assert_eq!(Some(0..7), hit("straße", "STRASSE"));
+        assert_eq!(Some(0..9), hit("istanbul", "İSTANBUL"));
+        assert_eq!(Some(0..16), hit("ΟΔΥΣΣΕΥΣ", "οδυσσευς"));
+        assert_eq!(Some(2..6), hit("FIT", "a ﬁt"));
+        assert_eq!(None, hit("s", "ß"));
+        assert_eq!(Some(0..3), hit("ss", "ẞ"));
+    }
+
+    #[test]
+    fn folding_is_consistent() {
+        for c in (0..=char::MAX as u32).filter_map(char::from_u32) {
+            let folded: Vec<char> = fold(c).collect();
+            let refolded: Vec<char> = folded.iter().copied().flat_map(fold).collect();
+            assert_eq!(folded, refolded, "{:?} does not fold in one step", c);
+            if !c.is_ascii() {
+                assert_eq!(folded[0].is_ascii(), folds_to_ascii(c), "{:?}", c);
+            }
+        }
+    }
+}
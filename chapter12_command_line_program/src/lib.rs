@@ -1,69 +1,585 @@
 use std::env;
-use std::error::Error;
-use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::ops::{ControlFlow, Range};
+use std::path::{Path, PathBuf};
+
+pub mod args;
+pub mod color;
+pub mod context;
+pub mod fold;
+pub mod regex;
+pub mod walk;
+
+use args::ArgsError;
+use context::{Context, ContextLine};
+use fold::CaseFolded;
+use regex::{is_word_char, Regex};
+use walk::{with_path, Walker};
+
+// What to print for the selected lines.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputMode {
+    // The lines themselves, with any context.
+    Lines,
+    // How many there are in each file (-c).
+    Count,
+    // The names of files that have some (-l).
+    FilesWithMatches,
+    // The names of files that have none (-L).
+    FilesWithoutMatch,
+    // Nothing at all (-q).
+    Quiet,
+}
+
+// How the search went, which decides the exit status.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Selected,
+    NothingSelected,
+    Error,
+}
+
+impl Status {
+    // The same exit codes grep uses.
+    pub fn code(self) -> i32 {
+        match self {
+            Status::Selected => 0,
+            Status::NothingSelected => 1,
+            Status::Error => 2,
+        }
+    }
+}
+
+// Files with a NUL byte this close to the start are taken to be binary and skipped.
+const BINARY_CHECK_LEN: usize = 8192;
 
 pub struct Config {
     pub query: String,
-    pub filename: String,
+    // `-` stands for standard input.
+    pub paths: Vec<String>,
     pub case_sensitive: bool,
+    // Set in regex mode (`-E`), compiled once up front so every line reuses it.
+    pub pattern: Option<Regex>,
+    pub invert_match: bool,
+    pub boundary: Boundary,
+    pub line_number: bool,
+    pub byte_offset: bool,
+    pub mode: OutputMode,
+    // Stop reading a file after this many selected lines.
+    pub max_count: Option<usize>,
+    // Lines to show before and after each selected line.
+    pub before_context: usize,
+    pub after_context: usize,
+    // Whether to highlight the output with ANSI colors.
+    pub color: bool,
+    // `None` means only when searching more than one file.
+    pub with_filename: Option<bool>,
+    pub recursive: bool,
+    // Decides which files under a directory are searched with -r.
+    pub walker: Walker,
 }
 
 impl Config {
     // This is the idiomatic way to construct new objects.
-    pub fn new(args: &[String]) -> Result<Config, &str> {
-        if args.len() < 3 {
-            return Err("Not enough input arguments.");
+    // `args` is the full command line, program name included, as returned by env::args().
+    pub fn new(args: &[String]) -> Result<Config, ArgsError> {
+        let args = args::parse(args.get(1..).unwrap_or_default())?;
+
+        // Flags win over the environment. Without one, case_sensitive is false exactly when the
+        // CASE_INSENSITIVE variable is set, so we check if the Result is Err to get the inverse.
+        let case_sensitive = match args.ignore_case {
+            Some(ignore_case) => !ignore_case,
+            None => env::var("CASE_INSENSITIVE").is_err(),
+        };
+
+        // -x wins over -w, as in grep.
+        let boundary = if args.line_regexp {
+            Boundary::Line
+        } else if args.word_regexp {
+            Boundary::Word
+        } else {
+            Boundary::Anywhere
+        };
+
+        let pattern = if !args.regex {
+            None
+        } else {
+            let compile = |source: &str| {
+                let compiled = if case_sensitive {
+                    Regex::new(source)
+                } else {
+                    Regex::case_insensitive(source)
+                };
+                compiled.map_err(|e| ArgsError::Usage(format!("minigrep: {}", e)))
+            };
+            // The pattern is checked on its own first: wrapped, `a)|(b` would pass as valid,
+            // and error positions would not point into what the user typed.
+            let compiled = compile(&args.pattern)?;
+            // Anchoring the pattern lets it try every way of covering the whole line, not just the
+            // one it would find first.
+            Some(match boundary {
+                Boundary::Line => compile(&format!("^(?:{})$", args.pattern))?,
+                _ => compiled,
+            })
+        };
+
+        let mut walker = Walker::new().gitignore(!args.no_ignore);
+        for glob in &args.include {
+            walker = walker.include(glob);
+        }
+        for glob in &args.exclude {
+            walker = walker.exclude(glob);
+        }
+        for glob in &args.exclude_dir {
+            walker = walker.exclude_dir(glob);
         }
 
-        let query = args[1].clone();
-        let filename = args[2].clone();
+        // Like grep, search standard input by default, or the current directory with -r.
+        let paths = match (args.paths.is_empty(), args.recursive) {
+            (false, _) => args.paths,
+            (true, false) => vec![String::from("-")],
+            (true, true) => vec![String::from(".")],
+        };
 
-        // If env variable is set, case_sensitive should evaluate to false.
-        // We therefore check if Result is Err to get this inverse behavior.
-        let case_sensitive = env::var("CASE_INSENSITIVE").is_err();
+        // Quiet beats listing files, which beats counting.
+        let mode = if args.quiet {
+            OutputMode::Quiet
+        } else if args.files_with_matches {
+            OutputMode::FilesWithMatches
+        } else if args.files_without_match {
+            OutputMode::FilesWithoutMatch
+        } else if args.count {
+            OutputMode::Count
+        } else {
+            OutputMode::Lines
+        };
 
         Ok(Config {
-            query,
-            filename,
+            query: args.pattern,
+            paths,
             case_sensitive,
+            pattern,
+            invert_match: args.invert_match,
+            boundary,
+            line_number: args.line_number,
+            byte_offset: args.byte_offset,
+            mode,
+            max_count: args.max_count,
+            before_context: args.before_context.or(args.context).unwrap_or(0),
+            after_context: args.after_context.or(args.context).unwrap_or(0),
+            color: args.color.enabled(),
+            with_filename: args.with_filename,
+            recursive: args.recursive,
+            walker,
         })
     }
 }
 
-// If Ok, returns the unit type.
-// Else, returns the trait object Box<dyn Error> which may be any type that implements the Error
-// trait.
-pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
-    let contents = fs::read_to_string(config.filename)?;
+// Files that cannot be searched are reported on stderr as we go and do not stop the others.
+pub fn run(config: Config) -> Status {
+    let with_filename = config.with_filename.unwrap_or(
+        config.paths.len() > 1 || (config.recursive && Path::new(&config.paths[0]).is_dir()),
+    );
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut found = false;
+    let mut failed = false;
+    let mut search = |file: io::Result<PathBuf>| {
+        let result = file.and_then(|path| {
+            search_path(&config, &path, with_filename, &mut out).map_err(|e| with_path(&path, e))
+        });
+        match result {
+            Ok(file_found) => found |= file_found,
+            // Whoever was reading our output has gone, so there is no point in going on.
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                failed = true;
+                return ControlFlow::Break(());
+            }
+            Err(e) => {
+                eprintln!("minigrep: {}", e);
+                failed = true;
+            }
+        }
+        // With -q the first selected line settles the exit status.
+        if found && config.mode == OutputMode::Quiet {
+            ControlFlow::Break(())
+        } else {
+            ControlFlow::Continue(())
+        }
+    };
+
+    for path in config.paths.iter().map(PathBuf::from) {
+        let flow = if path == Path::new("-") || !path.is_dir() {
+            search(Ok(path))
+        } else if config.recursive {
+            config.walker.walk(&path, &mut search)
+        } else {
+            search(Err(with_path(&path, io::Error::other("Is a directory"))))
+        };
+        if flow.is_break() {
+            break;
+        }
+    }
 
-    let res = if config.case_sensitive {
-        search(&config.query, &contents)
+    if failed && !(found && config.mode == OutputMode::Quiet) {
+        Status::Error
+    } else if found {
+        Status::Selected
     } else {
-        ci_search(&config.query, &contents)
+        Status::NothingSelected
+    }
+}
+
+// Returns whether the file counts towards a successful exit status: if it had a selected line,
+// or with -L, if it was listed.
+fn search_path(
+    config: &Config,
+    path: &Path,
+    with_filename: bool,
+    out: &mut impl Write,
+) -> io::Result<bool> {
+    if path == Path::new("-") {
+        search_reader(
+            config,
+            io::stdin().lock(),
+            "(standard input)",
+            with_filename,
+            out,
+        )
+    } else {
+        let file = BufReader::new(File::open(path)?);
+        search_reader(
+            config,
+            file,
+            &path.display().to_string(),
+            with_filename,
+            out,
+        )
+    }
+}
+
+// Search line by line, so memory use is bounded by the longest line rather than the size of the
+// input. Invalid UTF-8 is replaced with U+FFFD instead of failing the whole file.
+fn search_reader<R: BufRead>(
+    config: &Config,
+    mut reader: R,
+    name: &str,
+    with_filename: bool,
+    out: &mut impl Write,
+) -> io::Result<bool> {
+    if is_binary(reader.fill_buf()?) {
+        return Ok(false);
+    }
+    let filename = with_filename.then_some(name);
+
+    let folded_query = CaseFolded::new(&config.query);
+    let find = |line: &str| match &config.pattern {
+        Some(pattern) => collect_hits(line, config.boundary, |start| pattern.find_at(line, start)),
+        None if config.case_sensitive => find_all(&config.query, line, config.boundary),
+        None => collect_hits(line, config.boundary, |start| {
+            folded_query.find_at(line, start)
+        }),
+    };
+
+    let mut buf = Vec::new();
+    let mut line_number = 0;
+    let mut byte_offset = 0;
+    let mut count = 0;
+    let mut context = Context::new(config.before_context, config.after_context);
+    // When only the file name or the exit status is wanted, the first selected line is enough.
+    let max_count = match config.mode {
+        OutputMode::Lines | OutputMode::Count => config.max_count,
+        _ => Some(1),
     };
+    loop {
+        let limit_reached = max_count.is_some_and(|max| count >= max);
+        // Past the limit, we only keep reading for the context after the last selected line.
+        if limit_reached && !context.is_after_pending() {
+            break;
+        }
+        buf.clear();
+        let read = reader.read_until(b'\n', &mut buf)?;
+        if read == 0 {
+            break;
+        }
+        line_number += 1;
+        let line_offset = byte_offset;
+        byte_offset += read;
+        let line = String::from_utf8_lossy(trim_newline(&buf));
+        let ranges = find(&line);
+
+        // With -v, the lines that do not match are the ones we want.
+        let selected = ranges.is_empty() == config.invert_match && !limit_reached;
+        if selected {
+            count += 1;
+        }
+        if config.mode != OutputMode::Lines || !context.is_needed(selected) {
+            continue;
+        }
+
+        let line = OwnedLine {
+            text: line.into_owned(),
+            byte_offset: line_offset,
+            ranges,
+        };
+        for shown in context.push(line_number, line, selected) {
+            match shown {
+                ContextLine::Match(number, line) => {
+                    write_line(out, config, filename, &line.as_match(number), ':')?
+                }
+                ContextLine::Context(number, line) => {
+                    write_line(out, config, filename, &line.as_match(number), '-')?
+                }
+                ContextLine::Separator => {
+                    color::write(out, config.color, color::SEPARATOR, "--")?;
+                    writeln!(out)?;
+                }
+            }
+        }
+    }
 
-    for line in res {
-        println!("{}", line);
+    match config.mode {
+        OutputMode::Count => {
+            if let Some(filename) = filename {
+                color::write(out, config.color, color::FILENAME, filename)?;
+                color::write(out, config.color, color::SEPARATOR, ':')?;
+            }
+            writeln!(out, "{}", count)?;
+        }
+        OutputMode::FilesWithMatches if count > 0 => {
+            color::write(out, config.color, color::FILENAME, name)?;
+            writeln!(out)?;
+        }
+        OutputMode::FilesWithoutMatch if count == 0 => {
+            color::write(out, config.color, color::FILENAME, name)?;
+            writeln!(out)?;
+            return Ok(true);
+        }
+        _ => {}
     }
+    Ok(count > 0 && config.mode != OutputMode::FilesWithoutMatch)
+}
+
+// A line read from a stream, held on to while it may still be shown as context.
+struct OwnedLine {
+    text: String,
+    byte_offset: usize,
+    ranges: Vec<Range<usize>>,
+}
+
+impl OwnedLine {
+    fn as_match(&self, line_number: usize) -> Match<'_> {
+        Match {
+            line: &self.text,
+            line_number,
+            byte_offset: self.byte_offset,
+            ranges: self.ranges.clone(),
+        }
+    }
+}
+
+// Like grep, selected lines have their prefixes separated by `:` and context lines by `-`.
+fn write_line(
+    out: &mut impl Write,
+    config: &Config,
+    filename: Option<&str>,
+    line: &Match,
+    separator: char,
+) -> io::Result<()> {
+    if let Some(filename) = filename {
+        color::write(out, config.color, color::FILENAME, filename)?;
+        color::write(out, config.color, color::SEPARATOR, separator)?;
+    }
+    if config.line_number {
+        color::write(out, config.color, color::LINE_NUMBER, line.line_number)?;
+        color::write(out, config.color, color::SEPARATOR, separator)?;
+    }
+    if config.byte_offset {
+        color::write(out, config.color, color::LINE_NUMBER, line.byte_offset)?;
+        color::write(out, config.color, color::SEPARATOR, separator)?;
+    }
+    // The hits were found when the line was searched, so there is no need to search again.
+    color::highlight(out, config.color, line.line, &line.ranges)?;
+    writeln!(out)
+}
+
+// Strip the line ending the way str::lines does: `\n` or `\r\n`.
+fn trim_newline(line: &[u8]) -> &[u8] {
+    match line.strip_suffix(b"\n") {
+        Some(line) => line.strip_suffix(b"\r").unwrap_or(line),
+        None => line,
+    }
+}
+
+pub fn is_binary(bytes: &[u8]) -> bool {
+    bytes.iter().take(BINARY_CHECK_LEN).any(|&b| b == 0)
+}
+
+// A matching line and where to find it. The line is borrowed from the searched text, so a Match
+// cannot outlive it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Match<'a> {
+    pub line: &'a str,
+    // Numbered from 1.
+    pub line_number: usize,
+    // Where the line starts in the searched text.
+    pub byte_offset: usize,
+    // The byte ranges within `line` of every hit, in order and without overlaps.
+    pub ranges: Vec<Range<usize>>,
+}
 
-    // Idiomatic way to indicate that we call run for its side effects only.
-    Ok(())
+// Where a hit has to sit in the line to count.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Boundary {
+    #[default]
+    Anywhere,
+    // Not preceded or followed by a word character (-w). Letters and digits from any script count.
+    Word,
+    // Covering the whole line (-x).
+    Line,
 }
 
 // The search result is borrowed from the contents string slice. We therefore need to connect their
 // lifetimes or the borrow checker will complain.
-pub fn search<'a>(query: &str, contents: &'a str) -> Vec<&'a str> {
+pub fn search<'a>(query: &str, contents: &'a str) -> Vec<Match<'a>> {
+    search_with_boundary(query, contents, Boundary::Anywhere)
+}
+
+// Case is ignored by Unicode case folding, so "strasse" finds "Straße" too.
+pub fn ci_search<'a>(query: &str, contents: &'a str) -> Vec<Match<'a>> {
+    ci_search_with_boundary(query, contents, Boundary::Anywhere)
+}
+
+pub fn search_with_boundary<'a>(
+    query: &str,
+    contents: &'a str,
+    boundary: Boundary,
+) -> Vec<Match<'a>> {
+    find_matches(contents, |line| find_all(query, line, boundary))
+}
+
+pub fn ci_search_with_boundary<'a>(
+    query: &str,
+    contents: &'a str,
+    boundary: Boundary,
+) -> Vec<Match<'a>> {
+    let query = CaseFolded::new(query);
+    find_matches(contents, |line| {
+        collect_hits(line, boundary, |start| query.find_at(line, start))
+    })
+}
+
+// Case sensitivity is part of the compiled pattern.
+pub fn regex_search<'a>(pattern: &Regex, contents: &'a str) -> Vec<Match<'a>> {
+    find_matches(contents, |line| pattern.find_iter(line).collect())
+}
+
+fn find_matches<'a>(contents: &'a str, find: impl Fn(&str) -> Vec<Range<usize>>) -> Vec<Match<'a>> {
+    let mut byte_offset = 0;
     contents
-        .lines()
-        .filter(|line| line.contains(query))
+        .split_inclusive('\n')
+        .enumerate()
+        .filter_map(|(i, raw)| {
+            let line_offset = byte_offset;
+            byte_offset += raw.len();
+            let line = match raw.strip_suffix('\n') {
+                Some(line) => line.strip_suffix('\r').unwrap_or(line),
+                None => raw,
+            };
+            let ranges = find(line);
+            (!ranges.is_empty()).then(|| Match {
+                line,
+                line_number: i + 1,
+                byte_offset: line_offset,
+                ranges,
+            })
+        })
         .collect()
 }
 
-pub fn ci_search<'a>(query: &str, contents: &'a str) -> Vec<&'a str> {
-    let query = query.to_lowercase();
+// An empty query matches every line, once, at the start.
+fn find_all(query: &str, line: &str, boundary: Boundary) -> Vec<Range<usize>> {
+    collect_hits(line, boundary, |start| {
+        if query.is_empty() {
+            return (start == 0).then_some(0..0);
+        }
+        line[start..]
+            .find(query)
+            .map(|i| start + i..start + i + query.len())
+    })
+}
+
+// Collect the hits `find_at` finds from each start position that sit on `boundary`. When a hit
+// does not, we look again from its next character, since a hit that does may overlap it.
+fn collect_hits(
+    line: &str,
+    boundary: Boundary,
+    find_at: impl Fn(usize) -> Option<Range<usize>>,
+) -> Vec<Range<usize>> {
+    let mut hits = Vec::new();
+    let mut start = 0;
+    while let Some(hit) = find_at(start) {
+        let keep = match boundary {
+            Boundary::Anywhere => true,
+            Boundary::Word => is_whole_word(line, &hit),
+            Boundary::Line => hit == (0..line.len()),
+        };
+        start = if keep && !hit.is_empty() {
+            hit.end
+        } else {
+            hit.start + line[hit.start..].chars().next().map_or(1, char::len_utf8)
+        };
+        if keep {
+            hits.push(hit);
+        }
+        if start > line.len() {
+            break;
+        }
+    }
+    hits
+}
+
+fn is_whole_word(line: &str, hit: &Range<usize>) -> bool {
+    let before = line[..hit.start].chars().next_back();
+    let after = line[hit.end..].chars().next();
+    !before.is_some_and(is_word_char) && !after.is_some_and(is_word_char)
+}
+
+// Like search, with up to `before` and `after` lines of context around each matching line.
+pub fn search_context<'a>(
+    query: &str,
+    contents: &'a str,
+    before: usize,
+    after: usize,
+) -> Vec<ContextLine<&'a str>> {
+    with_context(contents, before, after, |line| line.contains(query))
+}
+
+pub fn ci_search_context<'a>(
+    query: &str,
+    contents: &'a str,
+    before: usize,
+    after: usize,
+) -> Vec<ContextLine<&'a str>> {
+    let query = CaseFolded::new(query);
+    with_context(contents, before, after, |line| query.is_match(line))
+}
+
+fn with_context(
+    contents: &str,
+    before: usize,
+    after: usize,
+    is_match: impl Fn(&str) -> bool,
+) -> Vec<ContextLine<&str>> {
+    let mut context = Context::new(before, after);
     contents
         .lines()
-        .filter(|line| line.to_lowercase().contains(query.as_str()))
+        .enumerate()
+        .flat_map(|(i, line)| context.push(i + 1, line, is_match(line)))
         .collect()
 }
 
@@ -71,12 +587,24 @@ pub fn ci_search<'a>(query: &str, contents: &'a str) -> Vec<&'a str> {
 mod tests {
     use super::*;
 
+    fn config(args: &[&str]) -> Config {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        Config::new(&args).unwrap()
+    }
+
+    fn lines<'a>(matches: Vec<Match<'a>>) -> Vec<&'a str> {
+        matches.into_iter().map(|m| m.line).collect()
+    }
+
     #[test]
     fn one_result() {
         let query = "duct";
         let contents = "\nRust:\nsafe, fast, productive.\nPick three.\nDuct tape.";
 
-        assert_eq!(vec!["safe, fast, productive."], search(query, contents));
+        assert_eq!(
+            vec!["safe, fast, productive."],
+            lines(search(query, contents))
+        );
     }
 
     #[test]
@@ -84,6 +612,122 @@ mod tests {
         let query = "rUsT";
         let contents = "\nRust:\nsafe, fast, productive.\nPick three.\nTrust me.";
 
-        assert_eq!(vec!["Rust:", "Trust me."], ci_search(query, contents));
+        assert_eq!(
+            vec!["Rust:", "Trust me."],
+            lines(ci_search(query, contents))
+        );
+    }
+
+    #[test]
+    fn whole_words_and_lines() {
+        let contents = "rust\nRust: trust rusty\nÜrust rust_ rust\nrust rust";
+
+        assert_eq!(
+            vec![(1, 0..4), (2, 0..4), (3, 13..17), (4, 0..4), (4, 5..9)],
+            ci_search_with_boundary("RUST", contents, Boundary::Word)
+                .into_iter()
+                .flat_map(|m| m.ranges.into_iter().map(move |hit| (m.line_number, hit)))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec!["rust"],
+            lines(ci_search_with_boundary("RUST", contents, Boundary::Line))
+        );
+        assert_eq!(
+            vec!["rust rust"],
+            lines(search_with_boundary("rust rust", contents, Boundary::Line))
+        );
+    }
+
+    #[test]
+    fn match_positions() {
+        let contents = "Rust: trust\r\nsafe, fast, productive.\nTRUST ME, rust.";
+
+        assert_eq!(
+            vec![
+                Match {
+                    line: "Rust: trust",
+                    line_number: 1,
+                    byte_offset: 0,
+                    ranges: vec![0..4, 7..11],
+                },
+                Match {
+                    line: "TRUST ME, rust.",
+                    line_number: 3,
+                    byte_offset: 37,
+                    ranges: vec![1..5, 10..14],
+                },
+            ],
+            ci_search("rUsT", contents)
+        );
+    }
+
+    #[test]
+    fn regex_mode() {
+        let pattern = Regex::new(r"^\w+:$|th(ree|is)").unwrap();
+        let contents = "\nRust:\nsafe, fast, productive.\nPick three.\nDuct tape.";
+
+        assert_eq!(
+            vec!["Rust:", "Pick three."],
+            lines(regex_search(&pattern, contents))
+        );
+    }
+
+    #[test]
+    fn whole_line_patterns_must_be_valid_alone() {
+        let args: Vec<String> = ["minigrep", "-Ex", "a)|(b"]
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        assert!(matches!(
+            Config::new(&args),
+            Err(ArgsError::Usage(m)) if m.contains("position 1")
+        ));
+        assert!(config(&["minigrep", "-Ex", "a|b"])
+            .pattern
+            .unwrap()
+            .is_match("b"));
+    }
+
+    #[test]
+    fn case_insensitive_context() {
+        let query = "rUsT";
+        let contents = "Rust:\nsafe, fast, productive.\nPick three.\nDuct tape.\nTrust me.";
+
+        assert_eq!(
+            vec![
+                ContextLine::Match(1, "Rust:"),
+                ContextLine::Context(2, "safe, fast, productive."),
+                ContextLine::Separator,
+                ContextLine::Context(4, "Duct tape."),
+                ContextLine::Match(5, "Trust me."),
+            ],
+            ci_search_context(query, contents, 1, 1)
+        );
+    }
+
+    #[test]
+    fn streams_lossy_lines() {
+        let config = config(&["minigrep", "-sn", "fast", "-"]);
+        let input: &[u8] = b"Rust:\r\nsafe, fast, \xFF productive.\nPick three.";
+        let mut out = Vec::new();
+
+        search_reader(&config, input, "-", false, &mut out).unwrap();
+        assert_eq!(
+            "2:safe, fast, \u{FFFD} productive.\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn counts_up_to_max() {
+        let input: &[u8] = b"Rust:\nTrust me.\nrusty\nthe end";
+        let mut out = Vec::new();
+
+        let count = config(&["minigrep", "-icm2", "rust"]);
+        assert!(search_reader(&count, input, "-", false, &mut out).unwrap());
+        let without_match = config(&["minigrep", "-L", "zebra"]);
+        assert!(search_reader(&without_match, input, "-", false, &mut out).unwrap());
+        assert_eq!("2\n-\n", String::from_utf8(out).unwrap());
     }
 }
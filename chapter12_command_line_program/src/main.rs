@@ -2,6 +2,7 @@ use std::env;
 use std::process;
 
 use chapter12_command_line_program as minigrep;
+use minigrep::args::ArgsError;
 use minigrep::Config;
 
 fn main() {
@@ -11,15 +12,19 @@ fn main() {
     // The method unwrap_or_else on Result allows for custom non-panicing error handling.
     // If Ok, unwraps Ok and assigns it.
     // Else, unwraps Err and passes its contents to the closure that that is itself passed as argument to unwrap_or_else.
-    let config = Config::new(&args).unwrap_or_else(|err| {
-        eprintln!("Problem parsing arguments: {}", err);
-        // Nonzero error code - something went wrong.
-        process::exit(1);
+    let config = Config::new(&args).unwrap_or_else(|err| match err {
+        // Asking for help or the version is not an error, so print to stdout and exit cleanly.
+        ArgsError::Help | ArgsError::Version => {
+            println!("{}", err);
+            process::exit(0);
+        }
+        ArgsError::Usage(_) => {
+            eprintln!("{}", err);
+            // Like grep, 2 means the command line itself was wrong.
+            process::exit(2);
+        }
     });
 
-    if let Err(e) = minigrep::run(config) {
-        eprintln!("Application error: {}", e);
-
-        process::exit(1);
-    }
+    // Like grep: 0 if a line was selected, 1 if not, 2 if something went wrong.
+    process::exit(minigrep::run(config).code());
 }
@@ -0,0 +1,735 @@
+use std::error::Error;
+use std::fmt;
+use std::ops::Range;
+
+use crate::fold::fold;
+
+// A small regular expression engine, enough for searching log files line by line.
+//
+// Supported syntax:
+//   literals, `.`, `[abc]`, `[^a-z]`, `\d \w \s \D \W \S`, escaped metacharacters
+//   anchors `^` and `$` (start and end of the line), word boundaries `\b` and `\B`
+//   alternation `a|b`, groups `(...)` and `(?:...)`
+//   repetition `*`, `+`, `?`, `{n}`, `{n,}`, `{n,m}`, each with a lazy `?` variant
+//
+// The pattern is compiled to a program for a Pike VM, which runs all possible paths through the
+// pattern in lockstep. That keeps matching linear in the length of the line, no matter how
+// pathological the pattern, and still reports the leftmost match the way Perl-style engines do.
+
+// Upper bound for counted repetition, which is compiled by copying the repeated expression.
+const MAX_REPEAT: u32 = 1000;
+
+// Upper bound for the compiled program, which nested counted repetition multiplies quickly.
+const MAX_PROGRAM_SIZE: usize = 1_000_000;
+
+#[derive(Debug, Clone)]
+pub struct Regex {
+    pattern: String,
+    program: Vec<Inst>,
+    case_insensitive: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegexError {
+    pub message: String,
+    // Character index into the pattern where the problem was found.
+    pub position: usize,
+}
+
+impl fmt::Display for RegexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "invalid pattern at position {}: {}",
+            self.position, self.message
+        )
+    }
+}
+
+impl Error for RegexError {}
+
+#[derive(Debug, Clone)]
+enum Inst {
+    Char(char),
+    Any,
+    Class(Class),
+    Assert(Assertion),
+    // Try both targets, preferring the first.
+    Split(usize, usize),
+    Jump(usize),
+    Match,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Assertion {
+    LineStart,
+    LineEnd,
+    WordBoundary,
+    NotWordBoundary,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Class {
+    ranges: Vec<(char, char)>,
+    // Also matches every word character, for `\w` and `[\w...]`.
+    word: bool,
+    negated: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Empty,
+    Literal(char),
+    Any,
+    Class(Class),
+    Assert(Assertion),
+    Concat(Vec<Node>),
+    Alternate(Vec<Node>),
+    Repeat {
+        node: Box<Node>,
+        min: u32,
+        max: Option<u32>,
+        greedy: bool,
+    },
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Regex, RegexError> {
+        Regex::build(pattern, false)
+    }
+
+    // Letters match regardless of case.
+    pub fn case_insensitive(pattern: &str) -> Result<Regex, RegexError> {
+        Regex::build(pattern, true)
+    }
+
+    fn build(pattern: &str, case_insensitive: bool) -> Result<Regex, RegexError> {
+        let mut parser = Parser {
+            chars: pattern.chars().collect(),
+            pos: 0,
+        };
+        let node = parser.parse_alternation()?;
+        if parser.pos < parser.chars.len() {
+            // parse_alternation only stops early at a `)` without a matching `(`.
+            return Err(parser.error("unmatched `)`"));
+        }
+
+        // Check before compiling, so a pattern like `((a{1000}){1000}){1000}` is turned down
+        // without first filling memory with its program.
+        if program_size(&node) >= MAX_PROGRAM_SIZE {
+            return Err(RegexError {
+                message: String::from("pattern too large"),
+                position: 0,
+            });
+        }
+
+        let mut program = Vec::new();
+        compile(&node, &mut program);
+        program.push(Inst::Match);
+
+        Ok(Regex {
+            pattern: pattern.to_string(),
+            program,
+            case_insensitive,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        self.find_at(text, 0).is_some()
+    }
+
+    // Byte range of the leftmost match starting at or after `start`.
+    pub fn find_at(&self, text: &str, start: usize) -> Option<Range<usize>> {
+        let mut current = Threads::new(self.program.len());
+        let mut next = Threads::new(self.program.len());
+        let mut matched = None;
+        let mut pos = start;
+
+        loop {
+            // Start a new attempt here unless an earlier start already matched; a match that
+            // starts further left always wins.
+            if matched.is_none() {
+                self.add_thread(&mut current, 0, pos, pos, text);
+            }
+            if current.is_empty() && matched.is_some() {
+                break;
+            }
+
+            let ch = text[pos..].chars().next();
+            let next_pos = pos + ch.map_or(0, char::len_utf8);
+            for i in 0..current.len() {
+                let (pc, thread_start) = current.get(i);
+                let step = match (&self.program[pc], ch) {
+                    (Inst::Match, _) => {
+                        matched = Some(thread_start..pos);
+                        // Threads after this one have lower priority.
+                        break;
+                    }
+                    (Inst::Char(expected), Some(c)) => self.chars_match(*expected, c),
+                    (Inst::Any, Some(_)) => true,
+                    (Inst::Class(class), Some(c)) => self.class_matches(class, c),
+                    _ => false,
+                };
+                if step {
+                    self.add_thread(&mut next, pc + 1, thread_start, next_pos, text);
+                }
+            }
+
+            std::mem::swap(&mut current, &mut next);
+            next.clear();
+            if ch.is_none() {
+                break;
+            }
+            pos = next_pos;
+        }
+        matched
+    }
+
+    // All non-overlapping matches, left to right.
+    pub fn find_iter<'r, 't>(&'r self, text: &'t str) -> impl Iterator<Item = Range<usize>> + 'r
+    where
+        't: 'r,
+    {
+        let mut pos = 0;
+        std::iter::from_fn(move || {
+            if pos > text.len() {
+                return None;
+            }
+            let found = self.find_at(text, pos)?;
+            pos = if found.is_empty() {
+                // Step over one character so an empty match cannot repeat forever.
+                found.end + text[found.end..].chars().next().map_or(1, char::len_utf8)
+            } else {
+                found.end
+            };
+            Some(found)
+        })
+    }
+
+    // Follow jumps, splits and assertions from `pc`, adding every instruction that consumes a
+    // character (or matches) to `threads`, in priority order. Patterns can chain a great many
+    // of these, so this walks them with an explicit stack instead of recursing.
+    fn add_thread(&self, threads: &mut Threads, pc: usize, start: usize, at: usize, text: &str) {
+        threads.stack.push(pc);
+        while let Some(pc) = threads.stack.pop() {
+            if !threads.insert(pc, start) {
+                continue;
+            }
+            match &self.program[pc] {
+                Inst::Jump(target) => threads.stack.push(*target),
+                Inst::Split(first, second) => {
+                    // Last in, first out: the preferred target is followed first.
+                    threads.stack.push(*second);
+                    threads.stack.push(*first);
+                }
+                Inst::Assert(assertion) if assertion_holds(*assertion, text, at) => {
+                    threads.stack.push(pc + 1);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Characters are compared one at a time, so folds that expand, like `ß` to "ss", only match
+    // themselves here.
+    fn chars_match(&self, expected: char, c: char) -> bool {
+        expected == c || (self.case_insensitive && fold(expected).eq(fold(c)))
+    }
+
+    fn class_matches(&self, class: &Class, c: char) -> bool {
+        if !self.case_insensitive {
+            return class.matches(c);
+        }
+        let found = class.contains(c)
+            || c.to_lowercase().any(|c| class.contains(c))
+            || c.to_uppercase().any(|c| class.contains(c));
+        found != class.negated
+    }
+}
+
+fn assertion_holds(assertion: Assertion, text: &str, at: usize) -> bool {
+    let before = text[..at].chars().next_back();
+    let after = text[at..].chars().next();
+    match assertion {
+        Assertion::LineStart => at == 0,
+        Assertion::LineEnd => at == text.len(),
+        Assertion::WordBoundary => {
+            before.is_some_and(is_word_char) != after.is_some_and(is_word_char)
+        }
+        Assertion::NotWordBoundary => {
+            before.is_some_and(is_word_char) == after.is_some_and(is_word_char)
+        }
+    }
+}
+
+pub fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+impl Class {
+    fn matches(&self, c: char) -> bool {
+        self.contains(c) != self.negated
+    }
+
+    // Membership before negation.
+    fn contains(&self, c: char) -> bool {
+        (self.word && is_word_char(c)) || self.ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi)
+    }
+}
+
+// The run list of the VM: program counters in priority order, each with the position where its
+// match attempt started. Every program counter is added at most once per step.
+struct Threads {
+    pcs: Vec<(usize, usize)>,
+    present: Vec<bool>,
+    // Scratch space for add_thread, kept to save allocating it on every step.
+    stack: Vec<usize>,
+}
+
+impl Threads {
+    fn new(size: usize) -> Threads {
+        Threads {
+            pcs: Vec::with_capacity(size),
+            present: vec![false; size],
+            stack: Vec::new(),
+        }
+    }
+
+    fn insert(&mut self, pc: usize, start: usize) -> bool {
+        if self.present[pc] {
+            return false;
+        }
+        self.present[pc] = true;
+        self.pcs.push((pc, start));
+        true
+    }
+
+    fn get(&self, i: usize) -> (usize, usize) {
+        self.pcs[i]
+    }
+
+    fn len(&self) -> usize {
+        self.pcs.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pcs.is_empty()
+    }
+
+    fn clear(&mut self) {
+        for &(pc, _) in &self.pcs {
+            self.present[pc] = false;
+        }
+        self.pcs.clear();
+    }
+}
+
+// The number of instructions `compile` emits for `node`, saturating instead of overflowing.
+fn program_size(node: &Node) -> usize {
+    match node {
+        Node::Empty => 0,
+        Node::Literal(_) | Node::Any | Node::Class(_) | Node::Assert(_) => 1,
+        Node::Concat(nodes) => nodes
+            .iter()
+            .fold(0, |size, node| size.saturating_add(program_size(node))),
+        // A split and a jump for every alternative but the last.
+        Node::Alternate(nodes) => nodes.iter().fold(2 * (nodes.len() - 1), |size, node| {
+            size.saturating_add(program_size(node))
+        }),
+        Node::Repeat { node, min, max, .. } => {
+            let body = program_size(node);
+            let optional = match max {
+                None => body.saturating_add(2),
+                Some(max) => body.saturating_add(1).saturating_mul((max - min) as usize),
+            };
+            body.saturating_mul(*min as usize).saturating_add(optional)
+        }
+    }
+}
+
+fn compile(node: &Node, program: &mut Vec<Inst>) {
+    match node {
+        Node::Empty => {}
+        Node::Literal(c) => program.push(Inst::Char(*c)),
+        Node::Any => program.push(Inst::Any),
+        Node::Class(class) => program.push(Inst::Class(class.clone())),
+        Node::Assert(assertion) => program.push(Inst::Assert(*assertion)),
+        Node::Concat(nodes) => {
+            for node in nodes {
+                compile(node, program);
+            }
+        }
+        Node::Alternate(nodes) => {
+            // split L1, next; L1: first; jump end; next: split L2, ...
+            let mut jumps = Vec::new();
+            for (i, node) in nodes.iter().enumerate() {
+                if i + 1 < nodes.len() {
+                    let split = program.len();
+                    program.push(Inst::Split(split + 1, 0));
+                    compile(node, program);
+                    jumps.push(program.len());
+                    program.push(Inst::Jump(0));
+                    let next = program.len();
+                    program[split] = Inst::Split(split + 1, next);
+                } else {
+                    compile(node, program);
+                }
+            }
+            let end = program.len();
+            for jump in jumps {
+                program[jump] = Inst::Jump(end);
+            }
+        }
+        Node::Repeat {
+            node,
+            min,
+            max,
+            greedy,
+        } => {
+            for _ in 0..*min {
+                compile(node, program);
+            }
+            match max {
+                None => {
+                    // loop: split body, end; body; jump loop
+                    let split = program.len();
+                    program.push(Inst::Split(0, 0));
+                    compile(node, program);
+                    program.push(Inst::Jump(split));
+                    let end = program.len();
+                    program[split] = prefer(*greedy, split + 1, end);
+                }
+                Some(max) => {
+                    // Each optional copy may be skipped, which also skips all later copies.
+                    let mut splits = Vec::new();
+                    for _ in *min..*max {
+                        splits.push(program.len());
+                        program.push(Inst::Split(0, 0));
+                        compile(node, program);
+                    }
+                    let end = program.len();
+                    for split in splits {
+                        program[split] = prefer(*greedy, split + 1, end);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn prefer(greedy: bool, body: usize, skip: usize) -> Inst {
+    if greedy {
+        Inst::Split(body, skip)
+    } else {
+        Inst::Split(skip, body)
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn error(&self, message: &str) -> RegexError {
+        RegexError {
+            message: message.to_string(),
+            position: self.pos,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, expected: char) -> bool {
+        if self.peek() == Some(expected) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn parse_alternation(&mut self) -> Result<Node, RegexError> {
+        let mut branches = vec![self.parse_concat()?];
+        while self.eat('|') {
+            branches.push(self.parse_concat()?);
+        }
+        Ok(if branches.len() == 1 {
+            branches.pop().unwrap()
+        } else {
+            Node::Alternate(branches)
+        })
+    }
+
+    fn parse_concat(&mut self) -> Result<Node, RegexError> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.parse_atom()?;
+            nodes.push(self.parse_repeat(atom)?);
+        }
+        Ok(match nodes.len() {
+            0 => Node::Empty,
+            1 => nodes.pop().unwrap(),
+            _ => Node::Concat(nodes),
+        })
+    }
+
+    fn parse_repeat(&mut self, mut atom: Node) -> Result<Node, RegexError> {
+        loop {
+            let start = self.pos;
+            let (min, max) = match self.peek() {
+                Some('*') => (0, None),
+                Some('+') => (1, None),
+                Some('?') => (0, Some(1)),
+                Some('{') => match self.parse_counts()? {
+                    Some(counts) => counts,
+                    // Not a valid count, so the brace is an ordinary character.
+                    None => return Ok(atom),
+                },
+                _ => return Ok(atom),
+            };
+            if start == self.pos {
+                self.pos += 1;
+            }
+            if let Node::Assert(_) | Node::Empty = atom {
+                self.pos = start;
+                return Err(self.error("nothing to repeat"));
+            }
+            let greedy = !self.eat('?');
+            atom = Node::Repeat {
+                node: Box::new(atom),
+                min,
+                max,
+                greedy,
+            };
+        }
+    }
+
+    // `{n}`, `{n,}` or `{n,m}`, consuming it only if it is one of those.
+    fn parse_counts(&mut self) -> Result<Option<(u32, Option<u32>)>, RegexError> {
+        let close = match self.chars[self.pos..].iter().position(|&c| c == '}') {
+            Some(offset) => self.pos + offset,
+            None => return Ok(None),
+        };
+        let inner: String = self.chars[self.pos + 1..close].iter().collect();
+        let number = |s: &str| s.parse::<u32>().ok();
+        let counts = match inner.split_once(',') {
+            None => number(&inner).map(|n| (n, Some(n))),
+            Some((min, "")) => number(min).map(|n| (n, None)),
+            Some((min, max)) => number(min).zip(number(max)).map(|(a, b)| (a, Some(b))),
+        };
+        let (min, max) = match counts {
+            Some(counts) => counts,
+            None => return Ok(None),
+        };
+        if max.is_some_and(|max| max < min) {
+            return Err(self.error("repetition range is backwards"));
+        }
+        if max.unwrap_or(min) > MAX_REPEAT {
+            return Err(self.error("repetition count is too large"));
+        }
+        self.pos = close + 1;
+        Ok(Some((min, max)))
+    }
+
+    fn parse_atom(&mut self) -> Result<Node, RegexError> {
+        let c = self.peek().unwrap();
+        self.pos += 1;
+        match c {
+            '.' => Ok(Node::Any),
+            '^' => Ok(Node::Assert(Assertion::LineStart)),
+            '$' => Ok(Node::Assert(Assertion::LineEnd)),
+            '(' => {
+                // Groups only group; matches are reported as a whole.
+                if self.eat('?') && !self.eat(':') {
+                    return Err(self.error("unsupported group flag"));
+                }
+                let node = self.parse_alternation()?;
+                if !self.eat(')') {
+                    return Err(self.error("unclosed group"));
+                }
+                Ok(node)
+            }
+            '[' => self.parse_class(),
+            '\\' => self.parse_escape(),
+            '*' | '+' | '?' => {
+                self.pos -= 1;
+                Err(self.error("nothing to repeat"))
+            }
+            c => Ok(Node::Literal(c)),
+        }
+    }
+
+    fn parse_escape(&mut self) -> Result<Node, RegexError> {
+        let c = match self.peek() {
+            Some(c) => c,
+            None => return Err(self.error("pattern ends with a backslash")),
+        };
+        self.pos += 1;
+        Ok(match c {
+            'b' => Node::Assert(Assertion::WordBoundary),
+            'B' => Node::Assert(Assertion::NotWordBoundary),
+            c => match perl_class(c) {
+                Some(class) => Node::Class(class),
+                None => Node::Literal(self.escaped_char(c)?),
+            },
+        })
+    }
+
+    fn escaped_char(&self, c: char) -> Result<char, RegexError> {
+        match c {
+            'n' => Ok('\n'),
+            't' => Ok('\t'),
+            'r' => Ok('\r'),
+            c if c.is_ascii_alphanumeric() => Err(self.error("unknown escape")),
+            c => Ok(c),
+        }
+    }
+
+    fn parse_class(&mut self) -> Result<Node, RegexError> {
+        let negated = self.eat('^');
+        let mut ranges = Vec::new();
+        let mut word = false;
+        let mut first = true;
+
+        loop {
+            let c = match self.peek() {
+                Some(c) => c,
+                None => return Err(self.error("unclosed character class")),
+            };
+            self.pos += 1;
+            // A `]` right after the opening bracket is a literal.
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+
+            let lo = if c == '\\' {
+                let escaped = match self.peek() {
+                    Some(e) => e,
+                    None => return Err(self.error("unclosed character class")),
+                };
+                self.pos += 1;
+                if let Some(class) = perl_class(escaped) {
+                    if class.negated {
+                        return Err(self.error("negated class inside brackets"));
+                    }
+                    ranges.extend(class.ranges);
+                    word |= class.word;
+                    continue;
+                }
+                self.escaped_char(escaped)?
+            } else {
+                c
+            };
+
+            if self.peek() == Some('-') && self.chars.get(self.pos + 1).is_some_and(|&c| c != ']') {
+                self.pos += 1;
+                let mut hi = self.chars[self.pos];
+                self.pos += 1;
+                if hi == '\\' {
+                    hi = match self.peek() {
+                        Some(e) => self.escaped_char(e)?,
+                        None => return Err(self.error("unclosed character class")),
+                    };
+                    self.pos += 1;
+                }
+                if hi < lo {
+                    return Err(self.error("character range is backwards"));
+                }
+                ranges.push((lo, hi));
+            } else {
+                ranges.push((lo, lo));
+            }
+        }
+        Ok(Node::Class(Class {
+            ranges,
+            word,
+            negated,
+        }))
+    }
+}
+
+// The classes behind `\d`, `\w` and `\s` and their negations. Digits and spaces are ASCII
+// only, while `\w` takes all Unicode letters and digits, like `\b` does.
+fn perl_class(c: char) -> Option<Class> {
+    let (ranges, word) = match c.to_ascii_lowercase() {
+        'd' => (vec![('0', '9')], false),
+        's' => (vec![(' ', ' '), ('\t', '\r')], false),
+        'w' => (Vec::new(), true),
+        _ => return None,
+    };
+    Some(Class {
+        ranges,
+        word,
+        negated: c.is_ascii_uppercase(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(pattern: &str, text: &str) -> Option<Range<usize>> {
+        Regex::new(pattern).unwrap().find_at(text, 0)
+    }
+
+    #[test]
+    fn classes_anchors_and_repetition() {
+        assert_eq!(Some(6..16), find(r"\d{4}-\d\d-\d\d", "date: 2023-10-15!"));
+        assert_eq!(Some(0..5), find("^[A-Z][a-z]+", "Hello world"));
+        assert_eq!(None, find("^world", "Hello world"));
+        assert_eq!(Some(6..11), find("wor.d$", "Hello world"));
+        assert_eq!(Some(0..3), find("[^ ]*", "abc def"));
+        assert_eq!(Some(2..5), find(r"\bcat\b", "a cat concatenated"));
+    }
+
+    #[test]
+    fn alternation_groups_and_laziness() {
+        assert_eq!(Some(4..10), find("(ERROR|WARN)(:|!)", "xx: ERROR: disk"));
+        assert_eq!(Some(0..6), find("(?:ab)+", "ababab"));
+        assert_eq!(Some(0..3), find("<.+?>", "<a><b>"));
+        assert_eq!(Some(0..6), find("<.+>", "<a><b>"));
+        // Leftmost-first, like Perl: the first alternative wins even if it is shorter.
+        assert_eq!(Some(0..1), find("a|ab", "ab"));
+    }
+
+    #[test]
+    fn pathological_patterns_stay_fast() {
+        let text = "a".repeat(5000);
+        assert_eq!(None, find("(a*)*b", &text));
+        assert_eq!(None, find("(a|aa)+$x", &text));
+        // Hundreds of thousands of splits in a row, each leading on to the next.
+        assert_eq!(Some(0..0), find("(?:(?:x?){1000}){200}", "y"));
+    }
+
+    #[test]
+    fn case_insensitive_and_iteration() {
+        let regex = Regex::case_insensitive("rust|[é]").unwrap();
+        let found: Vec<_> = regex.find_iter("Rust and RUST café").collect();
+        assert_eq!(vec![0..4, 9..13, 17..19], found);
+    }
+
+    #[test]
+    fn reports_errors_with_position() {
+        let err = Regex::new("ab(c").unwrap_err();
+        assert_eq!(4, err.position);
+        assert_eq!("nothing to repeat", Regex::new("a|*").unwrap_err().message);
+        assert!(Regex::new("[z-a]").is_err());
+        assert!(Regex::new("a)").is_err());
+        assert_eq!(
+            "pattern too large",
+            Regex::new("((a{1000}){1000}){1000}").unwrap_err().message
+        );
+        assert!(Regex::new("(a{1000}){100}").is_ok());
+    }
+}
@@ -0,0 +1,333 @@
+use std::fs;
+use std::io;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+
+// Finding the files to search under a directory.
+//
+// Directories are walked depth first in name order, so the output is the same from run to run.
+// Symbolic links are not followed and `.git` directories are never entered. File names can be
+// filtered with `--include`/`--exclude` globs, directory names with `--exclude-dir`, and by
+// default the `.gitignore` files found in the tree are honored as well.
+
+// A shell-style wildcard pattern:
+//   `*` matches any run of characters except `/`, `**` also matches across `/`
+//   `?` matches one character except `/`
+//   `[abc]`, `[a-z]`, `[!a-z]` (or `[^a-z]`) match one character from (or not from) a set
+//   `\` makes the next character literal
+#[derive(Debug, Clone, PartialEq)]
+pub struct Glob {
+    pattern: Vec<char>,
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Glob {
+        Glob {
+            pattern: pattern.chars().collect(),
+        }
+    }
+
+    pub fn matches(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        glob_match(&self.pattern, &text)
+    }
+}
+
+fn glob_match(pattern: &[char], text: &[char]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        ['*', '*', '/', rest @ ..] => {
+            // Zero or more whole directories.
+            glob_match(rest, text)
+                || (0..text.len())
+                    .filter(|&i| text[i] == '/')
+                    .any(|i| glob_match(rest, &text[i + 1..]))
+        }
+        ['*', '*', rest @ ..] => (0..=text.len()).any(|i| glob_match(rest, &text[i..])),
+        ['*', rest @ ..] => {
+            let segment = text.iter().position(|&c| c == '/').unwrap_or(text.len());
+            (0..=segment).any(|i| glob_match(rest, &text[i..]))
+        }
+        ['?', rest @ ..] => matches!(text, [c, ..] if *c != '/') && glob_match(rest, &text[1..]),
+        ['[', rest @ ..] => match (parse_class(rest), text) {
+            (Some((matches_class, after)), [c, ..]) => {
+                *c != '/' && matches_class(*c) && glob_match(after, &text[1..])
+            }
+            (Some(_), []) => false,
+            // An unterminated `[` is just a bracket.
+            (None, _) => matches!(text, ['[', ..]) && glob_match(rest, &text[1..]),
+        },
+        ['\\', c, rest @ ..] | [c, rest @ ..] => {
+            matches!(text, [t, ..] if t == c) && glob_match(rest, &text[1..])
+        }
+    }
+}
+
+// Parse the inside of a `[...]` set, returning a test for it and the pattern after the `]`.
+fn parse_class(pattern: &[char]) -> Option<(impl Fn(char) -> bool + '_, &[char])> {
+    let (negated, body) = match pattern {
+        ['!' | '^', rest @ ..] => (true, rest),
+        _ => (false, pattern),
+    };
+    // A `]` right at the start is part of the set rather than its end.
+    let first = usize::from(body.first() == Some(&']'));
+    let end = first + body.get(first..)?.iter().position(|&c| c == ']')?;
+    let set = &body[..end];
+
+    let test = move |c: char| {
+        let mut found = false;
+        let mut i = 0;
+        while i < set.len() {
+            if i + 2 < set.len() && set[i + 1] == '-' {
+                found |= set[i] <= c && c <= set[i + 2];
+                i += 3;
+            } else {
+                found |= set[i] == c;
+                i += 1;
+            }
+        }
+        found != negated
+    };
+    Some((test, &body[end + 1..]))
+}
+
+// One line of a `.gitignore` file.
+#[derive(Debug, Clone)]
+struct Rule {
+    glob: Glob,
+    // `!pattern` re-includes what an earlier rule ignored.
+    negated: bool,
+    // `pattern/` only matches directories.
+    dir_only: bool,
+    // Patterns containing a `/` match the path relative to the `.gitignore`, others match the
+    // name at any depth.
+    anchored: bool,
+}
+
+// The rules of one `.gitignore` file and the directory they apply to.
+#[derive(Debug, Clone)]
+struct Ignore {
+    base: PathBuf,
+    rules: Vec<Rule>,
+}
+
+impl Ignore {
+    fn parse(base: &Path, contents: &str) -> Ignore {
+        let rules = contents
+            .lines()
+            .map(str::trim_end)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let (negated, line) = match line.strip_prefix('!') {
+                    Some(rest) => (true, rest),
+                    None => (false, line),
+                };
+                let (dir_only, line) = match line.strip_suffix('/') {
+                    Some(rest) => (true, rest),
+                    None => (false, line),
+                };
+                let anchored = line.contains('/');
+                Rule {
+                    glob: Glob::new(line.strip_prefix('/').unwrap_or(line)),
+                    negated,
+                    dir_only,
+                    anchored,
+                }
+            })
+            .collect();
+        Ignore {
+            base: base.to_path_buf(),
+            rules,
+        }
+    }
+
+    // `Some(true)` if the last rule matching `path` ignores it, `Some(false)` if it re-includes
+    // it and `None` if no rule matches.
+    fn matched(&self, path: &Path, is_dir: bool) -> Option<bool> {
+        let relative = path.strip_prefix(&self.base).ok()?;
+        let relative = relative.to_string_lossy().replace('\\', "/");
+        let name = relative.rsplit('/').next().unwrap_or(&relative);
+
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| {
+                (is_dir || !rule.dir_only)
+                    && rule
+                        .glob
+                        .matches(if rule.anchored { &relative } else { name })
+            })
+            .map(|rule| !rule.negated)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Walker {
+    include: Vec<Glob>,
+    exclude: Vec<Glob>,
+    exclude_dir: Vec<Glob>,
+    gitignore: bool,
+}
+
+impl Default for Walker {
+    fn default() -> Walker {
+        Walker::new()
+    }
+}
+
+impl Walker {
+    pub fn new() -> Walker {
+        Walker {
+            include: Vec::new(),
+            exclude: Vec::new(),
+            exclude_dir: Vec::new(),
+            gitignore: true,
+        }
+    }
+
+    // Only search files whose name matches one of the include globs, if any are given.
+    pub fn include(mut self, glob: &str) -> Walker {
+        self.include.push(Glob::new(glob));
+        self
+    }
+
+    pub fn exclude(mut self, glob: &str) -> Walker {
+        self.exclude.push(Glob::new(glob));
+        self
+    }
+
+    pub fn exclude_dir(mut self, glob: &str) -> Walker {
+        self.exclude_dir.push(Glob::new(glob));
+        self
+    }
+
+    pub fn gitignore(mut self, enabled: bool) -> Walker {
+        self.gitignore = enabled;
+        self
+    }
+
+    // Call `found` with every file under `dir` that passes the filters. Errors reading a
+    // directory are passed on too, and the walk carries on with the rest of the tree until
+    // `found` breaks off.
+    pub fn walk(
+        &self,
+        dir: &Path,
+        found: &mut dyn FnMut(io::Result<PathBuf>) -> ControlFlow<()>,
+    ) -> ControlFlow<()> {
+        self.walk_dir(dir, &mut Vec::new(), found)
+    }
+
+    fn walk_dir(
+        &self,
+        dir: &Path,
+        ignores: &mut Vec<Ignore>,
+        found: &mut dyn FnMut(io::Result<PathBuf>) -> ControlFlow<()>,
+    ) -> ControlFlow<()> {
+        let mut entries =
+            match fs::read_dir(dir).and_then(|entries| entries.collect::<io::Result<Vec<_>>>()) {
+                Ok(entries) => entries,
+                Err(e) => return found(Err(with_path(dir, e))),
+            };
+        entries.sort_by_key(|entry| entry.file_name());
+
+        let pushed = self.gitignore && {
+            match fs::read_to_string(dir.join(".gitignore")) {
+                Ok(contents) => {
+                    ignores.push(Ignore::parse(dir, &contents));
+                    true
+                }
+                Err(_) => false,
+            }
+        };
+
+        for entry in entries {
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let file_type = match entry.file_type() {
+                Ok(file_type) => file_type,
+                Err(e) => {
+                    found(Err(with_path(&path, e)))?;
+                    continue;
+                }
+            };
+
+            if file_type.is_dir() {
+                if name != ".git"
+                    && !self.exclude_dir.iter().any(|glob| glob.matches(&name))
+                    && !is_ignored(ignores, &path, true)
+                {
+                    self.walk_dir(&path, ignores, found)?;
+                }
+            } else if file_type.is_file()
+                && self.wants_file(&name)
+                && !is_ignored(ignores, &path, false)
+            {
+                found(Ok(path))?;
+            }
+        }
+
+        if pushed {
+            ignores.pop();
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn wants_file(&self, name: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|glob| glob.matches(name)))
+            && !self.exclude.iter().any(|glob| glob.matches(name))
+    }
+}
+
+// Deeper `.gitignore` files take precedence over the ones above them.
+fn is_ignored(ignores: &[Ignore], path: &Path, is_dir: bool) -> bool {
+    ignores
+        .iter()
+        .rev()
+        .find_map(|ignore| ignore.matched(path, is_dir))
+        .unwrap_or(false)
+}
+
+pub(crate) fn with_path(path: &Path, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_wildcards() {
+        assert!(Glob::new("*.rs").matches("main.rs"));
+        assert!(!Glob::new("*.rs").matches("src/main.rs"));
+        assert!(Glob::new("**/*.rs").matches("main.rs"));
+        assert!(Glob::new("src/**/*.rs").matches("src/a/b/main.rs"));
+        assert!(Glob::new("log-??.[0-9]").matches("log-ab.7"));
+        assert!(!Glob::new("log-[!a-z]").matches("log-q"));
+        assert!(Glob::new(r"\*.txt").matches("*.txt"));
+        assert!(!Glob::new(r"\*.txt").matches("a.txt"));
+    }
+
+    #[test]
+    fn gitignore_rules() {
+        let ignore = Ignore::parse(
+            Path::new("repo"),
+            "# build output\ntarget/\n*.log\n!keep.log\n/docs/*.html\n",
+        );
+
+        assert_eq!(Some(true), ignore.matched(Path::new("repo/a/target"), true));
+        assert_eq!(None, ignore.matched(Path::new("repo/target"), false));
+        assert_eq!(
+            Some(true),
+            ignore.matched(Path::new("repo/x/app.log"), false)
+        );
+        assert_eq!(
+            Some(false),
+            ignore.matched(Path::new("repo/keep.log"), false)
+        );
+        assert_eq!(
+            Some(true),
+            ignore.matched(Path::new("repo/docs/a.html"), false)
+        );
+        assert_eq!(None, ignore.matched(Path::new("repo/x/docs/a.html"), false));
+    }
+}
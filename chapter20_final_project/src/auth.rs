@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::base64;
+use crate::digest;
+use crate::http::{Request, Response};
+use crate::router::{self, Router};
+
+const DEFAULT_ROUNDS: u32 = 10_000;
+
+/// HTTP Basic authentication against a password file.
+///
+/// Each line of the file is `name:$sha256$rounds$salt$hash`, as produced by `hash_password`
+/// or the `passwd` binary. Empty lines and lines starting with `#` are ignored. Only paths below
+/// a `protect`ed prefix require credentials.
+#[derive(Debug, Clone)]
+pub struct BasicAuth {
+    realm: String,
+    users: HashMap<String, PasswordHash>,
+    prefixes: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+struct PasswordHash {
+    rounds: u32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl BasicAuth {
+    pub fn new(realm: &str) -> BasicAuth {
+        BasicAuth {
+            realm: realm.to_string(),
+            users: HashMap::new(),
+            prefixes: Vec::new(),
+        }
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P, realm: &str) -> io::Result<BasicAuth> {
+        let mut auth = BasicAuth::new(realm);
+        for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parsed = line
+                .split_once(':')
+                .and_then(|(name, hash)| Some((name, PasswordHash::parse(hash)?)));
+            match parsed {
+                Some((name, hash)) => {
+                    auth.users.insert(name.to_string(), hash);
+                }
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("malformed password entry on line {}", number + 1),
+                    ))
+                }
+            }
+        }
+        Ok(auth)
+    }
+
+    /// Add a user whose password hash was made with `hash_password`.
+    pub fn with_user(mut self, name: &str, hash: &str) -> Option<BasicAuth> {
+        self.users
+            .insert(name.to_string(), PasswordHash::parse(hash)?);
+        Some(self)
+    }
+
+    /// Require credentials for `prefix` and everything below it.
+    pub fn protect(mut self, prefix: &str) -> BasicAuth {
+        self.prefixes.push(prefix.trim_end_matches('/').to_string());
+        self
+    }
+
+    /// Check `request`, returning the 401 to send if it needs credentials it does not carry.
+    ///
+    /// Prefixes are compared with the normalized path that routing and static files use, so
+    /// `/%61dmin` or `//admin` are protected like `/admin`. A path without a normal form always
+    /// needs credentials.
+    pub fn check(&self, request: &Request) -> Option<Response> {
+        let protected = match router::normalize_path(request.path()) {
+            Some(path) => self.prefixes.iter().any(|p| router::is_below(&path, p)),
+            None => true,
+        };
+        if !protected {
+            return None;
+        }
+        match self.user(request) {
+            Some(_) => None,
+            None => Some(
+                Response::new(401)
+                    .with_header(
+                        "WWW-Authenticate",
+                        &format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm),
+                    )
+                    .with_body(b"Authentication required\n".to_vec()),
+            ),
+        }
+    }
+
+    /// The name of the user whose valid credentials `request` carries.
+    pub fn user(&self, request: &Request) -> Option<String> {
+        let header = request.header("Authorization")?;
+        let (scheme, credentials) = header.split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("Basic") {
+            return None;
+        }
+        let decoded = base64::decode(credentials.trim())?;
+        let (name, password) = std::str::from_utf8(&decoded).ok()?.split_once(':')?;
+
+        let verified = match self.users.get(name) {
+            Some(stored) => stored.verify(password),
+            // Hash anyway so unknown names take as long as wrong passwords.
+            None => {
+                PasswordHash::generate(password, &[0; 16], DEFAULT_ROUNDS);
+                false
+            }
+        };
+        if verified {
+            Some(name.to_string())
+        } else {
+            None
+        }
+    }
+
+    /// Register `check` as a filter on `router`.
+    pub fn install(self, router: &mut Router) {
+        router.filter(move |request| self.check(request));
+    }
+}
+
+impl PasswordHash {
+    fn parse(text: &str) -> Option<PasswordHash> {
+        let mut parts = text.strip_prefix("$sha256$")?.split('$');
+        let rounds = parts.next()?.parse().ok().filter(|&r| r > 0)?;
+        let salt = from_hex(parts.next()?)?;
+        let hash = from_hex(parts.next()?)?;
+        if parts.next().is_some() || hash.len() != 32 {
+            return None;
+        }
+        Some(PasswordHash { rounds, salt, hash })
+    }
+
+    fn generate(password: &str, salt: &[u8], rounds: u32) -> PasswordHash {
+        let mut input = salt.to_vec();
+        input.extend_from_slice(password.as_bytes());
+        let mut hash = digest::sha256(&input);
+        for _ in 1..rounds {
+            let mut next = hash.to_vec();
+            next.extend_from_slice(&input);
+            hash = digest::sha256(&next);
+        }
+        PasswordHash {
+            rounds,
+            salt: salt.to_vec(),
+            hash: hash.to_vec(),
+        }
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        let candidate = PasswordHash::generate(password, &self.salt, self.rounds);
+        digest::constant_time_eq(&candidate.hash, &self.hash)
+    }
+}
+
+/// Hash `password` with `salt` for use in a password file.
+///
+/// The result is `$sha256$rounds$salt$hash`, with salt and hash in hex. The first round hashes
+/// the salt followed by the password; every further round hashes the previous hash followed by
+/// salt and password again, so checking a guess costs `rounds` SHA-256 computations. Use a
+/// random salt of 16 bytes, as `cargo run --bin passwd NAME` does.
+pub fn hash_password(password: &str, salt: &[u8]) -> String {
+    let hashed = PasswordHash::generate(password, salt, DEFAULT_ROUNDS);
+    format!(
+        "$sha256${}${}${}",
+        hashed.rounds,
+        digest::to_hex(&hashed.salt),
+        digest::to_hex(&hashed.hash)
+    )
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn basic(credentials: &str) -> String {
+        format!("Basic {}", base64::encode(credentials.as_bytes()))
+    }
+
+    #[test]
+    fn only_protected_paths_need_credentials() {
+        let auth = BasicAuth::new("admin")
+            .with_user("ferris", &hash_password("crab", b"salt"))
+            .unwrap()
+            .protect("/admin/");
+
+        assert!(auth.check(&Request::new("GET", "/public")).is_none());
+
+        let denied = auth.check(&Request::new("GET", "/admin/users")).unwrap();
+        assert_eq!(401, denied.status);
+        assert!(denied
+            .header("WWW-Authenticate")
+            .unwrap()
+            .starts_with("Basic realm=\"admin\""));
+
+        let wrong =
+            Request::new("GET", "/admin").with_header("Authorization", &basic("ferris:shell"));
+        assert!(auth.check(&wrong).is_some());
+
+        let right =
+            Request::new("GET", "/admin").with_header("Authorization", &basic("ferris:crab"));
+        assert!(auth.check(&right).is_none());
+        assert_eq!(Some(String::from("ferris")), auth.user(&right));
+    }
+
+    #[test]
+    fn other_spellings_of_protected_paths_need_credentials() {
+        let auth = BasicAuth::new("admin").protect("/admin");
+
+        for target in [
+            "/%61dmin/secret.html",
+            "//admin/secret.html",
+            "/./admin/secret.html",
+        ] {
+            assert_eq!(
+                Some(401),
+                auth.check(&Request::new("GET", target)).map(|r| r.status),
+                "{}",
+                target
+            );
+        }
+
+        let config = crate::config::ServerConfig::default();
+        let head = b"GET /%61dmin//./secret.html HTTP/1.1\r\nHost: a\r\n\r\n";
+        let (request, _) = crate::http::parse_head(head, &config).unwrap().unwrap();
+        assert_eq!("/admin/secret.html", request.path());
+        assert!(auth.check(&request).is_some());
+    }
+
+    #[test]
+    fn rejects_malformed_password_files() {
+        let path = std::env::temp_dir().join(format!("htpasswd-{}", std::process::id()));
+        fs::write(&path, "# users\nferris:plaintext\n").unwrap();
+        let err = BasicAuth::from_file(&path, "site").unwrap_err();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert!(err.to_string().contains("line 2"));
+    }
+}
@@ -0,0 +1,317 @@
+// Load generator for the server: N client threads send a weighted mix of requests for a fixed
+// time and report throughput and latency percentiles.
+//
+// Point it at a running server with `--target`, or let it start servers in-process with one or
+// more `--serve MODE:WORKERS` options (MODE is `threads` or, on Linux, `polling`) to compare
+// pool sizes and server variants in one run. In-process servers answer `/` from the document
+// root, echo the body of `POST /echo` and sleep for `ms` milliseconds on `/work?ms=N` to
+// simulate slow handlers.
+//
+// With `--idle`, each run first opens that many connections that send half a request and then
+// go quiet until the run is over, which is where the two modes differ most.
+//
+// Usage: cargo run --release --bin loadtest -- [options]
+//
+//   --target HOST:PORT        server to test
+//   --serve MODE:WORKERS      start a server in-process; may be repeated (default threads:4)
+//   -c, --clients N           client threads (default 8)
+//   -d, --duration SECS       length of each run (default 5)
+//   -r, --request SPEC        METHOD:PATH[:WEIGHT], may be repeated (default GET:/)
+//   --body-size BYTES         body sent with POST and PUT requests (default 64)
+//   --close                   open a new connection for every request
+//   --idle N                  idle connections held open during each run (default 0)
+//
+// In-process servers log every job their workers pick up; pipe through `grep -v ^Worker` to
+// see only the results.
+
+use chapter20_final_project::http::{Request, Response};
+#[cfg(target_os = "linux")]
+use chapter20_final_project::PollingServer;
+use chapter20_final_project::{Client, Router, Server, ServerConfig};
+use std::env;
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+struct Options {
+    target: Option<String>,
+    serve: Vec<(Mode, usize)>,
+    clients: usize,
+    duration: Duration,
+    mix: Vec<(String, String, u32)>,
+    body_size: usize,
+    close: bool,
+    idle: usize,
+}
+
+#[derive(Clone, Copy)]
+enum Mode {
+    Threads,
+    #[cfg(target_os = "linux")]
+    Polling,
+}
+
+/// What one client thread saw.
+#[derive(Default)]
+struct Tally {
+    latencies: Vec<Duration>,
+    ok: usize,
+    not_ok: usize,
+    errors: usize,
+}
+
+fn main() {
+    let options = parse_args(env::args().skip(1)).unwrap_or_else(|message| {
+        eprintln!("loadtest: {}", message);
+        eprintln!("Usage: loadtest [--target HOST:PORT | --serve MODE:WORKERS]... [-c N] [-d SECS] [-r METHOD:PATH[:WEIGHT]]... [--body-size BYTES] [--close] [--idle N]");
+        process::exit(2);
+    });
+
+    println!(
+        "{:<20} {:>6} {:>9} {:>7} {:>7} {:>7} {:>9} {:>9} {:>9} {:>9}",
+        "server", "idle", "req/s", "ok", "non-2xx", "errors", "p50", "p90", "p99", "max"
+    );
+    match &options.target {
+        Some(target) => run(target, target, &options),
+        None => {
+            for &(mode, workers) in &options.serve {
+                let (label, addr) = start_server(mode, workers, &options);
+                run(&label, &addr, &options);
+            }
+        }
+    }
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+    let mut options = Options {
+        target: None,
+        serve: Vec::new(),
+        clients: 8,
+        duration: Duration::from_secs(5),
+        mix: Vec::new(),
+        body_size: 64,
+        close: false,
+        idle: 0,
+    };
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--target" => options.target = Some(value()?),
+            "--serve" => {
+                let spec = value()?;
+                let (mode, workers) = spec.split_once(':').unwrap_or((&spec, "4"));
+                let mode = match mode {
+                    "threads" => Mode::Threads,
+                    #[cfg(target_os = "linux")]
+                    "polling" => Mode::Polling,
+                    _ => return Err(format!("unknown server mode `{}`", mode)),
+                };
+                options.serve.push((mode, number(workers)?));
+            }
+            "-c" | "--clients" => options.clients = number(&value()?)?,
+            "-d" | "--duration" => options.duration = Duration::from_secs(number(&value()?)?),
+            "-r" | "--request" => options.mix.push(request_spec(&value()?)?),
+            "--body-size" => options.body_size = number(&value()?)?,
+            "--close" => options.close = true,
+            "--idle" => options.idle = number(&value()?)?,
+            _ => return Err(format!("unknown option `{}`", arg)),
+        }
+    }
+
+    if options.target.is_some() && !options.serve.is_empty() {
+        return Err(String::from("use either --target or --serve"));
+    }
+    if options.serve.is_empty() {
+        options.serve.push((Mode::Threads, 4));
+    }
+    if options.mix.is_empty() {
+        options
+            .mix
+            .push((String::from("GET"), String::from("/"), 1));
+    }
+    if options.clients == 0 {
+        return Err(String::from("need at least one client"));
+    }
+    Ok(options)
+}
+
+fn number<T: std::str::FromStr>(text: &str) -> Result<T, String> {
+    text.parse()
+        .map_err(|_| format!("`{}` is not a valid number", text))
+}
+
+/// `METHOD:PATH[:WEIGHT]`
+fn request_spec(spec: &str) -> Result<(String, String, u32), String> {
+    let (method, rest) = spec
+        .split_once(':')
+        .ok_or(format!("request `{}` is not METHOD:PATH[:WEIGHT]", spec))?;
+    let (path, weight) = match rest.rsplit_once(':') {
+        Some((path, weight)) if weight.parse::<u32>().is_ok() => (path, number(weight)?),
+        _ => (rest, 1),
+    };
+    if !path.starts_with('/') || weight == 0 {
+        return Err(format!("request `{}` is not METHOD:PATH[:WEIGHT]", spec));
+    }
+    Ok((method.to_ascii_uppercase(), path.to_string(), weight))
+}
+
+/// Start a server in-process and return its label and address.
+fn start_server(mode: Mode, workers: usize, options: &Options) -> (String, String) {
+    let config = ServerConfig {
+        max_connections_per_ip: options.idle + 4 * options.clients + 16,
+        // Idle connections must last the whole run rather than be answered with 408.
+        header_read_timeout: options.duration + Duration::from_secs(10),
+        ..ServerConfig::default()
+    };
+
+    let mut router = Router::new();
+    router.route("POST", "/echo", |request| {
+        Response::new(200).with_body(request.body.clone())
+    });
+    router.get("/work", |request| {
+        let ms = request
+            .query_params()
+            .get("ms")
+            .and_then(|ms| ms.parse().ok())
+            .unwrap_or(10);
+        thread::sleep(Duration::from_millis(ms));
+        Response::new(200).with_body("done")
+    });
+
+    match mode {
+        Mode::Threads => {
+            let server = Server::bind("127.0.0.1:0", workers, config)
+                .unwrap()
+                .with_router(router);
+            let addr = server.local_addr().unwrap();
+            thread::spawn(move || {
+                for stream in server.listener().incoming() {
+                    match stream {
+                        Ok(stream) => server.dispatch(stream),
+                        Err(e) => eprintln!("accept failed: {}", e),
+                    }
+                }
+            });
+            (format!("threads:{}", workers), addr.to_string())
+        }
+        #[cfg(target_os = "linux")]
+        Mode::Polling => {
+            let server = PollingServer::bind("127.0.0.1:0", 2, workers, config)
+                .unwrap()
+                .with_router(router);
+            let addr = server.local_addr().unwrap();
+            thread::spawn(move || server.run().unwrap());
+            (format!("polling:{}", workers), addr.to_string())
+        }
+    }
+}
+
+fn run(label: &str, addr: &str, options: &Options) {
+    // Held until the run is over. Connections the server refuses or drops still count as
+    // opened; how it copes with them is what is being measured.
+    let idle: Vec<TcpStream> = (0..options.idle)
+        .filter_map(|_| {
+            let mut stream = TcpStream::connect(addr).ok()?;
+            stream.write_all(b"GET / HTTP/1.1\r\n").ok()?;
+            Some(stream)
+        })
+        .collect();
+
+    let start = Instant::now();
+    let tallies: Vec<Tally> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..options.clients)
+            .map(|id| scope.spawn(move || client(id, addr, options, start)))
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+    let elapsed = start.elapsed().as_secs_f64();
+
+    let mut latencies: Vec<Duration> = tallies
+        .iter()
+        .flat_map(|t| t.latencies.iter().copied())
+        .collect();
+    latencies.sort_unstable();
+    let total = |count: fn(&Tally) -> usize| tallies.iter().map(count).sum::<usize>();
+
+    println!(
+        "{:<20} {:>6} {:>9.0} {:>7} {:>7} {:>7} {:>9} {:>9} {:>9} {:>9}",
+        label,
+        idle.len(),
+        latencies.len() as f64 / elapsed,
+        total(|t| t.ok),
+        total(|t| t.not_ok),
+        total(|t| t.errors),
+        format_latency(percentile(&latencies, 50.0)),
+        format_latency(percentile(&latencies, 90.0)),
+        format_latency(percentile(&latencies, 99.0)),
+        format_latency(latencies.last().copied()),
+    );
+}
+
+fn client(id: usize, addr: &str, options: &Options, start: Instant) -> Tally {
+    let http = Client::new().with_timeout(Duration::from_secs(10));
+    let mut tally = Tally::default();
+    let total_weight: u32 = options.mix.iter().map(|(_, _, w)| w).sum();
+    // A per-thread xorshift generator picks from the mix; good enough and deterministic.
+    let mut state = 0x9E37_79B9_7F4A_7C15_u64 ^ (id as u64 + 1);
+
+    while start.elapsed() < options.duration {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        let mut pick = (state % u64::from(total_weight)) as u32;
+        let (method, path, _) = options
+            .mix
+            .iter()
+            .find(|(_, _, weight)| {
+                let hit = pick < *weight;
+                pick = pick.saturating_sub(*weight);
+                hit
+            })
+            .unwrap();
+
+        let mut request = Request::new(method, path);
+        if method == "POST" || method == "PUT" {
+            request = request.with_body(vec![b'x'; options.body_size]);
+        }
+        if options.close {
+            request = request.with_header("Connection", "close");
+        }
+
+        let sent = Instant::now();
+        match http.send(addr, request) {
+            Ok(response) => {
+                tally.latencies.push(sent.elapsed());
+                if (200..400).contains(&response.status) {
+                    tally.ok += 1;
+                } else {
+                    tally.not_ok += 1;
+                }
+            }
+            Err(_) => tally.errors += 1,
+        }
+    }
+    tally
+}
+
+/// Nearest-rank percentile of sorted samples.
+fn percentile(sorted: &[Duration], p: f64) -> Option<Duration> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.clamp(1, sorted.len()) - 1])
+}
+
+fn format_latency(latency: Option<Duration>) -> String {
+    match latency {
+        Some(latency) if latency >= Duration::from_millis(10) => {
+            format!("{}ms", latency.as_millis())
+        }
+        Some(latency) => format!("{:.2}ms", latency.as_secs_f64() * 1000.0),
+        None => String::from("-"),
+    }
+}
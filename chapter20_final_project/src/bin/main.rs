@@ -1,45 +1,35 @@
-use chapter20_final_project::ThreadPool;
-use std::fs;
-use std::io::prelude::*;
-use std::net::TcpListener;
-use std::net::TcpStream;
+use chapter20_final_project::{Router, Server, ServerConfig};
 
 fn main() {
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
-    let pool = ThreadPool::new(4);
-
-    for stream in listener.incoming().take(2) {
-        let stream = stream.unwrap();
+    // `--polling` switches to the polling server, which runs until killed.
+    #[cfg(target_os = "linux")]
+    if std::env::args().any(|arg| arg == "--polling") {
+        use chapter20_final_project::PollingServer;
+        let server = PollingServer::bind("127.0.0.1:7878", 2, 4, ServerConfig::default()).unwrap();
+        server.run().unwrap();
+        return;
+    }
 
-        pool.execute(|| {
-            handle_connection(stream);
-        });
+    let mut router = Router::new();
+    router.websocket("/echo", |mut ws| {
+        while let Ok(Some(message)) = ws.recv() {
+            if ws.send(&message).is_err() {
+                break;
+            }
+        }
+    });
+
+    let server = Server::bind("127.0.0.1:7878", 4, ServerConfig::default())
+        .unwrap()
+        .with_router(router);
+
+    for stream in server.listener().incoming().take(2) {
+        // A failed accept, such as running out of file descriptors, only loses that client.
+        match stream {
+            Ok(stream) => server.dispatch(stream),
+            Err(e) => eprintln!("Could not accept connection: {}", e),
+        }
     }
 
     println!("Shutting down...");
 }
-
-fn handle_connection(mut stream: TcpStream) {
-    let mut buffer = [0; 1024];
-
-    stream.read(&mut buffer).unwrap();
-
-    let get = b"GET / HTTP/1.1\r\n";
-
-    let (status_line, filename) = if buffer.starts_with(get) {
-        ("HTTP/1.1 200 OK", "hello.html")
-    } else {
-        ("HTTP/1.1 404 NOT FOUND", "404.html")
-    };
-
-    let contents = fs::read_to_string(filename).unwrap();
-    let response = format!(
-        "{}\r\nContent-Length: {}\r\n\r\n{}",
-        status_line,
-        contents.len(),
-        contents
-    );
-
-    stream.write(response.as_bytes()).unwrap();
-    stream.flush().unwrap();
-}
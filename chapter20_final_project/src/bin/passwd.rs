@@ -0,0 +1,39 @@
+// Print a password file line for `BasicAuth`, with a random salt.
+//
+// Usage: cargo run --bin passwd -- NAME < password.txt
+//
+// The password is the first line of standard input, so it stays out of the shell history.
+// Append the output to the password file.
+
+use chapter20_final_project::auth::hash_password;
+use std::env;
+use std::io;
+use std::process;
+
+fn main() {
+    let name = match env::args().nth(1) {
+        Some(name) if !name.is_empty() && !name.contains(':') => name,
+        _ => {
+            eprintln!("Usage: passwd NAME < password.txt  (NAME must not contain ':')");
+            process::exit(2);
+        }
+    };
+
+    let mut password = String::new();
+    if let Err(e) = io::stdin().read_line(&mut password) {
+        eprintln!("passwd: could not read the password: {}", e);
+        process::exit(1);
+    }
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        eprintln!("passwd: the password is empty");
+        process::exit(1);
+    }
+
+    let mut salt = [0u8; 16];
+    if let Err(e) = getrandom::getrandom(&mut salt) {
+        eprintln!("passwd: no random bytes for the salt: {}", e);
+        process::exit(1);
+    }
+    println!("{}:{}", name, hash_password(password, &salt));
+}
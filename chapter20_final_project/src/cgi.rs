@@ -0,0 +1,311 @@
+use std::ffi::OsString;
+use std::io;
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::ServerConfig;
+use crate::http::{Request, Response};
+
+/// Runs an external program for every request, following CGI/1.1 (RFC 3875).
+///
+/// The request is described to the program through environment variables and the body is
+/// written to its stdin. The program prints CGI headers, a blank line and the body on stdout.
+/// Its stderr goes to the server's stderr.
+///
+/// Register it with a router through a closure, for example
+/// `router.prefix("/cgi-bin/tool", move |req| cgi.run(req))`.
+pub struct Cgi {
+    script_name: String,
+    program: PathBuf,
+    args: Vec<String>,
+    env: Vec<(String, String)>,
+    timeout: Duration,
+    max_output_size: usize,
+}
+
+impl Cgi {
+    /// `script_name` is the path prefix the program is mounted at; whatever follows it in the
+    /// request path is passed on as `PATH_INFO`.
+    pub fn new<P: Into<PathBuf>>(script_name: &str, program: P) -> Cgi {
+        Cgi {
+            script_name: script_name.trim_end_matches('/').to_string(),
+            program: program.into(),
+            args: Vec::new(),
+            env: Vec::new(),
+            timeout: Duration::from_secs(30),
+            max_output_size: ServerConfig::default().max_body_size,
+        }
+    }
+
+    /// Pass an extra argument to the program, e.g. the script for an interpreter.
+    pub fn arg(mut self, arg: &str) -> Cgi {
+        self.args.push(arg.to_string());
+        self
+    }
+
+    /// Set an extra environment variable for the program.
+    pub fn env(mut self, name: &str, value: &str) -> Cgi {
+        self.env.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Kill the program and answer 504 if it runs longer than this.
+    pub fn with_timeout(mut self, timeout: Duration) -> Cgi {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Answer 502 instead of buffering the output of a program that prints more than this,
+    /// headers included. Defaults to the default `max_body_size`.
+    pub fn with_max_output_size(mut self, bytes: usize) -> Cgi {
+        self.max_output_size = bytes;
+        self
+    }
+
+    pub fn run(&self, request: &Request) -> Response {
+        match self.execute(request) {
+            Ok(output) => parse_output(&output),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                eprintln!("CGI {} timed out", self.program.display());
+                Response::new(504)
+            }
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                eprintln!("CGI {}: {}", self.program.display(), e);
+                Response::new(502)
+            }
+            Err(e) => {
+                eprintln!("CGI {} failed: {}", self.program.display(), e);
+                Response::new(500)
+            }
+        }
+    }
+
+    fn execute(&self, request: &Request) -> io::Result<Vec<u8>> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .env_clear()
+            .envs(std::env::var_os("PATH").map(|path| (OsString::from("PATH"), path)))
+            .envs(self.environment(request))
+            .envs(self.env.iter().cloned())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()?;
+
+        // Feed stdin and drain stdout on their own threads so neither pipe can fill up and
+        // stall the program while we wait for it.
+        let mut stdin = child.stdin.take().unwrap();
+        let body = request.body.clone();
+        let writer = thread::spawn(move || {
+            // The program is free to ignore its input and exit early.
+            let _ = stdin.write_all(&body);
+        });
+        // Stop reading once the output is too large. Dropping the pipe then makes the program's
+        // next write fail, so it does not have to run into the timeout.
+        let stdout = child.stdout.take().unwrap();
+        let max = self.max_output_size;
+        let reader = thread::spawn(move || {
+            let mut output = Vec::new();
+            stdout.take(max as u64 + 1).read_to_end(&mut output)?;
+            if output.len() > max {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("output larger than {} bytes", max),
+                ));
+            }
+            Ok(output)
+        });
+
+        let status = wait_with_deadline(&mut child, Instant::now() + self.timeout)?;
+        writer.join().unwrap();
+        let output = reader.join().unwrap()?;
+
+        if !status.success() && output.is_empty() {
+            return Err(io::Error::other(format!("exited with {}", status)));
+        }
+        Ok(output)
+    }
+
+    fn environment(&self, request: &Request) -> Vec<(String, String)> {
+        let path = request.path();
+        let path_info = path.strip_prefix(self.script_name.as_str()).unwrap_or("");
+        let host = request.header("Host").unwrap_or("localhost");
+        let (server_name, server_port) = split_host(host);
+
+        let mut env = vec![
+            ("GATEWAY_INTERFACE", String::from("CGI/1.1")),
+            ("SERVER_SOFTWARE", String::from("chapter20_final_project")),
+            ("SERVER_PROTOCOL", request.version.clone()),
+            ("SERVER_NAME", server_name.to_string()),
+            ("SERVER_PORT", server_port.to_string()),
+            ("REQUEST_METHOD", request.method.clone()),
+            ("SCRIPT_NAME", self.script_name.clone()),
+            ("PATH_INFO", path_info.to_string()),
+            ("QUERY_STRING", request.query().unwrap_or("").to_string()),
+        ];
+        if !request.body.is_empty() {
+            env.push(("CONTENT_LENGTH", request.body.len().to_string()));
+        }
+        if let Some(content_type) = request.header("Content-Type") {
+            env.push(("CONTENT_TYPE", content_type.to_string()));
+        }
+        if let Some(addr) = request.remote_addr {
+            env.push(("REMOTE_ADDR", addr.ip().to_string()));
+            env.push(("REMOTE_PORT", addr.port().to_string()));
+        }
+
+        let mut env: Vec<(String, String)> =
+            env.into_iter().map(|(n, v)| (n.to_string(), v)).collect();
+
+        // The remaining headers become HTTP_* variables. Credentials are not handed to scripts,
+        // and neither is `Proxy`: as HTTP_PROXY it would tell the script's HTTP libraries to
+        // send their requests through a server of the client's choosing ("httpoxy"). Names
+        // with a `_` are dropped, as `X_User` would otherwise pass for a proxy's `X-User`.
+        for (name, value) in &request.headers {
+            if name.contains('_')
+                || ["Content-Type", "Content-Length", "Authorization", "Proxy"]
+                    .iter()
+                    .any(|h| name.eq_ignore_ascii_case(h))
+            {
+                continue;
+            }
+            let var = format!("HTTP_{}", name.to_ascii_uppercase().replace('-', "_"));
+            match env.iter_mut().find(|(n, _)| *n == var) {
+                Some((_, existing)) => {
+                    existing.push_str(", ");
+                    existing.push_str(value);
+                }
+                None => env.push((var, value.clone())),
+            }
+        }
+        env
+    }
+}
+
+/// Split a `Host` header into name and port. IPv6 literals keep their brackets, as RFC 3875
+/// wants them in `SERVER_NAME`.
+fn split_host(host: &str) -> (&str, &str) {
+    let name_end = match host.find(']') {
+        Some(end) if host.starts_with('[') => end + 1,
+        _ => host.rfind(':').unwrap_or(host.len()),
+    };
+    let (name, rest) = host.split_at(name_end);
+    match rest.strip_prefix(':') {
+        Some(port) if !port.is_empty() => (name, port),
+        _ => (name, "80"),
+    }
+}
+
+fn wait_with_deadline(
+    child: &mut Child,
+    deadline: Instant,
+) -> io::Result<std::process::ExitStatus> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "CGI program timed out",
+            ));
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+/// Turn the program's output into a response. `Status` sets the status code, a `Location`
+/// without `Status` means a redirect, and every other header is passed through.
+fn parse_output(output: &[u8]) -> Response {
+    // Scripts commonly end lines with a bare "\n" rather than "\r\n"; accept both.
+    let (head_end, body_start) = match (find(output, b"\r\n\r\n"), find(output, b"\n\n")) {
+        (Some(crlf), Some(lf)) if lf < crlf => (lf, lf + 2),
+        (Some(crlf), _) => (crlf, crlf + 4),
+        (None, Some(lf)) => (lf, lf + 2),
+        (None, None) => {
+            eprintln!("CGI output has no header section");
+            return Response::new(500);
+        }
+    };
+
+    let head = match std::str::from_utf8(&output[..head_end]) {
+        Ok(head) => head,
+        Err(_) => return Response::new(500),
+    };
+
+    let mut response = Response::new(200).with_body(&output[body_start..]);
+    let mut has_status = false;
+    for line in head.lines() {
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => return Response::new(500),
+        };
+        if name.eq_ignore_ascii_case("Status") {
+            match value
+                .split_whitespace()
+                .next()
+                .and_then(|code| code.parse().ok())
+            {
+                Some(status) => response.status = status,
+                None => return Response::new(500),
+            }
+            has_status = true;
+        } else {
+            if name.eq_ignore_ascii_case("Location") && !has_status {
+                response.status = 302;
+            }
+            response = response.with_header(name, value);
+        }
+    }
+    response
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_status_and_headers() {
+        let response = parse_output(b"Status: 404 Not Found\nContent-Type: text/plain\n\ngone");
+
+        assert_eq!(404, response.status);
+        assert_eq!(Some("text/plain"), response.header("Content-Type"));
+        assert_eq!(b"gone".to_vec(), response.body);
+    }
+
+    #[test]
+    fn location_without_status_redirects() {
+        let response = parse_output(b"Location: /elsewhere\r\n\r\n");
+        assert_eq!(302, response.status);
+    }
+
+    #[test]
+    fn environment_leaves_out_proxy_and_keeps_ipv6_hosts() {
+        let cgi = Cgi::new("/cgi-bin/tool", "true");
+        let request = Request::new("GET", "/cgi-bin/tool/x")
+            .with_header("Host", "[::1]:8080")
+            .with_header("Proxy", "http://evil.example:3128")
+            .with_header("X_Trace", "spoofed")
+            .with_header("X-Trace", "abc");
+        let env = cgi.environment(&request);
+        let var = |name: &str| env.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str());
+
+        assert_eq!(Some("[::1]"), var("SERVER_NAME"));
+        assert_eq!(Some("8080"), var("SERVER_PORT"));
+        assert_eq!(None, var("HTTP_PROXY"));
+        assert_eq!(Some("abc"), var("HTTP_X_TRACE"));
+
+        assert_eq!(("[::1]", "80"), split_host("[::1]"));
+        assert_eq!(("example.com", "80"), split_host("example.com"));
+        assert_eq!(("example.com", "81"), split_host("example.com:81"));
+    }
+}
@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::http::{self, Connection, Request, Response};
+
+/// A small blocking HTTP/1.1 client built on the same `Request` and `Response` types as the
+/// server.
+///
+/// Connections are kept open per host and reused for later requests unless either side asks to
+/// close them. The client can be shared between threads; each request takes a connection of
+/// its own.
+pub struct Client {
+    timeout: Duration,
+    max_body_size: usize,
+    idle: Mutex<HashMap<String, Vec<Connection>>>,
+}
+
+/// Why `Client::try_send` failed: `Connect` means no connection to the host could be made,
+/// so the request cannot have reached it.
+pub(crate) enum SendError {
+    Connect(io::Error),
+    Exchange(io::Error),
+}
+
+impl From<SendError> for io::Error {
+    fn from(e: SendError) -> io::Error {
+        match e {
+            SendError::Connect(e) | SendError::Exchange(e) => e,
+        }
+    }
+}
+
+impl Default for Client {
+    fn default() -> Client {
+        Client::new()
+    }
+}
+
+impl Client {
+    pub fn new() -> Client {
+        Client {
+            timeout: Duration::from_secs(30),
+            max_body_size: usize::MAX,
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Bound for connecting and for every single read and write.
+    pub fn with_timeout(mut self, timeout: Duration) -> Client {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Fail with `InvalidData` instead of reading response bodies larger than this. There is no
+    /// limit by default.
+    pub fn with_max_body_size(mut self, bytes: usize) -> Client {
+        self.max_body_size = bytes;
+        self
+    }
+
+    pub fn get(&self, url: &str) -> io::Result<Response> {
+        let (host, target) = split_url(url)?;
+        self.send(host, Request::new("GET", target))
+    }
+
+    pub fn post(&self, url: &str, content_type: &str, body: &[u8]) -> io::Result<Response> {
+        let (host, target) = split_url(url)?;
+        let request = Request::new("POST", target)
+            .with_header("Content-Type", content_type)
+            .with_body(body);
+        self.send(host, request)
+    }
+
+    /// Send `request` to `host` (`name:port`) and wait for the response. A `Host` header is
+    /// added if the request has none.
+    pub fn send(&self, host: &str, request: Request) -> io::Result<Response> {
+        Ok(self.try_send(host, request)?)
+    }
+
+    pub(crate) fn try_send(&self, host: &str, mut request: Request) -> Result<Response, SendError> {
+        if request.header("Host").is_none() {
+            request
+                .headers
+                .push((String::from("Host"), host.to_string()));
+        }
+
+        // A pooled connection may have been closed by the server in the meantime. That only
+        // shows when we use it, so retry once on a fresh connection, but only if the server
+        // cannot have seen the request: sending failed, or the connection was closed without a
+        // byte of response. After a timeout or a partial response the request may well have
+        // been handled, and sending it again could run a POST twice.
+        let pooled = self.idle.lock().unwrap().get_mut(host).and_then(Vec::pop);
+        if let Some(mut conn) = pooled {
+            if request.write_to(conn.stream()).is_ok() {
+                match self.finish(host, conn, &request) {
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+                    result => return result.map_err(SendError::Exchange),
+                }
+            }
+        }
+        let mut conn = self.connect(host).map_err(SendError::Connect)?;
+        request
+            .write_to(conn.stream())
+            .map_err(SendError::Exchange)?;
+        self.finish(host, conn, &request)
+            .map_err(SendError::Exchange)
+    }
+
+    fn connect(&self, host: &str) -> io::Result<Connection> {
+        let with_port = if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{}:80", host)
+        };
+        let addr = with_port
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "unknown host"))?;
+        let stream = TcpStream::connect_timeout(&addr, self.timeout)?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        stream.set_nodelay(true)?;
+        Ok(Connection::new(stream))
+    }
+
+    /// Read the response to `request`, which has been sent on `conn`, and pool the connection
+    /// if it can be reused.
+    fn finish(&self, host: &str, mut conn: Connection, request: &Request) -> io::Result<Response> {
+        let response = conn.read_response(&request.method, self.timeout, self.max_body_size)?;
+
+        let closing = |value: Option<&str>| value.is_some_and(|v| http::has_token(v, "close"));
+        let delimited = response.header("Content-Length").is_some()
+            || response.header("Transfer-Encoding").is_some()
+            || request.method == "HEAD";
+        if !closing(request.header("Connection"))
+            && !closing(response.header("Connection"))
+            && delimited
+        {
+            self.idle
+                .lock()
+                .unwrap()
+                .entry(host.to_string())
+                .or_default()
+                .push(conn);
+        }
+
+        Ok(response)
+    }
+}
+
+/// Split `http://host:port/path` into `host:port` and `/path`.
+fn split_url(url: &str) -> io::Result<(&str, &str)> {
+    let rest = url.strip_prefix("http://").ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "only http:// URLs are supported",
+        )
+    })?;
+
+    Ok(match rest.find('/') {
+        Some(pos) => (&rest[..pos], &rest[pos..]),
+        None => (rest, "/"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_urls() {
+        assert_eq!(
+            ("localhost:7878", "/a?b=c"),
+            split_url("http://localhost:7878/a?b=c").unwrap()
+        );
+        assert_eq!(("localhost", "/"), split_url("http://localhost").unwrap());
+        assert!(split_url("https://localhost").is_err());
+    }
+}
@@ -0,0 +1,62 @@
+use std::time::Duration;
+
+/// Limits applied to every connection the server accepts.
+///
+/// The defaults are tuned so that a single slow or idle client cannot hold on to one of the
+/// `ThreadPool` workers for long.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Time allowed to receive the complete request line and headers.
+    pub header_read_timeout: Duration,
+    /// Time an idle keep-alive connection may wait for its next request.
+    pub keep_alive_timeout: Duration,
+    /// Time allowed to receive the request body once the headers are in.
+    pub body_read_timeout: Duration,
+    /// Time allowed for each write of the response.
+    pub write_timeout: Duration,
+    /// Upper bound for the request line plus all headers, in bytes.
+    pub max_header_size: usize,
+    /// Upper bound for the request body, in bytes.
+    pub max_body_size: usize,
+    /// Upper bound for the body of a request to an upload route, in bytes. Those bodies are
+    /// streamed to disk rather than held in memory, so this can be much larger.
+    pub max_upload_size: u64,
+    /// Slowest rate, in bytes per second, at which an upload may arrive once the first
+    /// `body_read_timeout` is over; slower uploads are answered with 408. Zero turns the check
+    /// off.
+    pub min_upload_rate: u64,
+    /// Number of connections a single client address may hold open at once.
+    pub max_connections_per_ip: usize,
+    /// Longest an event stream may stay quiet before a heartbeat comment is sent.
+    pub event_heartbeat_interval: Duration,
+    /// Number of WebSocket connections served at once. Each has a thread of its own; upgrade
+    /// requests beyond this are answered with 503.
+    pub max_websockets: usize,
+    /// Time a WebSocket may stay silent before it is pinged. If the ping is not answered
+    /// within the same time again, the connection is closed.
+    pub websocket_idle_timeout: Duration,
+    /// Time `PollingServer` gives a handler, queueing on the pool included, before it answers
+    /// 503 and closes the connection. `Server` runs each handler on the connection's own
+    /// worker and does not use it.
+    pub handler_timeout: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            header_read_timeout: Duration::from_secs(10),
+            keep_alive_timeout: Duration::from_secs(5),
+            body_read_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(10),
+            max_header_size: 8 * 1024,
+            max_body_size: 1024 * 1024,
+            max_upload_size: 1024 * 1024 * 1024,
+            min_upload_rate: 16 * 1024,
+            max_connections_per_ip: 8,
+            event_heartbeat_interval: Duration::from_secs(15),
+            max_websockets: 256,
+            websocket_idle_timeout: Duration::from_secs(60),
+            handler_timeout: Duration::from_secs(30),
+        }
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::http::{self, RequestError, Response};
+
+/// Everything that can go wrong while answering a request. Each variant maps to the status
+/// code sent to the client.
+#[derive(Debug)]
+pub enum ServerError {
+    NotFound,
+    Forbidden,
+    MethodNotAllowed,
+    /// The request could not be read or parsed.
+    Request(RequestError),
+    /// Reading a file or talking to another process failed.
+    Io(io::Error),
+    /// A handler panicked; the string is the panic message.
+    Panic(String),
+    /// A bug or misconfiguration on the server's side, such as a broken template. The message
+    /// is only logged.
+    Internal(String),
+    /// Any other status a handler wants to fail with.
+    Status(u16),
+}
+
+impl ServerError {
+    pub fn status(&self) -> u16 {
+        match self {
+            ServerError::NotFound => 404,
+            ServerError::Forbidden => 403,
+            ServerError::MethodNotAllowed => 405,
+            ServerError::Request(e) => e.status().unwrap_or(400),
+            ServerError::Io(e) => match e.kind() {
+                io::ErrorKind::NotFound => 404,
+                io::ErrorKind::PermissionDenied => 403,
+                io::ErrorKind::TimedOut => 504,
+                _ => 500,
+            },
+            ServerError::Panic(_) | ServerError::Internal(_) => 500,
+            ServerError::Status(status) => *status,
+        }
+    }
+
+    /// What the client may be told. Details of server-side failures only go to the log.
+    fn public_message(&self) -> String {
+        match self {
+            ServerError::Request(e) => e.to_string(),
+            _ => http::reason_phrase(self.status()).to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServerError::NotFound => write!(f, "not found"),
+            ServerError::Forbidden => write!(f, "forbidden"),
+            ServerError::MethodNotAllowed => write!(f, "method not allowed"),
+            ServerError::Request(e) => write!(f, "{}", e),
+            ServerError::Io(e) => write!(f, "{}", e),
+            ServerError::Panic(message) => write!(f, "handler panicked: {}", message),
+            ServerError::Internal(message) => write!(f, "{}", message),
+            ServerError::Status(status) => {
+                write!(f, "{} {}", status, http::reason_phrase(*status))
+            }
+        }
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+impl From<io::Error> for ServerError {
+    fn from(e: io::Error) -> ServerError {
+        ServerError::Io(e)
+    }
+}
+
+impl From<RequestError> for ServerError {
+    fn from(e: RequestError) -> ServerError {
+        ServerError::Request(e)
+    }
+}
+
+/// A bare response with the error's status, for handlers that fail. The server fills in the
+/// error page.
+impl From<ServerError> for Response {
+    fn from(e: ServerError) -> Response {
+        if e.status() >= 500 {
+            eprintln!("Error answering request: {}", e);
+        }
+        Response::new(e.status())
+    }
+}
+
+/// The pages sent for error statuses.
+///
+/// A page is an HTML file that may use `{{status}}`, `{{reason}}` and `{{message}}`. Statuses
+/// without a page of their own, or whose file cannot be read, get a small built-in page, so
+/// rendering an error never fails.
+#[derive(Debug, Clone)]
+pub struct ErrorPages {
+    pages: HashMap<u16, PathBuf>,
+    dir: Option<PathBuf>,
+    base: PathBuf,
+}
+
+impl ErrorPages {
+    /// No custom pages at all.
+    pub fn new() -> ErrorPages {
+        ErrorPages {
+            pages: HashMap::new(),
+            dir: None,
+            base: PathBuf::new(),
+        }
+    }
+
+    pub fn with_page<P: AsRef<Path>>(mut self, status: u16, path: P) -> ErrorPages {
+        self.pages.insert(status, path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Look for `<status>.html` in `dir` for statuses without a page of their own.
+    pub fn with_dir<P: AsRef<Path>>(mut self, dir: P) -> ErrorPages {
+        self.dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Resolve relative page paths against `dir` instead of the working directory.
+    pub fn relative_to<P: AsRef<Path>>(mut self, dir: P) -> ErrorPages {
+        self.base = dir.as_ref().to_path_buf();
+        self
+    }
+
+    pub fn render(&self, error: &ServerError) -> Response {
+        let status = error.status();
+        if status >= 500 {
+            eprintln!("Error answering request: {}", error);
+        }
+        self.render_status(status, &error.public_message())
+    }
+
+    pub fn render_status(&self, status: u16, message: &str) -> Response {
+        let reason = http::reason_phrase(status);
+        let template = self
+            .page(status)
+            .unwrap_or_else(|| String::from(DEFAULT_PAGE));
+        let body = template
+            .replace("{{status}}", &status.to_string())
+            .replace("{{reason}}", reason)
+            .replace("{{message}}", &escape_html(message));
+
+        Response::new(status)
+            .with_header("Content-Type", "text/html; charset=utf-8")
+            .with_body(body)
+    }
+
+    fn page(&self, status: u16) -> Option<String> {
+        let path = match (self.pages.get(&status), &self.dir) {
+            (Some(path), _) => self.base.join(path),
+            (None, Some(dir)) => self.base.join(dir).join(format!("{}.html", status)),
+            (None, None) => return None,
+        };
+        match fs::read_to_string(&path) {
+            Ok(page) => Some(page),
+            Err(e) => {
+                if self.pages.contains_key(&status) {
+                    eprintln!("Could not read error page {}: {}", path.display(), e);
+                }
+                None
+            }
+        }
+    }
+}
+
+/// The book's `404.html` for missing pages.
+impl Default for ErrorPages {
+    fn default() -> ErrorPages {
+        ErrorPages::new().with_page(404, "404.html")
+    }
+}
+
+const DEFAULT_PAGE: &str = "<!DOCTYPE html>
+<html lang=\"en\">
+    <head>
+        <meta charset=\"utf-8\">
+        <title>{{status}} {{reason}}</title>
+    </head>
+    <body>
+        <h1>{{status}} {{reason}}</h1>
+        <p>{{message}}</p>
+    </body>
+</html>
+";
+
+pub(crate) fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn io_errors_map_to_statuses() {
+        let missing = io::Error::new(io::ErrorKind::NotFound, "gone");
+        let broken = io::Error::other("disk on fire");
+
+        assert_eq!(404, ServerError::from(missing).status());
+        assert_eq!(500, ServerError::from(broken).status());
+        assert_eq!(413, ServerError::from(RequestError::BodyTooLarge).status());
+    }
+
+    #[test]
+    fn renders_templates_and_falls_back() {
+        let path = std::env::temp_dir().join(format!("418-{}.html", std::process::id()));
+        fs::write(&path, "<p>{{status}} {{message}}</p>").unwrap();
+        let pages = ErrorPages::new().with_page(418, &path);
+
+        let custom = pages.render_status(418, "<teapot>");
+        fs::remove_file(&path).unwrap();
+        assert_eq!(b"<p>418 &lt;teapot&gt;</p>".to_vec(), custom.body);
+
+        let fallback = pages.render(&ServerError::Io(io::Error::other("secret path /etc/x")));
+        let body = String::from_utf8(fallback.body).unwrap();
+        assert_eq!(500, fallback.status);
+        assert!(body.contains("500 Internal Server Error"));
+        assert!(!body.contains("secret"));
+    }
+}
@@ -0,0 +1,532 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::config::ServerConfig;
+use crate::http::{Connection, Request, RequestError};
+
+/// Largest header block of a single multipart part.
+const MAX_PART_HEADER_SIZE: usize = 8 * 1024;
+
+/// Largest value of a non-file multipart field; files go to disk and are not limited here.
+const MAX_FIELD_SIZE: usize = 64 * 1024;
+
+/// Decoded `name=value` pairs, in the order they were sent. Names may repeat.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Params(Vec<(String, String)>);
+
+impl Params {
+    /// The first value for `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.0
+            .iter()
+            .filter(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+            .collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[derive(Debug)]
+pub enum FormError {
+    /// The request does not carry the expected `Content-Type`.
+    WrongContentType,
+    Malformed(&'static str),
+    TooLarge,
+    /// A streamed upload needs a `Content-Length`.
+    LengthRequired,
+    Io(io::Error),
+}
+
+impl FormError {
+    pub fn status(&self) -> u16 {
+        match self {
+            FormError::WrongContentType => 415,
+            FormError::Malformed(_) => 400,
+            FormError::TooLarge => 413,
+            FormError::LengthRequired => 411,
+            FormError::Io(e) if e.kind() == io::ErrorKind::TimedOut => 408,
+            FormError::Io(_) => 500,
+        }
+    }
+}
+
+impl fmt::Display for FormError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FormError::WrongContentType => write!(f, "unexpected content type"),
+            FormError::Malformed(why) => write!(f, "malformed form data: {}", why),
+            FormError::TooLarge => write!(f, "form field too large"),
+            FormError::LengthRequired => write!(f, "upload length required"),
+            FormError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for FormError {}
+
+impl From<io::Error> for FormError {
+    fn from(e: io::Error) -> FormError {
+        FormError::Io(e)
+    }
+}
+
+impl From<RequestError> for FormError {
+    fn from(e: RequestError) -> FormError {
+        match e {
+            RequestError::BodyTooLarge => FormError::TooLarge,
+            RequestError::LengthRequired => FormError::LengthRequired,
+            RequestError::Malformed(why) => FormError::Malformed(why),
+            e => FormError::Io(e.into()),
+        }
+    }
+}
+
+impl Request {
+    /// The decoded query string parameters.
+    pub fn query_params(&self) -> Params {
+        parse_urlencoded(self.query().unwrap_or(""))
+    }
+
+    /// The decoded body of an `application/x-www-form-urlencoded` request.
+    pub fn form(&self) -> Result<Params, FormError> {
+        match self.header("Content-Type").map(media_type) {
+            Some(t) if t.eq_ignore_ascii_case("application/x-www-form-urlencoded") => {
+                Ok(parse_urlencoded(&String::from_utf8_lossy(&self.body)))
+            }
+            _ => Err(FormError::WrongContentType),
+        }
+    }
+
+    /// Parse a `multipart/form-data` body. Uploaded files are written to temporary files.
+    ///
+    /// The body has already been read into memory and is bounded by `max_body_size`; routes
+    /// added with `Router::upload` stream larger uploads from the connection instead.
+    pub fn multipart(&self) -> Result<Multipart, FormError> {
+        Multipart::parse(&self.body[..], &multipart_boundary(self)?, self.body.len())
+    }
+}
+
+impl Connection {
+    /// Parse the `multipart/form-data` body of `request`, whose head was just read, straight
+    /// from the connection. Files go to disk as they arrive, so uploads are bounded by
+    /// `max_upload_size` rather than by memory. On success the whole body has been read and
+    /// the connection can carry another request.
+    pub fn multipart(
+        &mut self,
+        request: &Request,
+        config: &ServerConfig,
+    ) -> Result<Multipart, FormError> {
+        let boundary = multipart_boundary(request)?;
+        let mut body = self.body_reader(request, config)?;
+        let multipart = Multipart::parse(&mut body, &boundary, config.max_body_size)?;
+        // Whatever follows the closing boundary is ignored, but it is still part of the body.
+        io::copy(&mut body, &mut io::sink())?;
+        Ok(multipart)
+    }
+}
+
+fn multipart_boundary(request: &Request) -> Result<String, FormError> {
+    let content_type = request
+        .header("Content-Type")
+        .ok_or(FormError::WrongContentType)?;
+    if !media_type(content_type).eq_ignore_ascii_case("multipart/form-data") {
+        return Err(FormError::WrongContentType);
+    }
+    header_param(content_type, "boundary").ok_or(FormError::Malformed("missing boundary"))
+}
+
+/// Parse `a=1&b=two+words` style data. Invalid escapes are kept as they are.
+pub fn parse_urlencoded(data: &str) -> Params {
+    Params(
+        data.split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (percent_decode(name, true), percent_decode(value, true))
+            })
+            .collect(),
+    )
+}
+
+/// Decode `%XX` escapes, and `+` as a space if `plus_as_space` is set. Invalid UTF-8 in the
+/// result is replaced rather than rejected.
+pub fn percent_decode(text: &str, plus_as_space: bool) -> String {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() && hex_pair(bytes[i + 1], bytes[i + 2]).is_some() => {
+                out.push(hex_pair(bytes[i + 1], bytes[i + 2]).unwrap());
+                i += 3;
+            }
+            b'+' if plus_as_space => {
+                out.push(b' ');
+                i += 1;
+            }
+            b => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn hex_pair(hi: u8, lo: u8) -> Option<u8> {
+    let digit = |c: u8| (c as char).to_digit(16);
+    Some((digit(hi)? * 16 + digit(lo)?) as u8)
+}
+
+/// The `type/subtype` part of a header like `text/html; charset=utf-8`.
+pub fn media_type(value: &str) -> &str {
+    value.split(';').next().unwrap_or("").trim()
+}
+
+/// A `name=value` parameter of a header such as `Content-Type` or `Content-Disposition`,
+/// with surrounding quotes removed.
+pub fn header_param(value: &str, name: &str) -> Option<String> {
+    value.split(';').skip(1).find_map(|param| {
+        let (n, v) = param.split_once('=')?;
+        if !n.trim().eq_ignore_ascii_case(name) {
+            return None;
+        }
+        let v = v.trim();
+        let v = v
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .unwrap_or(v);
+        Some(v.replace("\\\"", "\""))
+    })
+}
+
+/// The parsed contents of a `multipart/form-data` body.
+#[derive(Debug, Default)]
+pub struct Multipart {
+    pub fields: Params,
+    pub files: Vec<UploadedFile>,
+}
+
+/// An uploaded file, stored in a temporary file that is deleted when this value is dropped
+/// unless it is `persist`ed first.
+#[derive(Debug)]
+pub struct UploadedFile {
+    /// The form field name.
+    pub name: String,
+    /// The file name the client reported. Never trust it as a path.
+    pub filename: String,
+    pub content_type: Option<String>,
+    pub size: u64,
+    path: PathBuf,
+}
+
+impl UploadedFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Move the file to `to` so it survives the request.
+    pub fn persist<P: AsRef<Path>>(self, to: P) -> io::Result<()> {
+        fs::rename(&self.path, to.as_ref()).or_else(|_| {
+            // Renaming fails across file systems; fall back to copying.
+            fs::copy(&self.path, to.as_ref()).map(|_| ())
+        })
+    }
+}
+
+impl Drop for UploadedFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl Multipart {
+    /// Parse a multipart body from `reader`, streaming file parts straight to disk so only a
+    /// small window of the body is held in memory at any time.
+    ///
+    /// What is kept in memory, the values of plain fields and the headers of every part, may
+    /// add up to `max_in_memory` bytes; more is `TooLarge`.
+    pub fn parse<R: Read>(
+        reader: R,
+        boundary: &str,
+        max_in_memory: usize,
+    ) -> Result<Multipart, FormError> {
+        let mut input = Scanner::new(reader);
+        let delimiter = format!("\r\n--{}", boundary).into_bytes();
+        let mut multipart = Multipart::default();
+        let mut budget = max_in_memory;
+        let mut charge = |bytes: usize| {
+            budget = budget.checked_sub(bytes).ok_or(FormError::TooLarge)?;
+            Ok::<usize, FormError>(budget)
+        };
+
+        // The preamble is followed by the first boundary, which has no leading CRLF.
+        if !input.skip_until(&delimiter[2..])? {
+            return Err(FormError::Malformed("missing first boundary"));
+        }
+
+        loop {
+            match input.take(2)?.as_slice() {
+                b"--" => return Ok(multipart),
+                b"\r\n" => {}
+                _ => return Err(FormError::Malformed("bad boundary line")),
+            }
+
+            let headers = input.read_headers()?;
+            let left = charge(headers.iter().map(|(n, v)| n.len() + v.len()).sum())?;
+            let disposition = headers
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case("Content-Disposition"))
+                .map(|(_, v)| v.as_str())
+                .ok_or(FormError::Malformed("part without Content-Disposition"))?;
+            let name = header_param(disposition, "name")
+                .ok_or(FormError::Malformed("part without name"))?;
+            let content_type = headers
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case("Content-Type"))
+                .map(|(_, v)| v.clone());
+
+            match header_param(disposition, "filename") {
+                Some(filename) => {
+                    let (path, mut file) = temp_file()?;
+                    // Create the value first so the file is cleaned up if copying fails.
+                    let mut upload = UploadedFile {
+                        name,
+                        filename,
+                        content_type,
+                        size: 0,
+                        path,
+                    };
+                    upload.size = input.copy_until(&delimiter, &mut file, u64::MAX)?;
+                    multipart.files.push(upload);
+                }
+                None => {
+                    let mut value = Vec::new();
+                    let limit = MAX_FIELD_SIZE.min(left);
+                    input.copy_until(&delimiter, &mut value, limit as u64)?;
+                    charge(value.len())?;
+                    multipart
+                        .fields
+                        .0
+                        .push((name, String::from_utf8_lossy(&value).into_owned()));
+                }
+            }
+        }
+    }
+}
+
+fn temp_file() -> io::Result<(PathBuf, File)> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    loop {
+        let path = std::env::temp_dir().join(format!(
+            "chapter20-upload-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            // Left over from an earlier process with the same id; pick the next name.
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// A reader with a small look-ahead buffer for finding delimiters in a stream.
+struct Scanner<R> {
+    reader: R,
+    buf: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: Read> Scanner<R> {
+    fn new(reader: R) -> Scanner<R> {
+        Scanner {
+            reader,
+            buf: Vec::new(),
+            eof: false,
+        }
+    }
+
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.eof {
+            return Ok(false);
+        }
+        let mut chunk = [0; 8192];
+        let n = self.reader.read(&mut chunk)?;
+        self.buf.extend_from_slice(&chunk[..n]);
+        self.eof = n == 0;
+        Ok(n > 0)
+    }
+
+    fn take(&mut self, n: usize) -> Result<Vec<u8>, FormError> {
+        while self.buf.len() < n {
+            if !self.fill()? {
+                return Err(FormError::Malformed("unexpected end of body"));
+            }
+        }
+        Ok(self.buf.drain(..n).collect())
+    }
+
+    /// Discard everything up to and including `delimiter`. Returns false if it never shows.
+    fn skip_until(&mut self, delimiter: &[u8]) -> io::Result<bool> {
+        let mut sink = io::sink();
+        match self.copy_until(delimiter, &mut sink, u64::MAX) {
+            Ok(_) => Ok(true),
+            Err(FormError::Io(e)) => Err(e),
+            Err(_) => Ok(false),
+        }
+    }
+
+    /// Copy bytes to `out` until `delimiter`, which is consumed but not copied. Only the
+    /// bytes that might be the start of the delimiter are held back between reads.
+    fn copy_until<W: Write>(
+        &mut self,
+        delimiter: &[u8],
+        out: &mut W,
+        limit: u64,
+    ) -> Result<u64, FormError> {
+        let mut copied = 0u64;
+
+        loop {
+            if let Some(pos) = find(&self.buf, delimiter) {
+                copied += pos as u64;
+                if copied > limit {
+                    return Err(FormError::TooLarge);
+                }
+                out.write_all(&self.buf[..pos])?;
+                self.buf.drain(..pos + delimiter.len());
+                return Ok(copied);
+            }
+
+            let safe = self.buf.len().saturating_sub(delimiter.len() - 1);
+            copied += safe as u64;
+            if copied > limit {
+                return Err(FormError::TooLarge);
+            }
+            out.write_all(&self.buf[..safe])?;
+            self.buf.drain(..safe);
+
+            if !self.fill()? {
+                return Err(FormError::Malformed("missing closing boundary"));
+            }
+        }
+    }
+
+    fn read_headers(&mut self) -> Result<Vec<(String, String)>, FormError> {
+        let mut head = Vec::new();
+        self.copy_until(b"\r\n\r\n", &mut head, MAX_PART_HEADER_SIZE as u64)
+            .map_err(|e| match e {
+                FormError::TooLarge => FormError::Malformed("part headers too large"),
+                e => e,
+            })?;
+
+        String::from_utf8_lossy(&head)
+            .split("\r\n")
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                line.split_once(':')
+                    .map(|(n, v)| (n.trim().to_string(), v.trim().to_string()))
+                    .ok_or(FormError::Malformed("bad part header"))
+            })
+            .collect()
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hands out its data a few bytes at a time to exercise the buffering.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.0.len().min(buf.len()).min(3);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn decodes_urlencoded_pairs() {
+        let params = parse_urlencoded("name=J%C3%BCrgen+M&tag=a&tag=b&empty&bad=%zz");
+
+        assert_eq!(Some("Jürgen M"), params.get("name"));
+        assert_eq!(vec!["a", "b"], params.get_all("tag"));
+        assert_eq!(Some(""), params.get("empty"));
+        assert_eq!(Some("%zz"), params.get("bad"));
+    }
+
+    #[test]
+    fn streams_multipart_files_to_disk() {
+        let body = b"preamble\r\n--XyZ\r\n\
+            Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+            Holiday\r\n--XyZ\r\n\
+            Content-Disposition: form-data; name=\"photo\"; filename=\"a.txt\"\r\n\
+            Content-Type: text/plain\r\n\r\n\
+            line one\r\n--Xy\r\n--XyZ--\r\n";
+
+        let multipart = Multipart::parse(Trickle(body), "XyZ", 1024).unwrap();
+
+        assert_eq!(Some("Holiday"), multipart.fields.get("title"));
+        let file = &multipart.files[0];
+        assert_eq!(
+            ("photo", "a.txt"),
+            (file.name.as_str(), file.filename.as_str())
+        );
+        assert_eq!(Some("text/plain"), file.content_type.as_deref());
+        assert_eq!("line one\r\n--Xy", fs::read_to_string(file.path()).unwrap());
+
+        let path = file.path().to_path_buf();
+        drop(multipart);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn bounds_what_multipart_keeps_in_memory() {
+        let mut body = Vec::new();
+        for i in 0..100 {
+            body.extend_from_slice(
+                format!(
+                    "--B\r\nContent-Disposition: form-data; name=\"f{}\"\r\n\r\n{}\r\n",
+                    i,
+                    "x".repeat(100)
+                )
+                .as_bytes(),
+            );
+        }
+        body.extend_from_slice(b"--B--\r\n");
+
+        assert!(Multipart::parse(&body[..], "B", body.len()).is_ok());
+        assert!(matches!(
+            Multipart::parse(&body[..], "B", 10_000),
+            Err(FormError::TooLarge)
+        ));
+    }
+}
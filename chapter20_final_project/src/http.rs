@@ -0,0 +1,835 @@
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+use crate::config::ServerConfig;
+use crate::router;
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub target: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Address of the client that sent the request, when it came in over the network.
+    pub remote_addr: Option<SocketAddr>,
+}
+
+impl Request {
+    pub fn new(method: &str, target: &str) -> Request {
+        Request {
+            method: method.to_string(),
+            target: target.to_string(),
+            version: String::from("HTTP/1.1"),
+            headers: Vec::new(),
+            body: Vec::new(),
+            remote_addr: None,
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Request {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn with_body<B: Into<Vec<u8>>>(mut self, body: B) -> Request {
+        self.body = body.into();
+        self
+    }
+
+    /// Look up a header by name. Header names are case-insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    /// The target without its query string.
+    pub fn path(&self) -> &str {
+        match self.target.split_once('?') {
+            Some((path, _)) => path,
+            None => &self.target,
+        }
+    }
+
+    /// The raw query string, without the leading `?`.
+    pub fn query(&self) -> Option<&str> {
+        self.target.split_once('?').map(|(_, query)| query)
+    }
+
+    /// The host the request is addressed to, from the `Host` header, without the port.
+    pub fn host(&self) -> Option<&str> {
+        let host = self.header("Host")?.trim();
+        let host = match host.strip_prefix('[') {
+            // An IPv6 literal such as `[::1]:8080`.
+            Some(rest) => rest.split(']').next().unwrap_or(rest),
+            None => host.split(':').next().unwrap_or(host),
+        };
+        Some(host.trim_end_matches('.')).filter(|host| !host.is_empty())
+    }
+
+    /// Whether the connection may be reused after this request, following the HTTP/1.0 and
+    /// HTTP/1.1 defaults unless the client says otherwise.
+    pub fn keep_alive(&self) -> bool {
+        match self.header("Connection") {
+            Some(value) if has_token(value, "close") => false,
+            Some(value) if has_token(value, "keep-alive") => true,
+            _ => self.version == "HTTP/1.1",
+        }
+    }
+
+    /// Serialize the request. The body is always sent with a `Content-Length`.
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let mut head = format!("{} {} {}\r\n", self.method, self.target, self.version);
+        write_headers(&mut head, &self.headers);
+        if !self.body.is_empty() || self.method == "POST" || self.method == "PUT" {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        head.push_str("\r\n");
+
+        write_message(w, head, &self.body)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn with_body<B: Into<Vec<u8>>>(mut self, body: B) -> Response {
+        self.body = body.into();
+        self
+    }
+
+    /// Look up a header by name. Header names are case-insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    /// Serialize the status line, headers and body. `Content-Length` is always derived from the
+    /// body so handlers cannot get it wrong.
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_message(w, self.head(self.body.len()), &self.body)
+    }
+
+    /// Serialize the answer to a HEAD request: the head the full response would have, without
+    /// the body. With no body to derive it from, a `Content-Length` header is kept, so answers
+    /// to HEAD that were passed on from elsewhere keep their length.
+    pub fn write_head_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let length = match self.header("Content-Length") {
+            Some(length) if self.body.is_empty() => length.trim().parse().unwrap_or(0),
+            _ => self.body.len(),
+        };
+        write_message(w, self.head(length), &[])
+    }
+
+    fn head(&self, content_length: usize) -> String {
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\n",
+            self.status,
+            reason_phrase(self.status)
+        );
+        write_headers(&mut head, &self.headers);
+        // Informational and 204 responses must not announce a body length.
+        if self.status >= 200 && self.status != 204 {
+            head.push_str(&format!("Content-Length: {}\r\n", content_length));
+        }
+        head.push_str("\r\n");
+        head
+    }
+}
+
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        422 => "Unprocessable Content",
+        426 => "Upgrade Required",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Unknown",
+    }
+}
+
+/// Send head and body with a single write. Written separately, the body of a small message
+/// waits for the ACK of the head (Nagle's algorithm), which the peer delays by up to 40ms.
+fn write_message<W: Write>(w: &mut W, head: String, body: &[u8]) -> io::Result<()> {
+    let mut message = head.into_bytes();
+    message.extend_from_slice(body);
+    w.write_all(&message)?;
+    w.flush()
+}
+
+/// Append all headers except `Content-Length` and `Transfer-Encoding`, which the writers derive
+/// from the body themselves.
+fn write_headers(head: &mut String, headers: &[(String, String)]) {
+    for (name, value) in headers {
+        if name.eq_ignore_ascii_case("Content-Length")
+            || name.eq_ignore_ascii_case("Transfer-Encoding")
+        {
+            continue;
+        }
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+}
+
+/// Whether a comma separated header value such as `Connection` contains `token`.
+pub fn has_token(value: &str, token: &str) -> bool {
+    value
+        .split(',')
+        .any(|t| t.trim().eq_ignore_ascii_case(token))
+}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+#[derive(Debug)]
+pub enum RequestError {
+    /// The client did not deliver the headers or body in time.
+    Timeout,
+    HeaderTooLarge,
+    BodyTooLarge,
+    Malformed(&'static str),
+    /// The body length cannot be determined up front, e.g. for a chunked body where only a
+    /// length is supported.
+    LengthRequired,
+    /// The client closed the connection, or let it sit idle, before sending anything.
+    Closed,
+    Io(io::Error),
+}
+
+impl RequestError {
+    /// The status to answer with, if the client is still worth answering.
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            RequestError::Timeout => Some(408),
+            RequestError::HeaderTooLarge => Some(431),
+            RequestError::BodyTooLarge => Some(413),
+            RequestError::Malformed(_) => Some(400),
+            RequestError::LengthRequired => Some(411),
+            RequestError::Closed | RequestError::Io(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RequestError::Timeout => write!(f, "timed out reading request"),
+            RequestError::HeaderTooLarge => write!(f, "request headers too large"),
+            RequestError::BodyTooLarge => write!(f, "request body too large"),
+            RequestError::Malformed(why) => write!(f, "malformed request: {}", why),
+            RequestError::LengthRequired => write!(f, "request body length required"),
+            RequestError::Closed => write!(f, "connection closed"),
+            RequestError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+impl From<RequestError> for io::Error {
+    fn from(e: RequestError) -> io::Error {
+        match e {
+            RequestError::Io(e) => e,
+            RequestError::Timeout => io::Error::new(io::ErrorKind::TimedOut, e.to_string()),
+            RequestError::Closed => io::Error::new(io::ErrorKind::UnexpectedEof, e.to_string()),
+            e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+        }
+    }
+}
+
+impl From<io::Error> for RequestError {
+    fn from(e: io::Error) -> RequestError {
+        match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => RequestError::Timeout,
+            _ => RequestError::Io(e),
+        }
+    }
+}
+
+/// A TCP stream together with bytes already read from it but not consumed yet, so several
+/// messages can be read from one keep-alive connection.
+pub struct Connection {
+    stream: TcpStream,
+    buf: Vec<u8>,
+}
+
+impl Connection {
+    pub fn new(stream: TcpStream) -> Connection {
+        Connection {
+            stream,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Pick up a connection whose first bytes have already been read elsewhere.
+    pub(crate) fn with_read_ahead(stream: TcpStream, buf: Vec<u8>) -> Connection {
+        Connection { stream, buf }
+    }
+
+    pub fn stream(&mut self) -> &mut TcpStream {
+        &mut self.stream
+    }
+
+    /// Give up the stream. Bytes read ahead are returned alongside it.
+    pub fn into_parts(self) -> (TcpStream, Vec<u8>) {
+        (self.stream, self.buf)
+    }
+
+    /// Read one request, enforcing the header and body limits of `config`.
+    ///
+    /// `header_timeout` bounds the wait for the complete head. Timeouts are deadlines for the
+    /// whole phase, not per `read` call, so a client trickling in one byte at a time still gets
+    /// cut off. A client that sends nothing at all before the deadline is treated as gone.
+    pub fn read_request(
+        &mut self,
+        config: &ServerConfig,
+        header_timeout: Duration,
+    ) -> Result<Request, RequestError> {
+        let mut request = self.read_head(config, header_timeout)?;
+        self.read_body(&mut request, config)?;
+        Ok(request)
+    }
+
+    /// Read the request line and headers only, leaving the body on the connection for
+    /// `read_body` or `body_reader`.
+    pub fn read_head(
+        &mut self,
+        config: &ServerConfig,
+        header_timeout: Duration,
+    ) -> Result<Request, RequestError> {
+        let deadline = Instant::now() + header_timeout;
+
+        let (mut request, head_len) = loop {
+            if let Some(parsed) = parse_head(&self.buf, config)? {
+                break parsed;
+            }
+            match self.fill(Some(deadline)) {
+                Ok(0) if self.buf.is_empty() => return Err(RequestError::Closed),
+                Ok(0) => return Err(RequestError::Malformed("connection closed inside headers")),
+                Ok(_) => {}
+                Err(RequestError::Timeout) if self.buf.is_empty() => {
+                    return Err(RequestError::Closed)
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        self.buf.drain(..head_len);
+        request.remote_addr = self.stream.peer_addr().ok();
+        Ok(request)
+    }
+
+    /// Read the body of `request`, whose head was just read, into `request.body`.
+    pub fn read_body(
+        &mut self,
+        request: &mut Request,
+        config: &ServerConfig,
+    ) -> Result<(), RequestError> {
+        let deadline = Instant::now() + config.body_read_timeout;
+        request.body = if is_chunked(&request.headers) {
+            self.read_chunked(config.max_body_size, config.max_header_size, Some(deadline))?
+        } else {
+            let len = body_length(request, config)?;
+            self.read_exact_body(len, Some(deadline))?
+        };
+        Ok(())
+    }
+
+    /// Read the body of `request`, whose head was just read, as it arrives instead of all at
+    /// once. It may be as large as `max_upload_size`. The body needs a `Content-Length`, and
+    /// `body_read_timeout` bounds each wait for more of it rather than the whole transfer, so
+    /// a large upload is not cut off while it keeps coming.
+    pub fn body_reader(
+        &mut self,
+        request: &Request,
+        config: &ServerConfig,
+    ) -> Result<BodyReader<'_>, RequestError> {
+        if is_chunked(&request.headers) {
+            return Err(RequestError::LengthRequired);
+        }
+        let remaining = match request.header("Content-Length") {
+            Some(len) => len
+                .parse::<u64>()
+                .map_err(|_| RequestError::Malformed("bad Content-Length"))?,
+            None => 0,
+        };
+        if remaining > config.max_upload_size {
+            return Err(RequestError::BodyTooLarge);
+        }
+        Ok(BodyReader {
+            conn: self,
+            remaining,
+            timeout: config.body_read_timeout,
+            started: Instant::now(),
+            received: 0,
+            min_rate: config.min_upload_rate,
+        })
+    }
+
+    /// Read the response to a request sent with `method`.
+    ///
+    /// Bodies may be delimited by `Content-Length`, chunked, or by the server closing the
+    /// connection, and must not be larger than `max_body`. `timeout` bounds every single read.
+    pub fn read_response(
+        &mut self,
+        method: &str,
+        timeout: Duration,
+        max_body: usize,
+    ) -> io::Result<Response> {
+        self.stream.set_read_timeout(Some(timeout))?;
+
+        let (head, head_len) = loop {
+            if let Some(head) = split_head(&self.buf, usize::MAX)? {
+                break head;
+            }
+            // `Closed` means not a byte of the response arrived, so the server cannot have
+            // started on the request.
+            match self.fill(None) {
+                Ok(0) if self.buf.is_empty() => return Err(RequestError::Closed.into()),
+                Ok(0) => {
+                    return Err(RequestError::Malformed("connection closed inside head").into())
+                }
+                Ok(_) => {}
+                Err(RequestError::Io(e))
+                    if self.buf.is_empty() && e.kind() == io::ErrorKind::ConnectionReset =>
+                {
+                    return Err(RequestError::Closed.into())
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+        let (status_line, headers) = head;
+        self.buf.drain(..head_len);
+
+        let mut parts = status_line.splitn(3, ' ');
+        let status = match (parts.next(), parts.next()) {
+            (Some(version), Some(code)) if version.starts_with("HTTP/") => code
+                .parse::<u16>()
+                .map_err(|_| RequestError::Malformed("bad status code"))?,
+            _ => return Err(RequestError::Malformed("bad status line").into()),
+        };
+
+        let body = if method == "HEAD" || status < 200 || status == 204 || status == 304 {
+            Vec::new()
+        } else if is_chunked(&headers) {
+            self.read_chunked(max_body, usize::MAX, None)?
+        } else if let Some(len) = find_header(&headers, "Content-Length") {
+            let len = len
+                .parse::<usize>()
+                .map_err(|_| RequestError::Malformed("bad Content-Length"))?;
+            if len > max_body {
+                return Err(RequestError::BodyTooLarge.into());
+            }
+            self.read_exact_body(len, None)?
+        } else {
+            while self.fill(None)? > 0 {
+                if self.buf.len() > max_body {
+                    return Err(RequestError::BodyTooLarge.into());
+                }
+            }
+            std::mem::take(&mut self.buf)
+        };
+
+        Ok(Response {
+            status,
+            headers,
+            body,
+        })
+    }
+
+    fn read_exact_body(
+        &mut self,
+        len: usize,
+        deadline: Option<Instant>,
+    ) -> Result<Vec<u8>, RequestError> {
+        while self.buf.len() < len {
+            if self.fill(deadline)? == 0 {
+                return Err(RequestError::Malformed("connection closed inside body"));
+            }
+        }
+        Ok(self.buf.drain(..len).collect())
+    }
+
+    /// Decode a `Transfer-Encoding: chunked` body. Trailers are read and dropped.
+    ///
+    /// `max_head` bounds every chunk-size line, extensions included, and all trailers together,
+    /// the same way it bounds the head.
+    fn read_chunked(
+        &mut self,
+        max: usize,
+        max_head: usize,
+        deadline: Option<Instant>,
+    ) -> Result<Vec<u8>, RequestError> {
+        let mut body = Vec::new();
+
+        loop {
+            let line = self
+                .read_line(max_head, deadline)?
+                .ok_or(RequestError::Malformed("chunk size line too long"))?;
+            let size = line.split(';').next().unwrap_or("").trim();
+            let size = usize::from_str_radix(size, 16)
+                .map_err(|_| RequestError::Malformed("bad chunk size"))?;
+            if body.len().saturating_add(size) > max {
+                return Err(RequestError::BodyTooLarge);
+            }
+
+            if size == 0 {
+                let mut trailers = 0;
+                loop {
+                    let line = self
+                        .read_line(max_head - trailers, deadline)?
+                        .ok_or(RequestError::HeaderTooLarge)?;
+                    if line.is_empty() {
+                        return Ok(body);
+                    }
+                    trailers += line.len() + 2;
+                    if trailers > max_head {
+                        return Err(RequestError::HeaderTooLarge);
+                    }
+                }
+            }
+
+            body.extend(self.read_exact_body(size, deadline)?);
+            if self.read_line(0, deadline)? != Some(String::new()) {
+                return Err(RequestError::Malformed("chunk longer than announced"));
+            }
+        }
+    }
+
+    /// Read one CRLF-terminated line, or `None` if it is longer than `max` bytes. Only that
+    /// much is ever buffered while waiting for the end of the line.
+    fn read_line(
+        &mut self,
+        max: usize,
+        deadline: Option<Instant>,
+    ) -> Result<Option<String>, RequestError> {
+        loop {
+            let searched = self.buf.len().min(max.saturating_add(2));
+            if let Some(pos) = find_subslice(&self.buf[..searched], b"\r\n") {
+                let line: Vec<u8> = self.buf.drain(..pos + 2).take(pos).collect();
+                return String::from_utf8(line)
+                    .map(Some)
+                    .map_err(|_| RequestError::Malformed("bad chunk line"));
+            }
+            if searched < self.buf.len() || self.buf.len() >= max.saturating_add(2) {
+                return Ok(None);
+            }
+            if self.fill(deadline)? == 0 {
+                return Err(RequestError::Malformed(
+                    "connection closed inside chunked body",
+                ));
+            }
+        }
+    }
+
+    /// Read whatever is available into the buffer. With a deadline the socket timeout is set to
+    /// the time that is left.
+    fn fill(&mut self, deadline: Option<Instant>) -> Result<usize, RequestError> {
+        if let Some(deadline) = deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::ZERO {
+                return Err(RequestError::Timeout);
+            }
+            self.stream.set_read_timeout(Some(remaining))?;
+        }
+
+        let mut chunk = [0; 4096];
+        let n = self.stream.read(&mut chunk)?;
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(n)
+    }
+}
+
+/// A request body read straight from the connection, from `Connection::body_reader`. It ends
+/// where the body does, so the connection can carry another request once it is read to the
+/// end.
+///
+/// Every read may wait up to `body_read_timeout`. After the first `body_read_timeout` the body
+/// must also keep up `min_upload_rate` on average, so a client cannot hold the connection for
+/// hours by sending a byte just before each read times out.
+pub struct BodyReader<'a> {
+    conn: &'a mut Connection,
+    remaining: u64,
+    timeout: Duration,
+    started: Instant,
+    received: u64,
+    min_rate: u64,
+}
+
+impl BodyReader<'_> {
+    /// When the next read times out: after `timeout`, or as soon as the average rate would drop
+    /// below the minimum, whichever comes first.
+    fn deadline(&self) -> Instant {
+        let deadline = Instant::now() + self.timeout;
+        if self.min_rate == 0 {
+            return deadline;
+        }
+        let earned = Duration::from_secs_f64(self.received as f64 / self.min_rate as f64);
+        deadline.min(self.started + self.timeout + earned)
+    }
+}
+
+impl Read for BodyReader<'_> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 || out.is_empty() {
+            return Ok(0);
+        }
+        if self.conn.buf.is_empty() && self.conn.fill(Some(self.deadline()))? == 0 {
+            return Err(RequestError::Malformed("connection closed inside body").into());
+        }
+        let n = out
+            .len()
+            .min(self.conn.buf.len())
+            .min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        out[..n].copy_from_slice(&self.conn.buf[..n]);
+        self.conn.buf.drain(..n);
+        self.remaining -= n as u64;
+        self.received += n as u64;
+        Ok(n)
+    }
+}
+
+/// Parse the request line and headers once `buf` holds all of them.
+///
+/// Returns `Ok(None)` while the head is still incomplete. On success the request has an empty
+/// body and is returned together with the length of the head in bytes.
+pub fn parse_head(
+    buf: &[u8],
+    config: &ServerConfig,
+) -> Result<Option<(Request, usize)>, RequestError> {
+    let ((request_line, headers), head_len) = match split_head(buf, config.max_header_size)? {
+        Some(head) => head,
+        None => return Ok(None),
+    };
+
+    let mut parts = request_line.split_whitespace();
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(m), Some(t), Some(v), None) if v.starts_with("HTTP/") => {
+            (m.to_string(), t.to_string(), v.to_string())
+        }
+        _ => return Err(RequestError::Malformed("bad request line")),
+    };
+    // Filters, routes and static files all go by the normalized path, so no spelling of a
+    // path can reach a handler or file without passing the filters meant for it.
+    let target = match target.split_once('?') {
+        _ if target == "*" => target,
+        Some((path, query)) => format!("{}?{}", normalize(path)?, query),
+        None => normalize(&target)?,
+    };
+
+    // HTTP/1.1 requires exactly one Host header, and virtual hosts depend on it.
+    let hosts = headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Host"))
+        .count();
+    if hosts > 1 || (hosts == 0 && version == "HTTP/1.1") {
+        return Err(RequestError::Malformed("missing or repeated Host header"));
+    }
+
+    let request = Request {
+        method,
+        target,
+        version,
+        headers,
+        body: Vec::new(),
+        remote_addr: None,
+    };
+    Ok(Some((request, head_len)))
+}
+
+fn normalize(path: &str) -> Result<String, RequestError> {
+    router::normalize_path(path).ok_or(RequestError::Malformed("bad request path"))
+}
+
+type Head = (String, Vec<(String, String)>);
+
+/// Split a complete head into its first line and the headers, together with its length.
+fn split_head(buf: &[u8], max_header_size: usize) -> Result<Option<(Head, usize)>, RequestError> {
+    let head_len = match find_subslice(buf, b"\r\n\r\n") {
+        Some(pos) => pos + 4,
+        None if buf.len() > max_header_size => return Err(RequestError::HeaderTooLarge),
+        None => return Ok(None),
+    };
+    if head_len > max_header_size {
+        return Err(RequestError::HeaderTooLarge);
+    }
+
+    let head = std::str::from_utf8(&buf[..head_len])
+        .map_err(|_| RequestError::Malformed("headers are not valid UTF-8"))?;
+    let mut lines = head.split("\r\n");
+    let first_line = lines.next().unwrap_or("").to_string();
+
+    let mut headers = Vec::new();
+    for line in lines.take_while(|l| !l.is_empty()) {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or(RequestError::Malformed("bad header line"))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+
+    Ok(Some(((first_line, headers), head_len)))
+}
+
+fn is_chunked(headers: &[(String, String)]) -> bool {
+    find_header(headers, "Transfer-Encoding").is_some_and(|v| has_token(v, "chunked"))
+}
+
+/// The announced body length of `request`, checked against the configured maximum.
+///
+/// Chunked bodies have no length up front and are reported as `LengthRequired`.
+pub fn body_length(request: &Request, config: &ServerConfig) -> Result<usize, RequestError> {
+    if is_chunked(&request.headers) {
+        return Err(RequestError::LengthRequired);
+    }
+    let content_length = match request.header("Content-Length") {
+        Some(len) => len
+            .parse::<usize>()
+            .map_err(|_| RequestError::Malformed("bad Content-Length"))?,
+        None => 0,
+    };
+    if content_length > config.max_body_size {
+        return Err(RequestError::BodyTooLarge);
+    }
+    Ok(content_length)
+}
+
+fn find_subslice(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn accept_with(client_sends: &[u8], config: &ServerConfig) -> Result<Request, RequestError> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(client_sends).unwrap();
+
+        let (stream, _) = listener.accept().unwrap();
+        Connection::new(stream).read_request(config, config.header_read_timeout)
+    }
+
+    #[test]
+    fn silent_client_times_out() {
+        let config = ServerConfig {
+            header_read_timeout: Duration::from_millis(50),
+            ..ServerConfig::default()
+        };
+
+        let err = accept_with(b"GET / HTTP/1.1\r\n", &config).unwrap_err();
+        assert_eq!(Some(408), err.status());
+    }
+
+    #[test]
+    fn oversized_headers_are_rejected() {
+        let config = ServerConfig {
+            max_header_size: 16,
+            ..ServerConfig::default()
+        };
+
+        let err =
+            accept_with(b"GET / HTTP/1.1\r\nX-Padding: aaaaaaaa\r\n\r\n", &config).unwrap_err();
+        assert_eq!(Some(431), err.status());
+    }
+
+    #[test]
+    fn chunk_lines_and_trailers_are_bounded() {
+        let config = ServerConfig {
+            max_header_size: 64,
+            ..ServerConfig::default()
+        };
+        let send = |chunks: &str| {
+            let head = "POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n";
+            accept_with(format!("{}{}", head, chunks).as_bytes(), &config)
+        };
+
+        let request = send("3;x=y\r\nabc\r\n0\r\nX-T: 1\r\n\r\n").unwrap();
+        assert_eq!(b"abc".to_vec(), request.body);
+
+        let err = send(&format!("1;{}\r\n", "x".repeat(100))).unwrap_err();
+        assert_eq!(Some(400), err.status());
+
+        let err = send(&format!("0\r\n{}\r\n", "X-T: 1\r\n".repeat(10))).unwrap_err();
+        assert_eq!(Some(431), err.status());
+    }
+
+    #[test]
+    fn http11_requests_need_one_host() {
+        let config = ServerConfig::default();
+        let parse = |head: &[u8]| parse_head(head, &config).map(|r| r.unwrap().0);
+
+        let request = parse(b"GET / HTTP/1.1\r\nHost: Example.COM.:8080\r\n\r\n").unwrap();
+        assert_eq!(Some("Example.COM"), request.host());
+        let request = parse(b"GET / HTTP/1.1\r\nHost: [::1]:8080\r\n\r\n").unwrap();
+        assert_eq!(Some("::1"), request.host());
+
+        assert!(parse(b"GET / HTTP/1.0\r\n\r\n").is_ok());
+        assert!(parse(b"GET / HTTP/1.1\r\n\r\n").is_err());
+        assert!(parse(b"GET / HTTP/1.1\r\nHost: a\r\nHost: b\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn request_paths_are_normalized() {
+        let config = ServerConfig::default();
+        let target = |line: &str| {
+            let head = format!("{}\r\nHost: a\r\n\r\n", line);
+            parse_head(head.as_bytes(), &config).map(|r| r.unwrap().0.target)
+        };
+
+        assert_eq!(
+            "/admin/x?a=%2F",
+            target("GET //%61dmin/./x?a=%2F HTTP/1.1").unwrap()
+        );
+        assert_eq!("*", target("OPTIONS * HTTP/1.1").unwrap());
+        let err = target("GET /css/../secret.html HTTP/1.1").unwrap_err();
+        assert_eq!(Some(400), err.status());
+        assert!(target("GET admin HTTP/1.1").is_err());
+    }
+}
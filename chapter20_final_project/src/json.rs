@@ -0,0 +1,757 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::form::media_type;
+use crate::http::{self, Request, Response};
+
+/// Deepest nesting `Json::parse` accepts, so hostile input cannot overflow the stack.
+const MAX_DEPTH: usize = 128;
+
+/// A JSON value. Objects keep their keys in document order.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(Number),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+/// A JSON number. Integers are kept exactly, as not every 64-bit integer survives the trip
+/// through `f64`: whole numbers that fit `i64` parse to `Int`, larger ones that fit `u64` to
+/// `UInt`, and everything else to `Float`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Number {
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+}
+
+impl Number {
+    pub fn as_f64(self) -> f64 {
+        match self {
+            Number::Int(n) => n as f64,
+            Number::UInt(n) => n as f64,
+            Number::Float(n) => n,
+        }
+    }
+
+    /// The value as an integer, if it is a whole number. Floats outside the `i128` range have
+    /// lost their low digits anyway and give `None`.
+    fn as_i128(self) -> Option<i128> {
+        match self {
+            Number::Int(n) => Some(n as i128),
+            Number::UInt(n) => Some(n as i128),
+            Number::Float(n) if n.fract() == 0.0 && n.abs() < 2f64.powi(127) => Some(n as i128),
+            Number::Float(_) => None,
+        }
+    }
+}
+
+impl From<i64> for Number {
+    fn from(n: i64) -> Number {
+        Number::Int(n)
+    }
+}
+
+impl From<u64> for Number {
+    fn from(n: u64) -> Number {
+        match i64::try_from(n) {
+            Ok(n) => Number::Int(n),
+            Err(_) => Number::UInt(n),
+        }
+    }
+}
+
+impl From<f64> for Number {
+    fn from(n: f64) -> Number {
+        Number::Float(n)
+    }
+}
+
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Number::Int(n) => write!(f, "{}", n),
+            Number::UInt(n) => write!(f, "{}", n),
+            // JSON has no NaN or infinity.
+            Number::Float(n) if !n.is_finite() => write!(f, "null"),
+            Number::Float(n) => write!(f, "{}", n),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct JsonError {
+    pub message: String,
+    /// Byte offset into the input, for parse errors.
+    pub offset: Option<usize>,
+}
+
+impl JsonError {
+    pub fn new(message: &str) -> JsonError {
+        JsonError {
+            message: message.to_string(),
+            offset: None,
+        }
+    }
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.offset {
+            Some(offset) => write!(f, "{} at byte {}", self.message, offset),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for JsonError {}
+
+/// Conversion into a `Json` value, implemented by types that can be sent as a response body.
+pub trait ToJson {
+    fn to_json(&self) -> Json;
+}
+
+/// Conversion from a `Json` value, implemented by types that can be read from a request body.
+pub trait FromJson: Sized {
+    fn from_json(json: &Json) -> Result<Self, JsonError>;
+}
+
+impl Json {
+    pub fn parse(text: &str) -> Result<Json, JsonError> {
+        let mut parser = Parser {
+            bytes: text.as_bytes(),
+            pos: 0,
+        };
+        let value = parser.value(0)?;
+        parser.skip_whitespace();
+        if parser.pos != parser.bytes.len() {
+            return Err(parser.error("trailing characters"));
+        }
+        Ok(value)
+    }
+
+    /// Look up `key` in an object.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Json::Number(n) => Some(n.as_f64()),
+            _ => None,
+        }
+    }
+
+    /// The number as an `i64`, if it is a whole number in range.
+    pub fn as_i64(&self) -> Option<i64> {
+        i64::from_json(self).ok()
+    }
+
+    /// The number as a `u64`, if it is a whole number in range.
+    pub fn as_u64(&self) -> Option<u64> {
+        u64::from_json(self).ok()
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    /// Read field `key` of an object as `T`, with an error naming the field.
+    pub fn field<T: FromJson>(&self, key: &str) -> Result<T, JsonError> {
+        let value = self.get(key).unwrap_or(&Json::Null);
+        T::from_json(value).map_err(|e| JsonError::new(&format!("field `{}`: {}", key, e.message)))
+    }
+}
+
+/// Serializes compactly, e.g. `{"a":[1,true,null]}`.
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(s) => write_string(f, s),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(members) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &str) -> JsonError {
+        JsonError {
+            message: message.to_string(),
+            offset: Some(self.pos),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.bytes.get(self.pos) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, literal: &str, value: Json) -> Result<Json, JsonError> {
+        if self.bytes[self.pos..].starts_with(literal.as_bytes()) {
+            self.pos += literal.len();
+            Ok(value)
+        } else {
+            Err(self.error("invalid literal"))
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Json, JsonError> {
+        if depth > MAX_DEPTH {
+            return Err(self.error("nesting too deep"));
+        }
+        self.skip_whitespace();
+
+        match self.bytes.get(self.pos) {
+            None => Err(self.error("unexpected end of input")),
+            Some(b'n') => self.expect("null", Json::Null),
+            Some(b't') => self.expect("true", Json::Bool(true)),
+            Some(b'f') => self.expect("false", Json::Bool(false)),
+            Some(b'"') => self.string().map(Json::String),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                self.skip_whitespace();
+                if self.bytes.get(self.pos) == Some(&b']') {
+                    self.pos += 1;
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value(depth + 1)?);
+                    self.skip_whitespace();
+                    match self.bytes.get(self.pos) {
+                        Some(b',') => self.pos += 1,
+                        Some(b']') => {
+                            self.pos += 1;
+                            return Ok(Json::Array(items));
+                        }
+                        _ => return Err(self.error("expected `,` or `]`")),
+                    }
+                }
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                self.skip_whitespace();
+                if self.bytes.get(self.pos) == Some(&b'}') {
+                    self.pos += 1;
+                    return Ok(Json::Object(members));
+                }
+                loop {
+                    self.skip_whitespace();
+                    if self.bytes.get(self.pos) != Some(&b'"') {
+                        return Err(self.error("expected string key"));
+                    }
+                    let key = self.string()?;
+                    self.skip_whitespace();
+                    if self.bytes.get(self.pos) != Some(&b':') {
+                        return Err(self.error("expected `:`"));
+                    }
+                    self.pos += 1;
+                    members.push((key, self.value(depth + 1)?));
+                    self.skip_whitespace();
+                    match self.bytes.get(self.pos) {
+                        Some(b',') => self.pos += 1,
+                        Some(b'}') => {
+                            self.pos += 1;
+                            return Ok(Json::Object(members));
+                        }
+                        _ => return Err(self.error("expected `,` or `}`")),
+                    }
+                }
+            }
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            Some(_) => Err(self.error("unexpected character")),
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, JsonError> {
+        let start = self.pos;
+        let digits = |p: &mut Parser| {
+            let from = p.pos;
+            while p.bytes.get(p.pos).is_some_and(u8::is_ascii_digit) {
+                p.pos += 1;
+            }
+            p.pos > from
+        };
+
+        if self.bytes.get(self.pos) == Some(&b'-') {
+            self.pos += 1;
+        }
+        if self.bytes.get(self.pos) == Some(&b'0') {
+            self.pos += 1;
+        } else if !digits(self) {
+            return Err(self.error("invalid number"));
+        }
+        if self.bytes.get(self.pos) == Some(&b'.') {
+            self.pos += 1;
+            if !digits(self) {
+                return Err(self.error("invalid fraction"));
+            }
+        }
+        if let Some(b'e' | b'E') = self.bytes.get(self.pos) {
+            self.pos += 1;
+            if let Some(b'+' | b'-') = self.bytes.get(self.pos) {
+                self.pos += 1;
+            }
+            if !digits(self) {
+                return Err(self.error("invalid exponent"));
+            }
+        }
+
+        // The slice is ASCII by construction.
+        let text = std::str::from_utf8(&self.bytes[start..self.pos]).unwrap();
+        let integer = !text.contains(['.', 'e', 'E']);
+        if let Some(n) = integer.then(|| text.parse::<i64>().ok()).flatten() {
+            return Ok(Json::Number(Number::Int(n)));
+        }
+        if let Some(n) = integer.then(|| text.parse::<u64>().ok()).flatten() {
+            return Ok(Json::Number(Number::UInt(n)));
+        }
+        text.parse()
+            .map(|n| Json::Number(Number::Float(n)))
+            .map_err(|_| self.error("invalid number"))
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        self.pos += 1;
+        let mut out = String::new();
+
+        loop {
+            // Copy the run of plain characters in one go.
+            let start = self.pos;
+            while let Some(&b) = self.bytes.get(self.pos) {
+                if b == b'"' || b == b'\\' || b < 0x20 {
+                    break;
+                }
+                self.pos += 1;
+            }
+            // The input came from a `&str` and we only stop on ASCII, so this is valid UTF-8.
+            out.push_str(std::str::from_utf8(&self.bytes[start..self.pos]).unwrap());
+
+            match self.bytes.get(self.pos) {
+                Some(b'"') => {
+                    self.pos += 1;
+                    return Ok(out);
+                }
+                Some(b'\\') => {
+                    self.pos += 1;
+                    let escaped = match self.bytes.get(self.pos) {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => {
+                            self.pos += 1;
+                            let c = self.unicode_escape()?;
+                            out.push(c);
+                            continue;
+                        }
+                        _ => return Err(self.error("invalid escape")),
+                    };
+                    out.push(escaped);
+                    self.pos += 1;
+                }
+                Some(_) => return Err(self.error("control character in string")),
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    /// Decode the four hex digits after `\u`, combining surrogate pairs.
+    fn unicode_escape(&mut self) -> Result<char, JsonError> {
+        let first = self.hex4()?;
+        let code = if (0xD800..0xDC00).contains(&first) {
+            if !self.bytes[self.pos..].starts_with(b"\\u") {
+                return Err(self.error("unpaired surrogate"));
+            }
+            self.pos += 2;
+            let second = self.hex4()?;
+            if !(0xDC00..0xE000).contains(&second) {
+                return Err(self.error("unpaired surrogate"));
+            }
+            0x10000 + ((first - 0xD800) << 10) + (second - 0xDC00)
+        } else {
+            first
+        };
+        char::from_u32(code).ok_or_else(|| self.error("invalid code point"))
+    }
+
+    fn hex4(&mut self) -> Result<u32, JsonError> {
+        let digits = self
+            .bytes
+            .get(self.pos..self.pos + 4)
+            .and_then(|d| std::str::from_utf8(d).ok())
+            .and_then(|d| u32::from_str_radix(d, 16).ok())
+            .ok_or_else(|| self.error("invalid unicode escape"))?;
+        self.pos += 4;
+        Ok(digits)
+    }
+}
+
+impl ToJson for Json {
+    fn to_json(&self) -> Json {
+        self.clone()
+    }
+}
+
+impl FromJson for Json {
+    fn from_json(json: &Json) -> Result<Json, JsonError> {
+        Ok(json.clone())
+    }
+}
+
+impl ToJson for bool {
+    fn to_json(&self) -> Json {
+        Json::Bool(*self)
+    }
+}
+
+impl FromJson for bool {
+    fn from_json(json: &Json) -> Result<bool, JsonError> {
+        json.as_bool()
+            .ok_or_else(|| JsonError::new("expected a boolean"))
+    }
+}
+
+impl ToJson for str {
+    fn to_json(&self) -> Json {
+        Json::String(self.to_string())
+    }
+}
+
+impl ToJson for String {
+    fn to_json(&self) -> Json {
+        Json::String(self.clone())
+    }
+}
+
+impl FromJson for String {
+    fn from_json(json: &Json) -> Result<String, JsonError> {
+        json.as_str()
+            .map(str::to_string)
+            .ok_or_else(|| JsonError::new("expected a string"))
+    }
+}
+
+/// Integer types convert through `$wide`, the 64-bit type of the same signedness, so they
+/// are stored exactly.
+macro_rules! json_number {
+    ($($t:ty => $wide:ty),*) => {
+        $(
+            impl ToJson for $t {
+                fn to_json(&self) -> Json {
+                    Json::Number(Number::from(*self as $wide))
+                }
+            }
+
+            impl FromJson for $t {
+                fn from_json(json: &Json) -> Result<$t, JsonError> {
+                    let n = match json {
+                        Json::Number(n) => n,
+                        _ => return Err(JsonError::new("expected a number")),
+                    };
+                    // Fractions, and values the target type cannot hold, are errors rather
+                    // than being truncated or saturated.
+                    n.as_i128()
+                        .and_then(|n| <$t>::try_from(n).ok())
+                        .ok_or_else(|| JsonError::new(concat!("number does not fit ", stringify!($t))))
+                }
+            }
+        )*
+    };
+}
+
+json_number!(i32 => i64, i64 => i64, u8 => u64, u16 => u64, u32 => u64, u64 => u64, usize => u64);
+
+impl ToJson for f64 {
+    fn to_json(&self) -> Json {
+        Json::Number(Number::Float(*self))
+    }
+}
+
+impl FromJson for f64 {
+    fn from_json(json: &Json) -> Result<f64, JsonError> {
+        json.as_f64()
+            .ok_or_else(|| JsonError::new("expected a number"))
+    }
+}
+
+impl<T: ToJson> ToJson for Option<T> {
+    fn to_json(&self) -> Json {
+        match self {
+            Some(value) => value.to_json(),
+            None => Json::Null,
+        }
+    }
+}
+
+impl<T: FromJson> FromJson for Option<T> {
+    fn from_json(json: &Json) -> Result<Option<T>, JsonError> {
+        match json {
+            Json::Null => Ok(None),
+            json => T::from_json(json).map(Some),
+        }
+    }
+}
+
+impl<T: ToJson> ToJson for [T] {
+    fn to_json(&self) -> Json {
+        Json::Array(self.iter().map(ToJson::to_json).collect())
+    }
+}
+
+impl<T: ToJson> ToJson for Vec<T> {
+    fn to_json(&self) -> Json {
+        self.as_slice().to_json()
+    }
+}
+
+impl<T: FromJson> FromJson for Vec<T> {
+    fn from_json(json: &Json) -> Result<Vec<T>, JsonError> {
+        json.as_array()
+            .ok_or_else(|| JsonError::new("expected an array"))?
+            .iter()
+            .map(T::from_json)
+            .collect()
+    }
+}
+
+impl<T: ToJson> ToJson for HashMap<String, T> {
+    fn to_json(&self) -> Json {
+        let mut members: Vec<(String, Json)> =
+            self.iter().map(|(k, v)| (k.clone(), v.to_json())).collect();
+        // Sort for stable output; HashMap order is random.
+        members.sort_by(|a, b| a.0.cmp(&b.0));
+        Json::Object(members)
+    }
+}
+
+impl<T: FromJson> FromJson for HashMap<String, T> {
+    fn from_json(json: &Json) -> Result<HashMap<String, T>, JsonError> {
+        match json {
+            Json::Object(members) => members
+                .iter()
+                .map(|(k, v)| Ok((k.clone(), T::from_json(v)?)))
+                .collect(),
+            _ => Err(JsonError::new("expected an object")),
+        }
+    }
+}
+
+impl Request {
+    /// Parse an `application/json` body into `T`. Errors carry the status to answer with:
+    /// 415 for another content type, 400 for a body that does not parse or does not fit `T`.
+    pub fn json<T: FromJson>(&self) -> Result<T, Response> {
+        let is_json = self
+            .header("Content-Type")
+            .map(media_type)
+            .is_some_and(|t| t.eq_ignore_ascii_case("application/json") || t.ends_with("+json"));
+        if !is_json {
+            return Err(Response::json_error(
+                415,
+                "expected an application/json body",
+            ));
+        }
+
+        let text = std::str::from_utf8(&self.body)
+            .map_err(|_| Response::json_error(400, "body is not valid UTF-8"))?;
+        let json = Json::parse(text).map_err(|e| Response::json_error(400, &e.to_string()))?;
+        T::from_json(&json).map_err(|e| Response::json_error(400, &e.to_string()))
+    }
+}
+
+impl Response {
+    /// A response with `value` serialized as the body.
+    pub fn json<T: ToJson + ?Sized>(status: u16, value: &T) -> Response {
+        Response::new(status)
+            .with_header("Content-Type", "application/json")
+            .with_body(value.to_json().to_string())
+    }
+
+    /// A structured error body: `{"error":{"status":404,"reason":"Not Found","message":"..."}}`.
+    pub fn json_error(status: u16, message: &str) -> Response {
+        let error = Json::Object(vec![(
+            String::from("error"),
+            Json::Object(vec![
+                (String::from("status"), status.to_json()),
+                (
+                    String::from("reason"),
+                    http::reason_phrase(status).to_json(),
+                ),
+                (String::from("message"), message.to_json()),
+            ]),
+        )]);
+        Response::json(status, &error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct Point {
+        x: i32,
+        label: Option<String>,
+    }
+
+    impl FromJson for Point {
+        fn from_json(json: &Json) -> Result<Point, JsonError> {
+            Ok(Point {
+                x: json.field("x")?,
+                label: json.field("label")?,
+            })
+        }
+    }
+
+    #[test]
+    fn round_trips_documents() {
+        let text = r#"{"a":[1,-2.5,1e3,true,null],"s":"q\"\\\né😀","o":{}}"#;
+        let json = Json::parse(text).unwrap();
+
+        assert_eq!(Some("q\"\\\né😀"), json.get("s").and_then(Json::as_str));
+        assert_eq!(
+            r#"{"a":[1,-2.5,1000,true,null],"s":"q\"\\\né😀","o":{}}"#,
+            json.to_string()
+        );
+    }
+
+    #[test]
+    fn reports_error_offsets() {
+        assert_eq!(Some(5), Json::parse("[1, 2").unwrap_err().offset);
+        assert!(Json::parse("01").is_err());
+        assert!(Json::parse("{\"a\" 1}").is_err());
+        assert!(Json::parse(&"[".repeat(1000)).is_err());
+    }
+
+    #[test]
+    fn reads_typed_bodies() {
+        let request = Request::new("POST", "/points")
+            .with_header("Content-Type", "application/json; charset=utf-8")
+            .with_body(r#"{"x": 3}"#);
+        let point: Point = request.json().unwrap();
+        assert_eq!((3, None), (point.x, point.label));
+
+        let request = request.with_body(r#"{"x": 3.5}"#);
+        let error = request.json::<Point>().unwrap_err();
+        assert_eq!(400, error.status);
+    }
+
+    #[test]
+    fn rejects_numbers_out_of_range() {
+        let number = |text: &str| Json::parse(text).unwrap();
+
+        assert!(i64::from_json(&number("9223372036854775808")).is_err());
+        assert!(u64::from_json(&number("18446744073709551616")).is_err());
+        assert!(u8::from_json(&number("256")).is_err());
+        assert!(u32::from_json(&number("-1")).is_err());
+        assert_eq!(Ok(1 << 63), u64::from_json(&number("9223372036854775808")));
+        assert_eq!(
+            Ok(i64::MIN),
+            i64::from_json(&number("-9223372036854775808"))
+        );
+        assert_eq!(Ok(255), u8::from_json(&number("255")));
+        assert_eq!(Ok(1000), u16::from_json(&number("1e3")));
+    }
+
+    #[test]
+    fn keeps_integers_exact() {
+        let number = |text: &str| Json::parse(text).unwrap();
+
+        assert_eq!(Ok(i64::MAX), i64::from_json(&number("9223372036854775807")));
+        assert_eq!(
+            Ok(u64::MAX),
+            u64::from_json(&number("18446744073709551615"))
+        );
+        assert_eq!(
+            Ok(9007199254740993),
+            i64::from_json(&number("9007199254740993"))
+        );
+        assert!(i64::from_json(&number("18446744073709551615")).is_err());
+        for text in [
+            "9223372036854775807",
+            "18446744073709551615",
+            "-9223372036854775808",
+        ] {
+            assert_eq!(text, number(text).to_string());
+        }
+        assert_eq!("18446744073709551615", u64::MAX.to_json().to_string());
+        // Beyond u64 the number becomes a float.
+        assert_eq!(
+            Json::Number(Number::Float(18446744073709551616.0)),
+            number("18446744073709551616")
+        );
+    }
+}
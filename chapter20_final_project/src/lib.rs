@@ -2,6 +2,44 @@ use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
 use std::thread;
 
+pub mod auth;
+pub mod base64;
+pub mod cgi;
+pub mod client;
+pub mod config;
+pub mod cookie;
+pub mod digest;
+pub mod error;
+pub mod form;
+pub mod http;
+pub mod json;
+pub mod limit;
+#[cfg(target_os = "linux")]
+pub mod polling;
+pub mod proxy;
+pub mod router;
+pub mod server;
+pub mod session;
+pub mod sse;
+pub mod template;
+pub mod vhost;
+pub mod websocket;
+
+pub use auth::BasicAuth;
+pub use cgi::Cgi;
+pub use client::Client;
+pub use config::ServerConfig;
+pub use error::{ErrorPages, ServerError};
+pub use limit::RateLimiter;
+#[cfg(target_os = "linux")]
+pub use polling::PollingServer;
+pub use proxy::Proxy;
+pub use router::Router;
+pub use server::Server;
+pub use session::SessionStore;
+pub use template::Templates;
+pub use vhost::VirtualHosts;
+
 pub struct ThreadPool {
     workers: Vec<Worker>,
     sender: mpsc::Sender<Message>,
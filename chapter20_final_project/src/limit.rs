@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::http::{Request, Response};
+use crate::router::{self, Router};
+
+/// Caps the number of simultaneous connections per client address.
+///
+/// Clones share the same counters, so one limiter can be handed to the accept loop while the
+/// guards travel to the workers.
+#[derive(Clone)]
+pub struct ConnectionLimiter {
+    max_per_ip: usize,
+    active: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+/// Holds one connection slot; the slot is released when the guard is dropped.
+pub struct ConnectionGuard {
+    ip: IpAddr,
+    active: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_per_ip: usize) -> ConnectionLimiter {
+        ConnectionLimiter {
+            max_per_ip,
+            active: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Returns `None` if `ip` already holds the maximum number of connections.
+    pub fn try_acquire(&self, ip: IpAddr) -> Option<ConnectionGuard> {
+        let mut active = self.active.lock().unwrap();
+        let count = active.entry(ip).or_insert(0);
+        if *count >= self.max_per_ip {
+            return None;
+        }
+        *count += 1;
+
+        Some(ConnectionGuard {
+            ip,
+            active: Arc::clone(&self.active),
+        })
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut active = self.active.lock().unwrap();
+        if let Some(count) = active.get_mut(&self.ip) {
+            *count -= 1;
+            // Do not let the map grow with every address we have ever seen.
+            if *count == 0 {
+                active.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Token-bucket rate limiting per client address.
+///
+/// Every address gets a bucket of `burst` tokens that refills at `per_second`; each request
+/// takes one token, and requests finding the bucket empty are answered with 429. Paths below a
+/// prefix given to `route` use that route's limit and a bucket of their own instead. Clones
+/// share the same buckets, so one limiter can guard several sites.
+#[derive(Clone)]
+pub struct RateLimiter {
+    default: Option<Rate>,
+    routes: Vec<(String, Rate)>,
+    state: Arc<Mutex<Buckets>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Rate {
+    per_second: f64,
+    burst: f64,
+}
+
+#[derive(Default)]
+struct Buckets {
+    buckets: HashMap<(IpAddr, Option<String>), Bucket>,
+    checks: usize,
+}
+
+struct Bucket {
+    rate: Rate,
+    tokens: f64,
+    updated: Instant,
+}
+
+/// How many checks pass between sweeps of idle buckets.
+const SWEEP_INTERVAL: usize = 1024;
+
+impl RateLimiter {
+    /// Limit every path to `per_second` requests, with bursts of up to `burst`.
+    pub fn new(per_second: f64, burst: u32) -> RateLimiter {
+        RateLimiter {
+            default: Some(Rate::new(per_second, burst)),
+            ..RateLimiter::routes_only()
+        }
+    }
+
+    /// Limit only the paths given to `route`.
+    pub fn routes_only() -> RateLimiter {
+        RateLimiter {
+            default: None,
+            routes: Vec::new(),
+            state: Arc::new(Mutex::new(Buckets::default())),
+        }
+    }
+
+    /// Give `prefix` and everything below it a limit of its own.
+    pub fn route(mut self, prefix: &str, per_second: f64, burst: u32) -> RateLimiter {
+        let prefix = prefix.trim_end_matches('/').to_string();
+        self.routes.push((prefix, Rate::new(per_second, burst)));
+        // Keep the longest prefixes first so the most specific one wins.
+        self.routes
+            .sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        self
+    }
+
+    /// Take a token for `request`, returning the 429 to send if there is none left. Requests
+    /// without a known client address are not limited.
+    pub fn check(&self, request: &Request) -> Option<Response> {
+        let ip = request.remote_addr?.ip();
+        let path = request.path();
+        let (route, rate) = match self
+            .routes
+            .iter()
+            .find(|(prefix, _)| router::is_below(path, prefix))
+        {
+            Some((prefix, rate)) => (Some(prefix.clone()), *rate),
+            None => (None, self.default?),
+        };
+
+        match self.take(ip, route, rate) {
+            Ok(()) => None,
+            Err(wait) => {
+                Some(Response::new(429).with_header("Retry-After", &wait.as_secs().to_string()))
+            }
+        }
+    }
+
+    /// Register `check` as a filter on `router`.
+    pub fn install(self, router: &mut Router) {
+        router.filter(move |request| self.check(request));
+    }
+
+    /// Take one token, or return how long until the next one is available.
+    fn take(&self, ip: IpAddr, route: Option<String>, rate: Rate) -> Result<(), Duration> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+
+        state.checks += 1;
+        if state.checks.is_multiple_of(SWEEP_INTERVAL) {
+            // A bucket that has refilled completely is the same as no bucket at all.
+            state
+                .buckets
+                .retain(|_, bucket| bucket.refilled(now) < bucket.rate.burst);
+        }
+
+        let bucket = state.buckets.entry((ip, route)).or_insert(Bucket {
+            rate,
+            tokens: rate.burst,
+            updated: now,
+        });
+        bucket.tokens = bucket.refilled(now);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let seconds = ((1.0 - bucket.tokens) / rate.per_second).ceil();
+            Err(Duration::from_secs(seconds.max(1.0) as u64))
+        }
+    }
+}
+
+impl Rate {
+    fn new(per_second: f64, burst: u32) -> Rate {
+        assert!(per_second > 0.0, "rate must be positive");
+        Rate {
+            per_second,
+            burst: f64::from(burst.max(1)),
+        }
+    }
+}
+
+impl Bucket {
+    fn refilled(&self, now: Instant) -> f64 {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * self.rate.per_second).min(self.rate.burst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn releases_slot_on_drop() {
+        let limiter = ConnectionLimiter::new(1);
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        let guard = limiter.try_acquire(ip);
+        assert!(guard.is_some());
+        assert!(limiter.try_acquire(ip).is_none());
+
+        drop(guard);
+        assert!(limiter.try_acquire(ip).is_some());
+    }
+
+    fn from_ip(ip: [u8; 4], path: &str) -> Request {
+        let mut request = Request::new("GET", path);
+        request.remote_addr = Some((Ipv4Addr::from(ip), 40000).into());
+        request
+    }
+
+    #[test]
+    fn empty_bucket_gets_429() {
+        let limiter = RateLimiter::new(0.5, 2);
+
+        assert!(limiter.check(&from_ip([10, 0, 0, 1], "/")).is_none());
+        assert!(limiter.check(&from_ip([10, 0, 0, 1], "/")).is_none());
+        let limited = limiter.check(&from_ip([10, 0, 0, 1], "/")).unwrap();
+        assert_eq!(429, limited.status);
+        assert_eq!(Some("2"), limited.header("Retry-After"));
+
+        // Other clients have buckets of their own.
+        assert!(limiter.check(&from_ip([10, 0, 0, 2], "/")).is_none());
+    }
+
+    #[test]
+    fn routes_have_separate_limits() {
+        let limiter = RateLimiter::routes_only().route("/login", 1.0, 1);
+
+        assert!(limiter.check(&from_ip([10, 0, 0, 1], "/login")).is_none());
+        assert!(limiter.check(&from_ip([10, 0, 0, 1], "/login")).is_some());
+        assert!(limiter.check(&from_ip([10, 0, 0, 1], "/")).is_none());
+        assert!(limiter.check(&from_ip([10, 0, 0, 1], "/")).is_none());
+    }
+}
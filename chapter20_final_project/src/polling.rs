@@ -0,0 +1,656 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::ServerConfig;
+use crate::error::ServerError;
+use crate::http::{self, Request};
+use crate::limit::{ConnectionGuard, ConnectionLimiter};
+use crate::router::Router;
+use crate::server::{self, WebSocketSlots};
+use crate::vhost::VirtualHosts;
+use crate::ThreadPool;
+
+/// Token of a reactor's wakeup socket. Connections are numbered from zero and never get here.
+const WAKER: u64 = u64::MAX;
+
+/// Most readiness events taken from one `epoll_wait` call.
+const MAX_EVENTS: usize = 256;
+
+/// An alternative to `Server` that does not tie a pool thread to every connection.
+///
+/// A handful of reactor threads own all sockets in non-blocking mode. Each blocks in
+/// `epoll_wait` until one of its connections can make progress or a deadline comes up, and
+/// only hands complete requests to the `ThreadPool`. An idle keep-alive connection costs a file
+/// descriptor and its buffer, not a thread.
+///
+/// WebSocket, event-stream and upload routes keep their connection busy for as long as they
+/// run, so such a request is handed to a pool worker together with its connection, which is
+/// served as `Server` would serve it from then on. Other handlers that have not answered
+/// within `handler_timeout` get their connection closed with a 503.
+pub struct PollingServer {
+    listener: TcpListener,
+    reactors: usize,
+    pool: Arc<ThreadPool>,
+    config: Arc<ServerConfig>,
+    hosts: Arc<VirtualHosts>,
+    limiter: ConnectionLimiter,
+    websockets: WebSocketSlots,
+}
+
+struct Connection {
+    // Declared first so the slot is released before the socket is closed; a client reconnecting
+    // right after our close must not find its old slot still taken.
+    guard: ConnectionGuard,
+    stream: TcpStream,
+    state: State,
+    deadline: Instant,
+    /// The deadline the reactor's timer set holds for this connection.
+    armed: Instant,
+    /// The events the connection is registered for with `epoll`.
+    interest: u32,
+}
+
+enum State {
+    /// Collecting the request. Once the head is parsed the request and the expected body length
+    /// are kept so the body can be gathered without parsing again.
+    Reading {
+        buf: Vec<u8>,
+        head: Option<(Request, usize, usize)>,
+    },
+    /// The request is being handled on the pool. `rest` holds whatever the client sent behind
+    /// it, the start of its next request.
+    Waiting { rest: Vec<u8> },
+    Writing {
+        buf: Vec<u8>,
+        written: usize,
+        keep_alive: bool,
+        rest: Vec<u8>,
+    },
+}
+
+enum Step {
+    Continue,
+    Dispatch(Request),
+    HandOff,
+    Close,
+}
+
+impl PollingServer {
+    /// Bind the listener. `reactors` threads multiplex the connections and `workers` threads run
+    /// the handlers.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `reactors` or `workers` is zero.
+    pub fn bind<A: ToSocketAddrs>(
+        addr: A,
+        reactors: usize,
+        workers: usize,
+        config: ServerConfig,
+    ) -> io::Result<PollingServer> {
+        assert!(reactors > 0);
+
+        let listener = TcpListener::bind(addr)?;
+        let limiter = ConnectionLimiter::new(config.max_connections_per_ip);
+
+        Ok(PollingServer {
+            listener,
+            reactors,
+            pool: Arc::new(ThreadPool::new(workers)),
+            config: Arc::new(config),
+            hosts: Arc::new(VirtualHosts::default()),
+            limiter,
+            websockets: WebSocketSlots::default(),
+        })
+    }
+
+    /// Serve every request with `router`.
+    pub fn with_router(self, router: Router) -> PollingServer {
+        self.with_hosts(VirtualHosts::new(router))
+    }
+
+    /// Pick the router for each request by its `Host` header.
+    pub fn with_hosts(mut self, hosts: VirtualHosts) -> PollingServer {
+        self.hosts = Arc::new(hosts);
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Start the reactors and accept connections forever, spreading them round-robin.
+    pub fn run(self) -> io::Result<()> {
+        let mut reactors = Vec::with_capacity(self.reactors);
+        for id in 0..self.reactors {
+            let (tx, rx) = mpsc::channel();
+            let reactor = Reactor::new(
+                id,
+                rx,
+                Arc::clone(&self.pool),
+                Arc::clone(&self.config),
+                Arc::clone(&self.hosts),
+                self.websockets.clone(),
+            )?;
+            let waker = Arc::clone(&reactor.waker);
+            thread::spawn(move || reactor.run());
+            reactors.push((tx, waker));
+        }
+
+        // Errors that only concern one connection, such as running out of file descriptors
+        // or a client resetting before we get to it, must not stop the server.
+        for (next, stream) in self.listener.incoming().enumerate() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("Could not accept connection: {}", e);
+                    continue;
+                }
+            };
+            let guard = match stream
+                .peer_addr()
+                .map(|addr| self.limiter.try_acquire(addr.ip()))
+            {
+                Ok(Some(guard)) => guard,
+                Ok(None) => {
+                    eprintln!("Too many connections from one address; closing.");
+                    continue;
+                }
+                Err(e) => {
+                    eprintln!("Could not determine peer address: {}", e);
+                    continue;
+                }
+            };
+            // As in `Server`, responses go out in one write.
+            if let Err(e) = stream
+                .set_nonblocking(true)
+                .and_then(|_| stream.set_nodelay(true))
+            {
+                eprintln!("Connection error: {}", e);
+                continue;
+            }
+
+            let deadline = Instant::now() + self.config.header_read_timeout;
+            let conn = Connection {
+                guard,
+                stream,
+                state: State::Reading {
+                    buf: Vec::new(),
+                    head: None,
+                },
+                deadline,
+                armed: deadline,
+                interest: 0,
+            };
+            let (tx, waker) = &reactors[next % reactors.len()];
+            tx.send(conn).unwrap();
+            waker.wake();
+        }
+
+        Ok(())
+    }
+}
+
+/// A thin wrapper around an `epoll` instance.
+struct Poller {
+    epoll: OwnedFd,
+}
+
+impl Poller {
+    fn new() -> io::Result<Poller> {
+        // SAFETY: `epoll_create1` takes no pointers, and the descriptor it returns is ours.
+        let fd = cvt(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) })?;
+        Ok(Poller {
+            epoll: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    fn add(&self, fd: &impl AsRawFd, token: u64, interest: u32) -> io::Result<()> {
+        self.control(libc::EPOLL_CTL_ADD, fd, token, interest)
+    }
+
+    fn modify(&self, fd: &impl AsRawFd, token: u64, interest: u32) -> io::Result<()> {
+        self.control(libc::EPOLL_CTL_MOD, fd, token, interest)
+    }
+
+    fn delete(&self, fd: &impl AsRawFd) -> io::Result<()> {
+        self.control(libc::EPOLL_CTL_DEL, fd, 0, 0)
+    }
+
+    fn control(&self, op: i32, fd: &impl AsRawFd, token: u64, interest: u32) -> io::Result<()> {
+        let mut event = libc::epoll_event {
+            events: interest,
+            u64: token,
+        };
+        // SAFETY: `event` outlives the call; the kernel copies it.
+        cvt(unsafe { libc::epoll_ctl(self.epoll.as_raw_fd(), op, fd.as_raw_fd(), &mut event) })?;
+        Ok(())
+    }
+
+    /// Block until a registered descriptor is ready or `timeout` has passed, and put the
+    /// readiness events into `events`. `None` waits for as long as it takes.
+    fn wait(
+        &self,
+        events: &mut Vec<libc::epoll_event>,
+        timeout: Option<Duration>,
+    ) -> io::Result<()> {
+        // Round up, so the reactor does not wake just before a deadline and find nothing due.
+        let timeout = match timeout {
+            Some(timeout) => timeout.as_nanos().div_ceil(1_000_000).min(i32::MAX as u128) as i32,
+            None => -1,
+        };
+        events.clear();
+        // SAFETY: the kernel writes at most `capacity` events to the start of the buffer, and
+        // exactly as many as it reports.
+        let n = unsafe {
+            libc::epoll_wait(
+                self.epoll.as_raw_fd(),
+                events.as_mut_ptr(),
+                events.capacity() as i32,
+                timeout,
+            )
+        };
+        match cvt(n) {
+            Ok(n) => unsafe { events.set_len(n as usize) },
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+}
+
+fn cvt(result: i32) -> io::Result<i32> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+/// Interrupts a reactor's `epoll_wait` when a connection or a response is sent to it.
+struct Waker(UnixStream);
+
+impl Waker {
+    fn wake(&self) {
+        // A full buffer means a wakeup is pending already.
+        let _ = (&self.0).write(&[1]);
+    }
+}
+
+struct Reactor {
+    id: usize,
+    poller: Poller,
+    waker: Arc<Waker>,
+    wakeups: UnixStream,
+    incoming: mpsc::Receiver<Connection>,
+    done_tx: mpsc::Sender<(u64, Vec<u8>, bool)>,
+    done_rx: mpsc::Receiver<(u64, Vec<u8>, bool)>,
+    pool: Arc<ThreadPool>,
+    config: Arc<ServerConfig>,
+    hosts: Arc<VirtualHosts>,
+    websockets: WebSocketSlots,
+    connections: HashMap<u64, Connection>,
+    /// Every connection's deadline, soonest first.
+    timers: BTreeSet<(Instant, u64)>,
+    next_id: u64,
+}
+
+impl Reactor {
+    fn new(
+        id: usize,
+        incoming: mpsc::Receiver<Connection>,
+        pool: Arc<ThreadPool>,
+        config: Arc<ServerConfig>,
+        hosts: Arc<VirtualHosts>,
+        websockets: WebSocketSlots,
+    ) -> io::Result<Reactor> {
+        let poller = Poller::new()?;
+        let (waker, wakeups) = UnixStream::pair()?;
+        waker.set_nonblocking(true)?;
+        wakeups.set_nonblocking(true)?;
+        poller.add(&wakeups, WAKER, libc::EPOLLIN as u32)?;
+
+        let (done_tx, done_rx) = mpsc::channel();
+        Ok(Reactor {
+            id,
+            poller,
+            waker: Arc::new(Waker(waker)),
+            wakeups,
+            incoming,
+            done_tx,
+            done_rx,
+            pool,
+            config,
+            hosts,
+            websockets,
+            connections: HashMap::new(),
+            timers: BTreeSet::new(),
+            next_id: 0,
+        })
+    }
+
+    fn run(mut self) {
+        let mut events = Vec::with_capacity(MAX_EVENTS);
+
+        loop {
+            let timeout = self
+                .timers
+                .first()
+                .map(|&(deadline, _)| deadline.saturating_duration_since(Instant::now()));
+            if let Err(e) = self.poller.wait(&mut events, timeout) {
+                eprintln!("Reactor {} failed: {}", self.id, e);
+                return;
+            }
+
+            for event in &events {
+                let (token, ready) = (event.u64, event.events);
+                if token == WAKER {
+                    while let Ok(n) = (&self.wakeups).read(&mut [0; 64]) {
+                        if n == 0 {
+                            break;
+                        }
+                    }
+                    continue;
+                }
+                let step = match self.connections.get_mut(&token) {
+                    Some(_) if ready & (libc::EPOLLERR | libc::EPOLLHUP) as u32 != 0 => Step::Close,
+                    Some(conn) => match conn.state {
+                        State::Reading { .. } => conn.read(&self.config, &self.hosts),
+                        State::Writing { .. } => conn.write(&self.config, &self.hosts),
+                        State::Waiting { .. } => Step::Continue,
+                    },
+                    None => continue,
+                };
+                self.apply(token, step);
+            }
+
+            loop {
+                match self.incoming.try_recv() {
+                    Ok(conn) => self.add(conn),
+                    Err(mpsc::TryRecvError::Empty) => break,
+                    Err(mpsc::TryRecvError::Disconnected) => {
+                        if self.connections.is_empty() {
+                            println!("Reactor {} shutting down.", self.id);
+                            return;
+                        }
+                        break;
+                    }
+                }
+            }
+
+            while let Ok((id, response, keep_alive)) = self.done_rx.try_recv() {
+                // The connection may be gone, or have been answered with a 503 already.
+                if let Some(conn) = self.connections.get_mut(&id) {
+                    if let State::Waiting { rest } = &mut conn.state {
+                        let rest = std::mem::take(rest);
+                        conn.respond(response, keep_alive, rest, &self.config);
+                        self.apply(id, Step::Continue);
+                    }
+                }
+            }
+
+            let now = Instant::now();
+            while let Some(&(deadline, id)) = self.timers.first() {
+                if deadline > now {
+                    break;
+                }
+                self.timers.pop_first();
+                let step = match self.connections.get_mut(&id) {
+                    Some(conn) => conn.expire(&self.config, self.hosts.default_site()),
+                    None => continue,
+                };
+                self.apply(id, step);
+            }
+        }
+    }
+
+    fn add(&mut self, mut conn: Connection) {
+        let id = self.next_id;
+        self.next_id += 1;
+        conn.interest = conn.state.interest();
+        if let Err(e) = self.poller.add(&conn.stream, id, conn.interest) {
+            eprintln!("Connection error: {}", e);
+            return;
+        }
+        self.timers.insert((conn.deadline, id));
+        self.connections.insert(id, conn);
+    }
+
+    /// Carry out what a connection asked for after making progress.
+    fn apply(&mut self, id: u64, step: Step) {
+        match step {
+            Step::Continue => self.update(id),
+            Step::Dispatch(mut request) => {
+                self.update(id);
+                let conn = &self.connections[&id];
+                request.remote_addr = conn.stream.peer_addr().ok();
+                let done = self.done_tx.clone();
+                let waker = Arc::clone(&self.waker);
+                let hosts = Arc::clone(&self.hosts);
+                self.pool.execute(move || {
+                    let keep_alive = request.keep_alive();
+                    let response = server::route(hosts.select(&request), &request);
+                    let mut out = Vec::new();
+                    // Writing into a Vec cannot fail.
+                    server::send(&mut out, &request, response, keep_alive).unwrap();
+                    // The reactor may have dropped the connection in the meantime.
+                    if done.send((id, out, keep_alive)).is_ok() {
+                        waker.wake();
+                    }
+                });
+            }
+            Step::HandOff => {
+                let conn = self.remove(id);
+                let config = Arc::clone(&self.config);
+                let hosts = Arc::clone(&self.hosts);
+                let websockets = self.websockets.clone();
+                self.pool.execute(move || {
+                    let Connection {
+                        guard,
+                        stream,
+                        state,
+                        ..
+                    } = conn;
+                    let State::Reading { buf, .. } = state else {
+                        unreachable!("only a request being read is handed off")
+                    };
+                    let result = stream.set_nonblocking(false).and_then(|_| {
+                        let conn = http::Connection::with_read_ahead(stream, buf);
+                        server::serve(conn, &config, &hosts, &websockets, Some(guard))
+                    });
+                    if let Err(e) = result {
+                        eprintln!("Connection error: {}", e);
+                    }
+                });
+            }
+            Step::Close => {
+                self.remove(id);
+            }
+        }
+    }
+
+    /// Bring the connection's `epoll` registration and timer in line with its state.
+    fn update(&mut self, id: u64) {
+        let Some(conn) = self.connections.get_mut(&id) else {
+            return;
+        };
+        let interest = conn.state.interest();
+        if interest != conn.interest {
+            if let Err(e) = self.poller.modify(&conn.stream, id, interest) {
+                eprintln!("Connection error: {}", e);
+                self.remove(id);
+                return;
+            }
+            conn.interest = interest;
+        }
+        if conn.deadline != conn.armed {
+            self.timers.remove(&(conn.armed, id));
+            self.timers.insert((conn.deadline, id));
+            conn.armed = conn.deadline;
+        }
+    }
+
+    fn remove(&mut self, id: u64) -> Connection {
+        let conn = self.connections.remove(&id).unwrap();
+        self.timers.remove(&(conn.armed, id));
+        // Closing the socket would deregister it too, but a handed-off socket stays open.
+        let _ = self.poller.delete(&conn.stream);
+        conn
+    }
+}
+
+impl State {
+    /// The readiness events the connection waits for in this state.
+    fn interest(&self) -> u32 {
+        match self {
+            State::Reading { .. } => libc::EPOLLIN as u32,
+            State::Waiting { .. } => 0,
+            State::Writing { .. } => libc::EPOLLOUT as u32,
+        }
+    }
+}
+
+impl Connection {
+    fn read(&mut self, config: &ServerConfig, hosts: &VirtualHosts) -> Step {
+        let State::Reading { buf, .. } = &mut self.state else {
+            return Step::Continue;
+        };
+        let mut chunk = [0; 4096];
+        match self.stream.read(&mut chunk) {
+            Ok(0) => Step::Close,
+            Ok(n) => {
+                buf.extend_from_slice(&chunk[..n]);
+                self.parse(config, hosts)
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Step::Continue,
+            Err(_) => Step::Close,
+        }
+    }
+
+    /// Look for a complete request in what has been read so far.
+    fn parse(&mut self, config: &ServerConfig, hosts: &VirtualHosts) -> Step {
+        let State::Reading { buf, head } = &mut self.state else {
+            return Step::Continue;
+        };
+
+        if head.is_none() {
+            let (request, head_len) = match http::parse_head(buf, config) {
+                Ok(Some(parsed)) => parsed,
+                Ok(None) => return Step::Continue,
+                // There is no Host header to go by, so the default site answers.
+                Err(e) => return self.fail(e, config, hosts.default_site()),
+            };
+            let router = hosts.select(&request);
+            if server::takes_connection(router, &request) {
+                return Step::HandOff;
+            }
+            match http::body_length(&request, config) {
+                Ok(body_len) => {
+                    *head = Some((request, head_len, body_len));
+                    self.deadline = Instant::now() + config.body_read_timeout;
+                }
+                Err(e) => return self.fail(e, config, router),
+            }
+        }
+
+        match head {
+            Some((_, head_len, body_len)) if buf.len() >= *head_len + *body_len => {
+                let (mut request, head_len, body_len) = head.take().unwrap();
+                let rest = buf.split_off(head_len + body_len);
+                request.body = buf.split_off(head_len);
+                self.state = State::Waiting { rest };
+                self.deadline = Instant::now() + config.handler_timeout;
+                Step::Dispatch(request)
+            }
+            _ => Step::Continue,
+        }
+    }
+
+    fn write(&mut self, config: &ServerConfig, hosts: &VirtualHosts) -> Step {
+        let State::Writing {
+            buf,
+            written,
+            keep_alive,
+            rest,
+        } = &mut self.state
+        else {
+            return Step::Continue;
+        };
+        match self.stream.write(&buf[*written..]) {
+            Ok(0) => Step::Close,
+            Ok(n) => {
+                *written += n;
+                if *written < buf.len() {
+                    Step::Continue
+                } else if *keep_alive {
+                    // The next request may have arrived with this one already.
+                    self.state = State::Reading {
+                        buf: std::mem::take(rest),
+                        head: None,
+                    };
+                    self.deadline = Instant::now() + config.keep_alive_timeout;
+                    self.parse(config, hosts)
+                } else {
+                    Step::Close
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Step::Continue,
+            Err(_) => Step::Close,
+        }
+    }
+
+    fn respond(
+        &mut self,
+        response: Vec<u8>,
+        keep_alive: bool,
+        rest: Vec<u8>,
+        config: &ServerConfig,
+    ) {
+        self.state = State::Writing {
+            buf: response,
+            written: 0,
+            keep_alive,
+            rest,
+        };
+        self.deadline = Instant::now() + config.write_timeout;
+    }
+
+    /// The connection's deadline has passed.
+    fn expire(&mut self, config: &ServerConfig, router: &Router) -> Step {
+        match &self.state {
+            // A client that sends nothing at all before the deadline is treated as gone.
+            State::Reading { buf, .. } if buf.is_empty() => Step::Close,
+            State::Reading { .. } => self.fail(http::RequestError::Timeout, config, router),
+            State::Waiting { .. } => {
+                let mut buf = Vec::new();
+                server::error_response(router, ServerError::Status(503))
+                    .write_to(&mut buf)
+                    .unwrap();
+                self.respond(buf, false, Vec::new(), config);
+                Step::Continue
+            }
+            State::Writing { .. } => Step::Close,
+        }
+    }
+
+    /// Queue the error response for `e`, or close if the client is not worth answering.
+    fn fail(&mut self, e: http::RequestError, config: &ServerConfig, router: &Router) -> Step {
+        if e.status().is_none() {
+            return Step::Close;
+        }
+        let mut buf = Vec::new();
+        server::error_response(router, e.into())
+            .write_to(&mut buf)
+            .unwrap();
+        self.respond(buf, false, Vec::new(), config);
+        Step::Continue
+    }
+}
@@ -0,0 +1,268 @@
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::client::{Client, SendError};
+use crate::config::ServerConfig;
+use crate::http::{self, Request, Response};
+
+/// Headers that only concern a single hop and must not be forwarded.
+const HOP_BY_HOP: [&str; 8] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+/// Forwards requests to a set of upstream servers, picked round-robin.
+///
+/// Health is tracked passively: a backend that fails `max_failures` requests in a row is left
+/// out of the rotation for `cooldown`, after which it gets another chance.
+///
+/// Register it with a router through a closure, for example
+/// `router.prefix("/api", move |req| proxy.forward(req))`.
+pub struct Proxy {
+    backends: Vec<Backend>,
+    next: AtomicUsize,
+    client: Client,
+    timeout: Duration,
+    max_body_size: usize,
+    strip_prefix: Option<String>,
+    request_headers: Vec<(String, String)>,
+    response_headers: Vec<(String, String)>,
+    max_failures: usize,
+    cooldown: Duration,
+}
+
+struct Backend {
+    addr: String,
+    health: Mutex<Health>,
+}
+
+#[derive(Default)]
+struct Health {
+    failures: usize,
+    down_until: Option<Instant>,
+}
+
+impl Proxy {
+    /// Create a proxy for the given `host:port` backends.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no backend is given.
+    pub fn new(backends: &[&str]) -> Proxy {
+        assert!(!backends.is_empty());
+
+        Proxy {
+            backends: backends
+                .iter()
+                .map(|addr| Backend {
+                    addr: addr.to_string(),
+                    health: Mutex::new(Health::default()),
+                })
+                .collect(),
+            next: AtomicUsize::new(0),
+            client: Client::new(),
+            timeout: Duration::from_secs(30),
+            max_body_size: ServerConfig::default().max_body_size,
+            strip_prefix: None,
+            request_headers: Vec::new(),
+            response_headers: Vec::new(),
+            max_failures: 3,
+            cooldown: Duration::from_secs(10),
+        }
+        .with_client()
+    }
+
+    /// Replace the client, and with it the pooled connections, after a setting changed.
+    fn with_client(mut self) -> Proxy {
+        self.client = Client::new()
+            .with_timeout(self.timeout)
+            .with_max_body_size(self.max_body_size);
+        self
+    }
+
+    /// Bound for connecting to a backend and for each read and write on it.
+    pub fn with_timeout(mut self, timeout: Duration) -> Proxy {
+        self.timeout = timeout;
+        self.with_client()
+    }
+
+    /// Answer 502 instead of passing on response bodies larger than this. Defaults to the
+    /// default `max_body_size`.
+    pub fn with_max_body_size(mut self, bytes: usize) -> Proxy {
+        self.max_body_size = bytes;
+        self.with_client()
+    }
+
+    /// Remove `prefix` from the path before forwarding, so `/api/users` can become `/users`.
+    pub fn strip_prefix(mut self, prefix: &str) -> Proxy {
+        self.strip_prefix = Some(prefix.trim_end_matches('/').to_string());
+        self
+    }
+
+    /// Set a header on every forwarded request. An empty value removes the header instead.
+    pub fn set_request_header(mut self, name: &str, value: &str) -> Proxy {
+        self.request_headers
+            .push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Set a header on every response passed back. An empty value removes the header instead.
+    pub fn set_response_header(mut self, name: &str, value: &str) -> Proxy {
+        self.response_headers
+            .push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn with_health_check(mut self, max_failures: usize, cooldown: Duration) -> Proxy {
+        self.max_failures = max_failures;
+        self.cooldown = cooldown;
+        self
+    }
+
+    /// Forward `request` and return the upstream response, or 502/504 if no backend answered.
+    ///
+    /// Requests are only retried on another backend when no connection could be established,
+    /// timeouts included, so nothing is ever sent upstream twice.
+    pub fn forward(&self, request: &Request) -> Response {
+        let upstream = self.upstream_request(request);
+        let mut status = 502;
+
+        for backend in self.candidates() {
+            let (e, retry) = match self.client.try_send(&backend.addr, upstream.clone()) {
+                Ok(response) => {
+                    backend.succeeded();
+                    return self.downstream_response(response);
+                }
+                Err(SendError::Connect(e)) => (e, true),
+                Err(SendError::Exchange(e)) => (e, false),
+            };
+            eprintln!("Proxy: backend {} failed: {}", backend.addr, e);
+            backend.failed(self.max_failures, self.cooldown);
+            status = match e.kind() {
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => 504,
+                _ => 502,
+            };
+            if !retry {
+                break;
+            }
+        }
+
+        Response::new(status).with_body(format!("{} {}", status, http::reason_phrase(status)))
+    }
+
+    /// Healthy backends in round-robin order. If every backend is down they are all tried,
+    /// since failing outright would only prolong the outage.
+    fn candidates(&self) -> Vec<&Backend> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let rotation: Vec<&Backend> = (0..self.backends.len())
+            .map(|i| &self.backends[(start + i) % self.backends.len()])
+            .collect();
+
+        let healthy: Vec<&Backend> = rotation.iter().copied().filter(|b| b.is_up()).collect();
+        if healthy.is_empty() {
+            rotation
+        } else {
+            healthy
+        }
+    }
+
+    fn upstream_request(&self, request: &Request) -> Request {
+        let mut target = request.target.clone();
+        if let Some(prefix) = &self.strip_prefix {
+            if let Some(rest) = target.strip_prefix(prefix.as_str()) {
+                target = if rest.starts_with('/') {
+                    rest.to_string()
+                } else {
+                    format!("/{}", rest)
+                };
+            }
+        }
+
+        let mut upstream = Request::new(&request.method, &target).with_body(request.body.clone());
+        upstream.headers = without_hop_by_hop(&request.headers);
+        // The backend address is filled in by the client.
+        upstream
+            .headers
+            .retain(|(n, _)| !n.eq_ignore_ascii_case("Host"));
+
+        if let Some(addr) = request.remote_addr {
+            let forwarded_for = match request.header("X-Forwarded-For") {
+                Some(earlier) => format!("{}, {}", earlier, addr.ip()),
+                None => addr.ip().to_string(),
+            };
+            set_header(&mut upstream.headers, "X-Forwarded-For", &forwarded_for);
+        }
+        if let Some(host) = request.header("Host") {
+            set_header(&mut upstream.headers, "X-Forwarded-Host", host);
+        }
+        set_header(&mut upstream.headers, "X-Forwarded-Proto", "http");
+
+        for (name, value) in &self.request_headers {
+            set_header(&mut upstream.headers, name, value);
+        }
+        upstream
+    }
+
+    fn downstream_response(&self, mut response: Response) -> Response {
+        response.headers = without_hop_by_hop(&response.headers);
+        for (name, value) in &self.response_headers {
+            set_header(&mut response.headers, name, value);
+        }
+        response
+    }
+}
+
+impl Backend {
+    fn is_up(&self) -> bool {
+        let health = self.health.lock().unwrap();
+        health
+            .down_until
+            .is_none_or(|until| Instant::now() >= until)
+    }
+
+    fn succeeded(&self) {
+        *self.health.lock().unwrap() = Health::default();
+    }
+
+    fn failed(&self, max_failures: usize, cooldown: Duration) {
+        let mut health = self.health.lock().unwrap();
+        health.failures += 1;
+        if health.failures >= max_failures {
+            health.down_until = Some(Instant::now() + cooldown);
+        }
+    }
+}
+
+fn without_hop_by_hop(headers: &[(String, String)]) -> Vec<(String, String)> {
+    // Headers named in `Connection` are hop-by-hop as well.
+    let listed: Vec<&str> = headers
+        .iter()
+        .filter(|(n, _)| n.eq_ignore_ascii_case("Connection"))
+        .flat_map(|(_, v)| v.split(',').map(str::trim))
+        .collect();
+
+    headers
+        .iter()
+        .filter(|(n, _)| {
+            !HOP_BY_HOP.iter().any(|h| n.eq_ignore_ascii_case(h))
+                && !listed.iter().any(|h| n.eq_ignore_ascii_case(h))
+        })
+        .cloned()
+        .collect()
+}
+
+/// Replace all occurrences of header `name`, or remove them if `value` is empty.
+fn set_header(headers: &mut Vec<(String, String)>, name: &str, value: &str) {
+    headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    if !value.is_empty() {
+        headers.push((name.to_string(), value.to_string()));
+    }
+}
@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::error::ErrorPages;
+use crate::form::Multipart;
+use crate::http::{Request, Response};
+use crate::sse::EventSink;
+use crate::websocket::WebSocket;
+
+pub type Handler = Arc<dyn Fn(&Request) -> Response + Send + Sync + 'static>;
+pub type WebSocketHandler = Arc<dyn Fn(WebSocket) + Send + Sync + 'static>;
+pub type EventHandler = Arc<dyn Fn(&Request, EventSink) + Send + Sync + 'static>;
+pub type UploadHandler = Arc<dyn Fn(&Request, Multipart) -> Response + Send + Sync + 'static>;
+pub type Filter = Arc<dyn Fn(&Request) -> Option<Response> + Send + Sync + 'static>;
+
+/// Maps request paths to the handlers registered for them.
+///
+/// Exact routes are tried first, then the longest matching prefix route. Filters run before
+/// any of them. Requests no route takes are served from the document root, and error
+/// responses are given the router's error pages. Together these make up one site.
+#[derive(Default)]
+pub struct Router {
+    filters: Vec<Filter>,
+    document_root: PathBuf,
+    error_pages: ErrorPages,
+    routes: HashMap<(String, String), Handler>,
+    prefixes: Vec<(String, Handler)>,
+    uploads: HashMap<String, UploadHandler>,
+    websockets: HashMap<String, WebSocketHandler>,
+    event_streams: HashMap<String, EventHandler>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    /// Handle requests with exactly this method and path. The query string is not part of the
+    /// match.
+    pub fn route<F>(&mut self, method: &str, path: &str, handler: F)
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.routes
+            .insert((method.to_string(), path.to_string()), Arc::new(handler));
+    }
+
+    pub fn get<F>(&mut self, path: &str, handler: F)
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.route("GET", path, handler);
+    }
+
+    /// Handle every request, whatever the method, whose path is `prefix` or lies below it.
+    pub fn prefix<F>(&mut self, prefix: &str, handler: F)
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        let prefix = prefix.trim_end_matches('/').to_string();
+        self.prefixes.push((prefix, Arc::new(handler)));
+        // Keep the longest prefixes first so the most specific one wins.
+        self.prefixes
+            .sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+    }
+
+    /// Handle `multipart/form-data` POSTs to `path`. The body is not buffered like other
+    /// request bodies: filters run as soon as the headers are in, and then the form is parsed
+    /// straight from the connection, files going to disk as they arrive, so uploads are bounded
+    /// by `max_upload_size` instead of `max_body_size`. Only `Server` serves these routes.
+    pub fn upload<F>(&mut self, path: &str, handler: F)
+    where
+        F: Fn(&Request, Multipart) -> Response + Send + Sync + 'static,
+    {
+        self.uploads.insert(path.to_string(), Arc::new(handler));
+    }
+
+    pub fn upload_handler(&self, path: &str) -> Option<&UploadHandler> {
+        self.uploads.get(path)
+    }
+
+    /// Run `filter` on every request before it is routed, in the order filters were added. A
+    /// filter that returns a response answers the request in place of the handler.
+    pub fn filter<F>(&mut self, filter: F)
+    where
+        F: Fn(&Request) -> Option<Response> + Send + Sync + 'static,
+    {
+        self.filters.push(Arc::new(filter));
+    }
+
+    /// The first response produced by a filter, if any.
+    pub fn run_filters(&self, request: &Request) -> Option<Response> {
+        self.filters.iter().find_map(|filter| filter(request))
+    }
+
+    /// Serve static files from `root` instead of the working directory. Relative error page
+    /// paths are looked up there as well.
+    pub fn set_document_root<P: AsRef<Path>>(&mut self, root: P) {
+        self.document_root = root.as_ref().to_path_buf();
+        self.error_pages = self.error_pages.clone().relative_to(root);
+    }
+
+    pub fn document_root(&self) -> &Path {
+        &self.document_root
+    }
+
+    /// Replace the default error pages, which only have the book's `404.html`.
+    pub fn set_error_pages(&mut self, pages: ErrorPages) {
+        self.error_pages = pages.relative_to(&self.document_root);
+    }
+
+    pub fn error_pages(&self) -> &ErrorPages {
+        &self.error_pages
+    }
+
+    /// Serve WebSocket connections on `path`. The handler owns the socket until it returns and
+    /// runs on a thread of its own, so long-lived connections do not tie up pool workers;
+    /// `max_websockets` bounds how many run at once.
+    pub fn websocket<F>(&mut self, path: &str, handler: F)
+    where
+        F: Fn(WebSocket) + Send + Sync + 'static,
+    {
+        self.websockets.insert(path.to_string(), Arc::new(handler));
+    }
+
+    pub fn websocket_handler(&self, path: &str) -> Option<&WebSocketHandler> {
+        self.websockets.get(path)
+    }
+
+    /// Serve `text/event-stream` responses on `path`. The handler should keep the sink, for
+    /// example by subscribing it to a `Broadcaster`, and return promptly: it runs on a pool
+    /// worker, and the stream stays open until the last clone of the sink is dropped.
+    pub fn events<F>(&mut self, path: &str, handler: F)
+    where
+        F: Fn(&Request, EventSink) + Send + Sync + 'static,
+    {
+        self.event_streams
+            .insert(path.to_string(), Arc::new(handler));
+    }
+
+    pub fn event_handler(&self, path: &str) -> Option<&EventHandler> {
+        self.event_streams.get(path)
+    }
+
+    /// Find the handler for `request`, if any.
+    pub fn handler(&self, request: &Request) -> Option<&Handler> {
+        let path = request.path();
+        if let Some(handler) = self.routes.get(&(request.method.clone(), path.to_string())) {
+            return Some(handler);
+        }
+
+        self.prefixes
+            .iter()
+            .find(|(prefix, _)| is_below(path, prefix))
+            .map(|(_, handler)| handler)
+    }
+}
+
+/// Whether `path` equals `prefix` or is below it; `/api` matches `/api/x` but not `/apix`.
+pub fn is_below(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/') || prefix.is_empty(),
+        None => false,
+    }
+}
+
+/// Bring a request path into the one form that filters, routes and static files all go by.
+///
+/// Escapes are decoded, empty and `.` segments dropped, and whatever is not plain path text is
+/// escaped again, so `/%61dmin//./x` becomes `/admin/x` and every path has exactly one
+/// spelling. A trailing `/` is kept. Paths with a `..` segment, or that do not start with `/`,
+/// have no normal form and give `None`.
+pub fn normalize_path(path: &str) -> Option<String> {
+    let rest = path.strip_prefix('/')?;
+    let decoded = percent_decode_bytes(rest.as_bytes());
+
+    let mut normal = String::with_capacity(path.len());
+    let mut segments = decoded.split(|&b| b == b'/').peekable();
+    while let Some(segment) = segments.next() {
+        match segment {
+            b".." => return None,
+            b"" | b"." => {
+                if segments.peek().is_none() && !normal.is_empty() {
+                    normal.push('/');
+                }
+            }
+            segment => {
+                normal.push('/');
+                for &b in segment {
+                    if b.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@".contains(&b) {
+                        normal.push(b as char);
+                    } else {
+                        normal.push_str(&format!("%{:02X}", b));
+                    }
+                }
+            }
+        }
+    }
+    if normal.is_empty() {
+        normal.push('/');
+    }
+    Some(normal)
+}
+
+fn percent_decode_bytes(bytes: &[u8]) -> Vec<u8> {
+    let hex = |b: u8| (b as char).to_digit(16);
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match (
+            bytes[i],
+            bytes.get(i + 1).copied(),
+            bytes.get(i + 2).copied(),
+        ) {
+            (b'%', Some(hi), Some(lo)) if hex(hi).is_some() && hex(lo).is_some() => {
+                out.push((hex(hi).unwrap() * 16 + hex(lo).unwrap()) as u8);
+                i += 3;
+            }
+            (b, _, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn longest_prefix_wins() {
+        let mut router = Router::new();
+        router.prefix("/api", |_| Response::new(200));
+        router.prefix("/api/v2/", |_| Response::new(201));
+
+        let status = |target: &str| {
+            router
+                .handler(&Request::new("GET", target))
+                .map(|handler| handler(&Request::new("GET", target)).status)
+        };
+        assert_eq!(Some(201), status("/api/v2/users?page=1"));
+        assert_eq!(Some(200), status("/api/v1"));
+        assert_eq!(None, status("/apix"));
+    }
+
+    #[test]
+    fn paths_have_one_normal_form() {
+        let normal = |path: &str| normalize_path(path);
+
+        assert_eq!(Some(String::from("/admin/x")), normal("/%61dmin//./x"));
+        assert_eq!(Some(String::from("/admin/")), normal("//admin/./"));
+        assert_eq!(Some(String::from("/")), normal("/./"));
+        assert_eq!(Some(String::from("/a%20b/%2561")), normal("/a b/%2561"));
+        assert_eq!(normal("/a%20b/%2561"), normal("/a b/%2561"));
+        assert_eq!(None, normal("/css/%2E%2E/secret"));
+        assert_eq!(None, normal("/a/..%2Fb"));
+        assert_eq!(None, normal("admin"));
+    }
+}
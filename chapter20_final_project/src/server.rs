@@ -0,0 +1,511 @@
+use std::any::Any;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use crate::config::ServerConfig;
+use crate::error::ServerError;
+use crate::form;
+use crate::http::{reason_phrase, Connection, Request, RequestError, Response};
+use crate::limit::{ConnectionGuard, ConnectionLimiter};
+use crate::router::{Router, UploadHandler};
+use crate::sse;
+use crate::vhost::VirtualHosts;
+use crate::websocket::{self, WebSocket};
+use crate::ThreadPool;
+
+/// The thread-per-connection server: the accept loop runs on the caller's thread and every
+/// connection is handed to a `ThreadPool` worker.
+pub struct Server {
+    listener: TcpListener,
+    pool: ThreadPool,
+    config: Arc<ServerConfig>,
+    hosts: Arc<VirtualHosts>,
+    limiter: ConnectionLimiter,
+    websockets: WebSocketSlots,
+}
+
+/// Counts the WebSocket connections a server is running, to hold them to `max_websockets`.
+#[derive(Clone, Default)]
+pub(crate) struct WebSocketSlots(Arc<AtomicUsize>);
+
+/// One running WebSocket connection; the slot is released when it is dropped.
+struct WebSocketSlot(Arc<AtomicUsize>);
+
+impl WebSocketSlots {
+    fn try_acquire(&self, max: usize) -> Option<WebSocketSlot> {
+        self.0
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < max).then_some(n + 1)
+            })
+            .ok()?;
+        Some(WebSocketSlot(Arc::clone(&self.0)))
+    }
+}
+
+impl Drop for WebSocketSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl Server {
+    pub fn bind<A: ToSocketAddrs>(
+        addr: A,
+        workers: usize,
+        config: ServerConfig,
+    ) -> io::Result<Server> {
+        let listener = TcpListener::bind(addr)?;
+        let limiter = ConnectionLimiter::new(config.max_connections_per_ip);
+
+        Ok(Server {
+            listener,
+            pool: ThreadPool::new(workers),
+            config: Arc::new(config),
+            hosts: Arc::new(VirtualHosts::default()),
+            limiter,
+            websockets: WebSocketSlots::default(),
+        })
+    }
+
+    /// Serve every request with `router`.
+    pub fn with_router(self, router: Router) -> Server {
+        self.with_hosts(VirtualHosts::new(router))
+    }
+
+    /// Pick the router for each request by its `Host` header.
+    pub fn with_hosts(mut self, hosts: VirtualHosts) -> Server {
+        self.hosts = Arc::new(hosts);
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn listener(&self) -> &TcpListener {
+        &self.listener
+    }
+
+    /// Hand an accepted connection to the pool. Connections from addresses that are already at
+    /// their limit are closed straight away instead of queueing up behind the workers.
+    pub fn dispatch(&self, stream: TcpStream) {
+        let guard = match stream
+            .peer_addr()
+            .map(|addr| self.limiter.try_acquire(addr.ip()))
+        {
+            Ok(Some(guard)) => guard,
+            Ok(None) => {
+                eprintln!("Too many connections from one address; closing.");
+                return;
+            }
+            Err(e) => {
+                eprintln!("Could not determine peer address: {}", e);
+                return;
+            }
+        };
+
+        let config = Arc::clone(&self.config);
+        let hosts = Arc::clone(&self.hosts);
+        let websockets = self.websockets.clone();
+        self.pool.execute(move || {
+            let conn = Connection::new(stream);
+            if let Err(e) = serve(conn, &config, &hosts, &websockets, Some(guard)) {
+                eprintln!("Connection error: {}", e);
+            }
+        });
+    }
+}
+
+pub fn handle_connection(stream: TcpStream, config: &ServerConfig, hosts: &VirtualHosts) {
+    let websockets = WebSocketSlots::default();
+    if let Err(e) = serve(Connection::new(stream), config, hosts, &websockets, None) {
+        eprintln!("Connection error: {}", e);
+    }
+}
+
+/// Answer requests on one connection until either side asks to close it. The connection's
+/// `guard` is released when this returns, unless a WebSocket or event stream takes it over.
+pub(crate) fn serve(
+    mut conn: Connection,
+    config: &ServerConfig,
+    hosts: &VirtualHosts,
+    websockets: &WebSocketSlots,
+    guard: Option<ConnectionGuard>,
+) -> io::Result<()> {
+    conn.stream()
+        .set_write_timeout(Some(config.write_timeout))?;
+    // Responses go out in one write, and the next request will not arrive before the client
+    // has it, so there is nothing to gain from holding small packets back.
+    conn.stream().set_nodelay(true)?;
+    let mut header_timeout = config.header_read_timeout;
+
+    loop {
+        let mut request = match conn.read_head(config, header_timeout) {
+            Ok(request) => request,
+            // There is no Host header to go by, so the default site answers.
+            Err(e) => return reject(&mut conn, hosts.default_site(), e),
+        };
+        let router = hosts.select(&request);
+        header_timeout = config.keep_alive_timeout;
+
+        // Uploads are read from the connection by the form parser rather than up front.
+        if request.method == "POST" {
+            if let Some(handler) = router.upload_handler(request.path()) {
+                let (response, reusable) =
+                    receive_upload(&mut conn, config, router, &request, handler);
+                if !send(
+                    conn.stream(),
+                    &request,
+                    response,
+                    reusable && request.keep_alive(),
+                )? {
+                    return Ok(());
+                }
+                continue;
+            }
+        }
+        if let Err(e) = conn.read_body(&mut request, config) {
+            return reject(&mut conn, router, e);
+        }
+
+        if websocket::is_upgrade(&request) {
+            if let Some(handler) = router.websocket_handler(request.path()) {
+                if let Some(response) = run_filters(router, &request) {
+                    return response
+                        .with_header("Connection", "close")
+                        .write_to(conn.stream());
+                }
+                let response = match websocket::handshake(&request) {
+                    Ok(response) => response,
+                    Err(response) => return response.write_to(conn.stream()),
+                };
+                let slot = match websockets.try_acquire(config.max_websockets) {
+                    Some(slot) => slot,
+                    None => {
+                        let busy = error_response(router, ServerError::Status(503));
+                        return busy.write_to(conn.stream());
+                    }
+                };
+                response.write_to(conn.stream())?;
+                let (stream, pending) = conn.into_parts();
+                let socket = WebSocket::new(stream, pending, config.websocket_idle_timeout)?;
+                // The handler may keep the socket for hours; give the worker back right away.
+                let handler = Arc::clone(handler);
+                thread::Builder::new()
+                    .name(String::from("websocket"))
+                    .spawn(move || {
+                        catch_panic("WebSocket", || handler(socket));
+                        drop((slot, guard));
+                    })?;
+                return Ok(());
+            }
+        }
+
+        if request.method == "GET" {
+            if let Some(handler) = router.event_handler(request.path()) {
+                if let Some(response) = run_filters(router, &request) {
+                    return response
+                        .with_header("Connection", "close")
+                        .write_to(conn.stream());
+                }
+                // The sink owns the socket from here on; this worker is free again as soon as
+                // the handler returns.
+                let (stream, _) = conn.into_parts();
+                let sink = sse::start(stream, &request, config.event_heartbeat_interval, guard)?;
+                catch_panic("Event stream", || handler(&request, sink));
+                return Ok(());
+            }
+        }
+
+        let response = route(router, &request);
+        if !send(conn.stream(), &request, response, request.keep_alive())? {
+            return Ok(());
+        }
+    }
+}
+
+/// Write `response` to `request`, asking the client to close the connection unless
+/// `keep_alive`. Returns whether the connection stays open.
+pub(crate) fn send<W: Write>(
+    out: &mut W,
+    request: &Request,
+    response: Response,
+    keep_alive: bool,
+) -> io::Result<bool> {
+    let response = if keep_alive {
+        response
+    } else {
+        response.with_header("Connection", "close")
+    };
+    if request.method == "HEAD" {
+        response.write_head_to(out)?;
+    } else {
+        response.write_to(out)?;
+    }
+    Ok(keep_alive)
+}
+
+/// Whether `serve` gives `request` the connection to itself: WebSocket upgrades, event streams
+/// and uploads read from or write to the socket for as long as they run.
+pub(crate) fn takes_connection(router: &Router, request: &Request) -> bool {
+    let path = request.path();
+    (websocket::is_upgrade(request) && router.websocket_handler(path).is_some())
+        || (request.method == "GET" && router.event_handler(path).is_some())
+        || (request.method == "POST" && router.upload_handler(path).is_some())
+}
+
+/// Answer a request that could not be read, if the client is still worth answering.
+fn reject(conn: &mut Connection, router: &Router, e: RequestError) -> io::Result<()> {
+    match e.status() {
+        Some(_) => error_response(router, e.into()).write_to(conn.stream()),
+        None => Ok(()),
+    }
+}
+
+/// Run the filters on an upload request and, if they let it through, parse its form from the
+/// connection and hand it to `handler`. Also returns whether the whole body was read, as the
+/// connection cannot carry another request otherwise.
+fn receive_upload(
+    conn: &mut Connection,
+    config: &ServerConfig,
+    router: &Router,
+    request: &Request,
+    handler: &UploadHandler,
+) -> (Response, bool) {
+    if let Some(response) = run_filters(router, request) {
+        return (with_error_page(router, Ok(response)), false);
+    }
+    // Clients that wait for the go-ahead before sending a large body get it now. Should the
+    // write fail, reading the body fails as well.
+    if request
+        .header("Expect")
+        .is_some_and(|value| value.eq_ignore_ascii_case("100-continue"))
+    {
+        let _ = Response::new(100).write_to(conn.stream());
+    }
+    match conn.multipart(request, config) {
+        Ok(form) => {
+            let result = call_handler(|| handler(request, form));
+            (with_error_page(router, result), true)
+        }
+        Err(e) => {
+            eprintln!("Upload failed: {}", e);
+            (
+                with_error_page(router, Ok(Response::new(e.status()))),
+                false,
+            )
+        }
+    }
+}
+
+/// The response sent when the request itself could not be read.
+pub(crate) fn error_response(router: &Router, error: ServerError) -> Response {
+    router
+        .error_pages()
+        .render(&error)
+        .with_header("Connection", "close")
+}
+
+/// Answer `request` with the router, falling back to the files in its document root. Handlers that panic
+/// get a 500 instead of taking the worker down, and error responses without a body of their
+/// own are given the matching error page.
+pub(crate) fn route(router: &Router, request: &Request) -> Response {
+    let result = match run_filters(router, request) {
+        Some(response) => Ok(response),
+        None => match router.handler(request) {
+            Some(handler) => call_handler(|| handler(request)),
+            None => static_file(router, request),
+        },
+    };
+    with_error_page(router, result)
+}
+
+/// Run the router's filters. A filter that panics answers the request with a 500, as a
+/// handler would.
+fn run_filters(router: &Router, request: &Request) -> Option<Response> {
+    panic::catch_unwind(AssertUnwindSafe(|| router.run_filters(request))).unwrap_or_else(
+        |payload| {
+            let error = ServerError::Panic(panic_message(payload.as_ref()));
+            Some(router.error_pages().render(&error))
+        },
+    )
+}
+
+fn call_handler<F: FnOnce() -> Response>(handler: F) -> Result<Response, ServerError> {
+    panic::catch_unwind(AssertUnwindSafe(handler))
+        .map_err(|payload| ServerError::Panic(panic_message(payload.as_ref())))
+}
+
+/// Give error responses without a body of their own the matching error page.
+fn with_error_page(router: &Router, result: Result<Response, ServerError>) -> Response {
+    match result {
+        Ok(response) if response.status >= 400 && response.body.is_empty() => {
+            let pages = router.error_pages();
+            let page = pages.render_status(response.status, reason_phrase(response.status));
+            // Keep the handler's headers, such as `WWW-Authenticate` or `Retry-After`.
+            let response = match response.header("Content-Type") {
+                Some(_) => response,
+                None => response.with_header("Content-Type", "text/html; charset=utf-8"),
+            };
+            response.with_body(page.body)
+        }
+        Ok(response) => response,
+        Err(e) => router.error_pages().render(&e),
+    }
+}
+
+/// Serve the file below the document root that `request` names, `hello.html` for `/`.
+fn static_file(router: &Router, request: &Request) -> Result<Response, ServerError> {
+    if request.method != "GET" && request.method != "HEAD" {
+        return Err(ServerError::NotFound);
+    }
+    // The path was normalized when the request was read, so this is its only decoding.
+    let path = form::percent_decode(request.path(), false);
+    let relative = match path.trim_start_matches('/') {
+        "" => "hello.html",
+        relative => relative,
+    };
+    // Never leave the document root.
+    if relative
+        .split('/')
+        .any(|part| part == ".." || part.contains('\\') || part.contains('\0'))
+    {
+        return Err(ServerError::NotFound);
+    }
+
+    let file = router.document_root().join(relative);
+    if file.is_dir() {
+        return Err(ServerError::NotFound);
+    }
+    let contents = fs::read(&file)?;
+    Ok(Response::new(200)
+        .with_header("Content-Type", content_type(&file))
+        .with_body(contents))
+}
+
+fn content_type(file: &Path) -> &'static str {
+    match file.extension().and_then(|e| e.to_str()) {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css",
+        Some("js") => "text/javascript",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("ico") => "image/x-icon",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Run a handler that owns its connection, so a panic in it only ends that connection instead
+/// of the worker running it.
+fn catch_panic<F: FnOnce()>(kind: &str, handler: F) {
+    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(handler)) {
+        eprintln!(
+            "{} handler panicked: {}",
+            kind,
+            panic_message(payload.as_ref())
+        );
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        String::from("unknown panic")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failures_become_error_pages() {
+        let mut router = Router::new();
+        router.get("/boom", |_| panic!("handler bug"));
+        router.get("/denied", |_| ServerError::Forbidden.into());
+
+        let panicked = route(&router, &Request::new("GET", "/boom"));
+        assert_eq!(500, panicked.status);
+        assert!(!String::from_utf8(panicked.body)
+            .unwrap()
+            .contains("handler bug"));
+
+        let mut filtered = Router::new();
+        filtered.filter(|_| panic!("filter bug"));
+        assert_eq!(500, route(&filtered, &Request::new("GET", "/")).status);
+
+        let denied = route(&router, &Request::new("GET", "/denied"));
+        assert_eq!(403, denied.status);
+        assert!(!denied.body.is_empty());
+
+        let missing = route(&router, &Request::new("GET", "/nowhere"));
+        assert_eq!(404, missing.status);
+        assert_eq!(fs::read("404.html").unwrap(), missing.body);
+    }
+
+    #[test]
+    fn serves_files_from_the_document_root() {
+        let root = std::env::temp_dir().join(format!("docroot-{}", std::process::id()));
+        fs::create_dir_all(root.join("css")).unwrap();
+        fs::write(root.join("css/site.css"), "body {}").unwrap();
+        fs::write(root.join("404.html"), "site missing").unwrap();
+        let mut router = Router::new();
+        router.set_document_root(&root);
+
+        let css = route(&router, &Request::new("GET", "/css/site%2Ecss"));
+        let escape = route(&router, &Request::new("GET", "/css/../../etc/passwd"));
+        let missing = route(&router, &Request::new("GET", "/hello.html"));
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(200, css.status);
+        assert_eq!(Some("text/css"), css.header("Content-Type"));
+        assert_eq!(404, escape.status);
+        assert_eq!(b"site missing".to_vec(), missing.body);
+    }
+
+    #[test]
+    fn filters_see_the_path_files_are_served_from() {
+        let root = std::env::temp_dir().join(format!("protected-{}", std::process::id()));
+        fs::create_dir_all(root.join("admin")).unwrap();
+        fs::write(root.join("admin/secret.html"), "secret").unwrap();
+        let mut router = Router::new();
+        router.set_document_root(&root);
+        router.filter(|request| {
+            crate::router::is_below(request.path(), "/admin").then(|| Response::new(401))
+        });
+
+        let config = ServerConfig::default();
+        let statuses: Vec<u16> = [
+            "/admin/secret.html",
+            "/%61dmin/secret.html",
+            "//admin/secret.html",
+            "/./admin/secret.html",
+        ]
+        .iter()
+        .map(|path| {
+            let head = format!("GET {} HTTP/1.1\r\nHost: a\r\n\r\n", path);
+            let (request, _) = crate::http::parse_head(head.as_bytes(), &config)
+                .unwrap()
+                .unwrap();
+            route(&router, &request).status
+        })
+        .collect();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(vec![401; 4], statuses);
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::cookie::{Cookie, SameSite};
+use crate::digest;
+use crate::http::{Request, Response};
+
+/// The data of one session. Changes take effect once the session is `save`d back to the store.
+#[derive(Debug, Clone)]
+pub struct Session {
+    id: String,
+    pub data: HashMap<String, String>,
+}
+
+impl Session {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.data.get(key).map(String::as_str)
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) {
+        self.data.insert(key.to_string(), value.to_string());
+    }
+}
+
+/// `save` sweeps out expired sessions once the map has grown to twice its size after the last
+/// sweep, and never below this many sessions, so the sweeps cost constant time per save.
+const MIN_SWEEP_SIZE: usize = 1024;
+
+struct Entry {
+    data: HashMap<String, String>,
+    expires: Instant,
+}
+
+struct Sessions {
+    entries: HashMap<String, Entry>,
+    sweep_at: usize,
+}
+
+/// Sessions kept in memory and identified by a random id in a cookie.
+///
+/// A session expires after `ttl` without being loaded or saved. Share the store between
+/// handlers through an `Arc`.
+pub struct SessionStore {
+    cookie_name: String,
+    ttl: Duration,
+    secure: bool,
+    sessions: Mutex<Sessions>,
+}
+
+impl SessionStore {
+    pub fn new(ttl: Duration) -> SessionStore {
+        SessionStore {
+            cookie_name: String::from("session"),
+            ttl,
+            secure: false,
+            sessions: Mutex::new(Sessions {
+                entries: HashMap::new(),
+                sweep_at: MIN_SWEEP_SIZE,
+            }),
+        }
+    }
+
+    pub fn with_cookie_name(mut self, name: &str) -> SessionStore {
+        self.cookie_name = name.to_string();
+        self
+    }
+
+    /// Mark the session cookie `Secure`, for sites served over HTTPS.
+    pub fn secure(mut self, secure: bool) -> SessionStore {
+        self.secure = secure;
+        self
+    }
+
+    /// The session the request's cookie refers to, if it exists and has not expired.
+    pub fn load(&self, request: &Request) -> Option<Session> {
+        let id = request.cookie(&self.cookie_name)?;
+        let mut sessions = self.sessions.lock().unwrap();
+        let now = Instant::now();
+
+        match sessions.entries.get_mut(id) {
+            Some(entry) if entry.expires > now => {
+                entry.expires = now + self.ttl;
+                Some(Session {
+                    id: id.to_string(),
+                    data: entry.data.clone(),
+                })
+            }
+            Some(_) => {
+                sessions.entries.remove(id);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// The existing session for `request`, or a new empty one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the operating system cannot provide random bytes for a new session id.
+    pub fn load_or_create(&self, request: &Request) -> Session {
+        self.load(request).unwrap_or_else(|| Session {
+            id: new_session_id(),
+            data: HashMap::new(),
+        })
+    }
+
+    /// Store `session` and attach its cookie to `response`.
+    pub fn save(&self, session: &Session, response: Response) -> Response {
+        let mut sessions = self.sessions.lock().unwrap();
+        let now = Instant::now();
+        // Sweep expired sessions here so the map does not grow without bound.
+        if sessions.entries.len() >= sessions.sweep_at {
+            sessions.entries.retain(|_, entry| entry.expires > now);
+            sessions.sweep_at = MIN_SWEEP_SIZE.max(2 * sessions.entries.len());
+        }
+        sessions.entries.insert(
+            session.id.clone(),
+            Entry {
+                data: session.data.clone(),
+                expires: now + self.ttl,
+            },
+        );
+
+        let cookie = Cookie::new(&self.cookie_name, &session.id)
+            .max_age(self.ttl)
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(SameSite::Lax);
+        response.with_cookie(&cookie)
+    }
+
+    /// Forget the session and tell the client to drop its cookie.
+    pub fn destroy(&self, session: Session, response: Response) -> Response {
+        self.sessions.lock().unwrap().entries.remove(&session.id);
+        response.with_cookie(&Cookie::removal(&self.cookie_name))
+    }
+}
+
+/// 128 random bits from the operating system, hex encoded. There is no weaker fallback: an id
+/// that can be guessed hands the session to whoever guesses it.
+fn new_session_id() -> String {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).expect("no random bytes for a session id");
+    digest::to_hex(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_with(cookie: &str) -> Request {
+        Request::new("GET", "/").with_header("Cookie", cookie)
+    }
+
+    #[test]
+    fn saved_sessions_can_be_loaded_until_they_expire() {
+        let store = SessionStore::new(Duration::from_millis(50));
+        let mut session = store.load_or_create(&Request::new("GET", "/"));
+        session.set("user", "ferris");
+
+        let response = store.save(&session, Response::new(200));
+        let set_cookie = response.header("Set-Cookie").unwrap();
+        assert!(set_cookie.contains("HttpOnly") && set_cookie.contains("SameSite=Lax"));
+
+        let cookie = format!("session={}", session.id());
+        let loaded = store.load(&request_with(&cookie)).unwrap();
+        assert_eq!(Some("ferris"), loaded.get("user"));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(store.load(&request_with(&cookie)).is_none());
+    }
+
+    #[test]
+    fn expired_sessions_are_swept_in_batches() {
+        let store = SessionStore::new(Duration::ZERO);
+        let request = Request::new("GET", "/");
+        for _ in 0..MIN_SWEEP_SIZE {
+            store.save(&store.load_or_create(&request), Response::new(200));
+        }
+        assert_eq!(MIN_SWEEP_SIZE, store.sessions.lock().unwrap().entries.len());
+
+        store.save(&store.load_or_create(&request), Response::new(200));
+        assert_eq!(1, store.sessions.lock().unwrap().entries.len());
+    }
+}
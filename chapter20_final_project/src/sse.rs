@@ -0,0 +1,349 @@
+//! Server-Sent Events: one-way streaming of events over a long-lived `text/event-stream`
+//! response.
+//!
+//! The worker that accepts an event stream only writes the response head and calls the
+//! handler; the connection then lives on in the `EventSink` the handler keeps, so no worker
+//! stays tied up while events trickle out. A shared background thread writes heartbeat comments
+//! to quiet streams so proxies do not time them out and dead clients are noticed.
+//!
+//! Sinks never block: what the socket does not take at once is queued, and a client that lets
+//! more than `MAX_BACKLOG` bytes pile up is dropped instead of holding up the sender.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::net::{Shutdown, TcpStream};
+use std::sync::{mpsc, Arc, Mutex, OnceLock, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::http::Request;
+use crate::limit::ConnectionGuard;
+
+/// How often the heartbeat thread looks for streams that are due a heartbeat, and retries
+/// writing what is queued for slow ones.
+const HEARTBEAT_TICK: Duration = Duration::from_secs(1);
+
+/// Most bytes queued for a client that does not read fast enough before it is dropped.
+const MAX_BACKLOG: usize = 64 * 1024;
+
+/// One event. Only `data` is required; `id` is what clients send back as `Last-Event-ID` when
+/// they reconnect.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Event {
+    id: Option<String>,
+    name: Option<String>,
+    data: String,
+    retry: Option<Duration>,
+}
+
+impl Event {
+    pub fn new(data: &str) -> Event {
+        Event {
+            data: data.to_string(),
+            ..Event::default()
+        }
+    }
+
+    pub fn id(mut self, id: &str) -> Event {
+        self.id = Some(single_line(id));
+        self
+    }
+
+    /// The event type; clients listen for it with `addEventListener(name, ...)`.
+    pub fn name(mut self, name: &str) -> Event {
+        self.name = Some(single_line(name));
+        self
+    }
+
+    /// How long the client should wait before reconnecting after the stream drops.
+    pub fn retry(mut self, retry: Duration) -> Event {
+        self.retry = Some(retry);
+        self
+    }
+}
+
+/// The wire format of the event, ending with the blank line that dispatches it.
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(id) = &self.id {
+            writeln!(f, "id: {}", id)?;
+        }
+        if let Some(name) = &self.name {
+            writeln!(f, "event: {}", name)?;
+        }
+        if let Some(retry) = self.retry {
+            writeln!(f, "retry: {}", retry.as_millis())?;
+        }
+        // Every line of the data needs its own field; the client joins them with newlines.
+        for line in self.data.split('\n') {
+            writeln!(f, "data: {}", line.strip_suffix('\r').unwrap_or(line))?;
+        }
+        writeln!(f)
+    }
+}
+
+fn single_line(text: &str) -> String {
+    text.replace(['\r', '\n'], "")
+}
+
+/// The sending end of one event stream. Clones send to the same client; the connection is
+/// closed, and its slot under `max_connections_per_ip` released, once the last clone is
+/// dropped.
+#[derive(Clone)]
+pub struct EventSink {
+    inner: Arc<SinkInner>,
+    last_event_id: Option<String>,
+}
+
+struct SinkInner {
+    stream: Mutex<Stream>,
+    heartbeat: Duration,
+    _guard: Option<ConnectionGuard>,
+}
+
+struct Stream {
+    /// Non-blocking, so a client that stops reading cannot stall the writer.
+    stream: TcpStream,
+    /// Bytes the socket has not taken yet.
+    backlog: Vec<u8>,
+    last_write: Instant,
+    closed: bool,
+}
+
+impl EventSink {
+    /// The id of the last event the client saw before reconnecting, if any.
+    pub fn last_event_id(&self) -> Option<&str> {
+        self.last_event_id.as_deref()
+    }
+
+    pub fn send(&self, event: &Event) -> io::Result<()> {
+        self.write(event.to_string().as_bytes())
+    }
+
+    /// Send a comment, which clients ignore.
+    pub fn comment(&self, text: &str) -> io::Result<()> {
+        self.write(format!(": {}\n\n", single_line(text)).as_bytes())
+    }
+
+    /// Whether the client has gone, or was dropped for falling too far behind.
+    pub fn is_closed(&self) -> bool {
+        self.inner.stream.lock().unwrap().closed
+    }
+
+    /// Queue `bytes` and write as much as the socket takes right now.
+    fn write(&self, bytes: &[u8]) -> io::Result<()> {
+        let mut stream = self.inner.stream.lock().unwrap();
+        if stream.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        stream.backlog.extend_from_slice(bytes);
+        stream.last_write = Instant::now();
+        stream.flush_backlog()
+    }
+}
+
+impl Stream {
+    /// Write queued bytes until the socket would block. Fails, and closes the stream, if the
+    /// client has gone or has let too much pile up.
+    fn flush_backlog(&mut self) -> io::Result<()> {
+        while !self.backlog.is_empty() {
+            match self.stream.write(&self.backlog) {
+                Ok(0) => return self.close(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.backlog.drain(..n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return self.close(e),
+            }
+        }
+        if self.backlog.len() > MAX_BACKLOG {
+            return self.close(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "client is not reading its events",
+            ));
+        }
+        Ok(())
+    }
+
+    fn close(&mut self, e: io::Error) -> io::Result<()> {
+        self.closed = true;
+        self.backlog = Vec::new();
+        let _ = self.stream.shutdown(Shutdown::Both);
+        Err(e)
+    }
+}
+
+impl SinkInner {
+    /// Retry what is queued, and write a heartbeat if the stream has been quiet for too long.
+    /// Returns whether the stream is still open.
+    fn heartbeat(&self) -> bool {
+        let mut stream = self.stream.lock().unwrap();
+        if stream.closed {
+            return false;
+        }
+        if stream.last_write.elapsed() >= self.heartbeat {
+            stream.backlog.extend_from_slice(b": heartbeat\n\n");
+            stream.last_write = Instant::now();
+        }
+        stream.flush_backlog().is_ok()
+    }
+}
+
+/// Write the response head for `request` and turn `stream` into an event sink, which holds on
+/// to the connection's `guard` for as long as it lives.
+pub(crate) fn start(
+    mut stream: TcpStream,
+    request: &Request,
+    heartbeat: Duration,
+    guard: Option<ConnectionGuard>,
+) -> io::Result<EventSink> {
+    stream.write_all(
+        b"HTTP/1.1 200 OK\r\n\
+          Content-Type: text/event-stream\r\n\
+          Cache-Control: no-cache\r\n\
+          Connection: close\r\n\r\n",
+    )?;
+    stream.set_nonblocking(true)?;
+
+    let inner = Arc::new(SinkInner {
+        stream: Mutex::new(Stream {
+            stream,
+            backlog: Vec::new(),
+            last_write: Instant::now(),
+            closed: false,
+        }),
+        heartbeat,
+        _guard: guard,
+    });
+    register(Arc::downgrade(&inner));
+
+    Ok(EventSink {
+        inner,
+        last_event_id: request.header("Last-Event-ID").map(str::to_string),
+    })
+}
+
+/// Hand `sink` to the heartbeat thread, starting the thread on first use. It only holds weak
+/// references, so it never keeps a connection open by itself.
+fn register(sink: Weak<SinkInner>) {
+    static REGISTER: OnceLock<mpsc::Sender<Weak<SinkInner>>> = OnceLock::new();
+    let register = REGISTER.get_or_init(|| {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || send_heartbeats(rx));
+        tx
+    });
+    // The thread never exits, so the receiver is always there.
+    let _ = register.send(sink);
+}
+
+fn send_heartbeats(new_sinks: mpsc::Receiver<Weak<SinkInner>>) {
+    let mut sinks = Vec::new();
+    loop {
+        let deadline = Instant::now() + HEARTBEAT_TICK;
+        while let Some(wait) = deadline.checked_duration_since(Instant::now()) {
+            match new_sinks.recv_timeout(wait) {
+                Ok(sink) => sinks.push(sink),
+                Err(mpsc::RecvTimeoutError::Timeout) => break,
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            }
+        }
+        sinks.retain(|sink: &Weak<SinkInner>| sink.upgrade().is_some_and(|sink| sink.heartbeat()));
+    }
+}
+
+/// Fans events out to every subscribed stream and keeps the most recent ones, so clients that
+/// reconnect with `Last-Event-ID` get what they missed.
+pub struct Broadcaster {
+    state: Mutex<BroadcastState>,
+    history: usize,
+}
+
+struct BroadcastState {
+    sinks: Vec<EventSink>,
+    recent: VecDeque<Event>,
+}
+
+impl Broadcaster {
+    /// Keep the last `history` events for replay.
+    pub fn new(history: usize) -> Broadcaster {
+        Broadcaster {
+            state: Mutex::new(BroadcastState {
+                sinks: Vec::new(),
+                recent: VecDeque::with_capacity(history),
+            }),
+            history,
+        }
+    }
+
+    /// Add `sink`, first replaying the events after its `Last-Event-ID`. A client whose last
+    /// event is no longer in the history gets all of it.
+    pub fn subscribe(&self, sink: EventSink) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(last) = sink.last_event_id() {
+            let start = state
+                .recent
+                .iter()
+                .position(|e| e.id.as_deref() == Some(last))
+                .map_or(0, |i| i + 1);
+            for event in state.recent.iter().skip(start) {
+                if sink.send(event).is_err() {
+                    return;
+                }
+            }
+        }
+        state.sinks.push(sink);
+    }
+
+    /// Send `event` to every subscriber, dropping those that have gone away or fallen too far
+    /// behind.
+    ///
+    /// The subscribers are copied out first, so subscribing is not held up while the event is
+    /// written. Events sent from several threads at once may reach clients in different orders.
+    pub fn send(&self, event: Event) {
+        let sinks = {
+            let mut state = self.state.lock().unwrap();
+            if self.history > 0 {
+                if state.recent.len() == self.history {
+                    state.recent.pop_front();
+                }
+                state.recent.push_back(event.clone());
+            }
+            state.sinks.clone()
+        };
+
+        let text = event.to_string();
+        let failed = sinks
+            .iter()
+            .filter(|sink| sink.write(text.as_bytes()).is_err())
+            .count();
+        if failed > 0 {
+            let mut state = self.state.lock().unwrap();
+            state.sinks.retain(|sink| !sink.is_closed());
+        }
+    }
+
+    pub fn subscribers(&self) -> usize {
+        self.state.lock().unwrap().sinks.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_multiline_events() {
+        let event = Event::new("first\nsecond")
+            .id("7")
+            .name("update\n")
+            .retry(Duration::from_secs(3));
+
+        assert_eq!(
+            "id: 7\nevent: update\nretry: 3000\ndata: first\ndata: second\n\n",
+            event.to_string()
+        );
+    }
+}
@@ -0,0 +1,512 @@
+//! A small template language for HTML pages.
+//!
+//! ```text
+//! <h1>{{ title }}</h1>                      values are HTML-escaped...
+//! {{ intro | raw }}                         ...unless marked raw
+//! {% if user.admin %}...{% else %}...{% endif %}
+//! {% for post in posts %}<li>{{ loop.index }}. {{ post.title }}</li>{% endfor %}
+//! {% include "footer.html" %}
+//! {# a comment #}
+//! ```
+//!
+//! Values come from a `Json` context; dotted names look into objects. Missing values render as
+//! nothing and count as false. Templates are read from one directory, usually a site's document
+//! root, and kept parsed in memory until the file changes.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::error::{escape_html, ServerError};
+use crate::http::Response;
+use crate::json::{Json, ToJson};
+
+/// How deeply includes may nest, which also stops a template from including itself forever.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+#[derive(Debug)]
+pub enum TemplateError {
+    NotFound(String),
+    Syntax {
+        template: String,
+        line: usize,
+        message: String,
+    },
+    IncludeDepth(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TemplateError::NotFound(name) => write!(f, "template {} not found", name),
+            TemplateError::Syntax {
+                template,
+                line,
+                message,
+            } => write!(f, "{}:{}: {}", template, line, message),
+            TemplateError::IncludeDepth(name) => {
+                write!(f, "includes nested too deeply in {}", name)
+            }
+            TemplateError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+/// A template that cannot be rendered is a fault of the site, never of the request.
+impl From<TemplateError> for ServerError {
+    fn from(e: TemplateError) -> ServerError {
+        ServerError::Internal(e.to_string())
+    }
+}
+
+/// The templates in one directory.
+pub struct Templates {
+    root: PathBuf,
+    cache: Mutex<HashMap<String, Cached>>,
+}
+
+struct Cached {
+    modified: Option<SystemTime>,
+    template: Arc<Template>,
+}
+
+impl Templates {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Templates {
+        Templates {
+            root: root.into(),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn render(&self, name: &str, context: &Json) -> Result<String, TemplateError> {
+        let mut out = String::new();
+        let mut scope = Scope {
+            root: context,
+            locals: Vec::new(),
+        };
+        self.render_into(name, &mut scope, &mut out, 0)?;
+        Ok(out)
+    }
+
+    /// A 200 response with the rendered page, or the error as a bare response for the server to
+    /// fill in with its error page.
+    pub fn response(&self, name: &str, context: &Json) -> Response {
+        match self.render(name, context) {
+            Ok(page) => Response::new(200)
+                .with_header("Content-Type", "text/html; charset=utf-8")
+                .with_body(page),
+            Err(e) => ServerError::from(e).into(),
+        }
+    }
+
+    fn render_into(
+        &self,
+        name: &str,
+        scope: &mut Scope,
+        out: &mut String,
+        depth: usize,
+    ) -> Result<(), TemplateError> {
+        if depth > MAX_INCLUDE_DEPTH {
+            return Err(TemplateError::IncludeDepth(name.to_string()));
+        }
+        let template = self.load(name)?;
+        self.render_nodes(&template.nodes, scope, out, depth)
+    }
+
+    fn render_nodes(
+        &self,
+        nodes: &[Node],
+        scope: &mut Scope,
+        out: &mut String,
+        depth: usize,
+    ) -> Result<(), TemplateError> {
+        for node in nodes {
+            match node {
+                Node::Text(text) => out.push_str(text),
+                Node::Value { path, raw } => {
+                    let text = scope.lookup(path).map(display).unwrap_or_default();
+                    if *raw {
+                        out.push_str(&text);
+                    } else {
+                        out.push_str(&escape_html(&text));
+                    }
+                }
+                Node::If {
+                    path,
+                    negated,
+                    then,
+                    otherwise,
+                } => {
+                    let truthy = scope.lookup(path).is_some_and(is_truthy);
+                    let branch = if truthy != *negated { then } else { otherwise };
+                    self.render_nodes(branch, scope, out, depth)?;
+                }
+                Node::For {
+                    name,
+                    path,
+                    body,
+                    otherwise,
+                } => {
+                    let items = match scope.lookup(path) {
+                        Some(Json::Array(items)) if !items.is_empty() => items.clone(),
+                        _ => {
+                            self.render_nodes(otherwise, scope, out, depth)?;
+                            continue;
+                        }
+                    };
+                    let count = items.len();
+                    for (i, item) in items.into_iter().enumerate() {
+                        let position = Json::Object(vec![
+                            (String::from("index"), (i + 1).to_json()),
+                            (String::from("first"), Json::Bool(i == 0)),
+                            (String::from("last"), Json::Bool(i + 1 == count)),
+                        ]);
+                        scope.locals.push((name.clone(), item));
+                        scope.locals.push((String::from("loop"), position));
+                        let result = self.render_nodes(body, scope, out, depth);
+                        scope.locals.truncate(scope.locals.len() - 2);
+                        result?;
+                    }
+                }
+                Node::Include(name) => self.render_into(name, scope, out, depth + 1)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// The parsed template, from the cache unless the file changed since it was read.
+    fn load(&self, name: &str) -> Result<Arc<Template>, TemplateError> {
+        if name.split('/').any(|part| part == ".." || part.is_empty()) || name.contains('\\') {
+            return Err(TemplateError::NotFound(name.to_string()));
+        }
+        let path = self.root.join(name);
+        let modified = match fs::metadata(&path) {
+            Ok(metadata) => metadata.modified().ok(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(TemplateError::NotFound(name.to_string()))
+            }
+            Err(e) => return Err(TemplateError::Io(e)),
+        };
+
+        if let Some(cached) = self.cache.lock().unwrap().get(name) {
+            if cached.modified.is_some() && cached.modified == modified {
+                return Ok(Arc::clone(&cached.template));
+            }
+        }
+
+        let source = fs::read_to_string(&path).map_err(TemplateError::Io)?;
+        let template = Arc::new(Template::parse(name, &source)?);
+        self.cache.lock().unwrap().insert(
+            name.to_string(),
+            Cached {
+                modified,
+                template: Arc::clone(&template),
+            },
+        );
+        Ok(template)
+    }
+}
+
+struct Scope<'a> {
+    root: &'a Json,
+    /// Loop variables, innermost last.
+    locals: Vec<(String, Json)>,
+}
+
+impl Scope<'_> {
+    fn lookup(&self, path: &[String]) -> Option<&Json> {
+        let (first, rest) = path.split_first()?;
+        let mut value = match self.locals.iter().rev().find(|(name, _)| name == first) {
+            Some((_, value)) => value,
+            None => self.root.get(first)?,
+        };
+        for key in rest {
+            value = match value {
+                Json::Array(items) => items.get(key.parse::<usize>().ok()?)?,
+                _ => value.get(key)?,
+            };
+        }
+        Some(value)
+    }
+}
+
+fn display(value: &Json) -> String {
+    match value {
+        Json::Null => String::new(),
+        Json::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn is_truthy(value: &Json) -> bool {
+    match value {
+        Json::Null => false,
+        Json::Bool(b) => *b,
+        Json::Number(n) => n.as_f64() != 0.0,
+        Json::String(s) => !s.is_empty(),
+        Json::Array(items) => !items.is_empty(),
+        Json::Object(members) => !members.is_empty(),
+    }
+}
+
+struct Template {
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug)]
+enum Node {
+    Text(String),
+    Value {
+        path: Vec<String>,
+        raw: bool,
+    },
+    If {
+        path: Vec<String>,
+        negated: bool,
+        then: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+    For {
+        name: String,
+        path: Vec<String>,
+        body: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+    Include(String),
+}
+
+/// A block that is still open while parsing, and what the nodes collected so far belong to.
+enum Open {
+    If {
+        path: Vec<String>,
+        negated: bool,
+        then: Option<Vec<Node>>,
+    },
+    For {
+        name: String,
+        path: Vec<String>,
+        body: Option<Vec<Node>>,
+    },
+}
+
+impl Template {
+    fn parse(name: &str, source: &str) -> Result<Template, TemplateError> {
+        let error = |offset: usize, message: String| TemplateError::Syntax {
+            template: name.to_string(),
+            line: source[..offset].matches('\n').count() + 1,
+            message,
+        };
+
+        // Each open block keeps the nodes of its enclosing level.
+        let mut stack: Vec<(Open, Vec<Node>, usize)> = Vec::new();
+        let mut nodes = Vec::new();
+        let mut rest = source;
+
+        while !rest.is_empty() {
+            let offset = source.len() - rest.len();
+            let start = match ["{{", "{%", "{#"]
+                .iter()
+                .filter_map(|open| rest.find(open))
+                .min()
+            {
+                Some(start) => start,
+                None => {
+                    nodes.push(Node::Text(rest.to_string()));
+                    break;
+                }
+            };
+            if start > 0 {
+                nodes.push(Node::Text(rest[..start].to_string()));
+            }
+
+            let tag = &rest[start..];
+            let close = match &tag[..2] {
+                "{{" => "}}",
+                "{%" => "%}",
+                _ => "#}",
+            };
+            let end = tag
+                .find(close)
+                .ok_or_else(|| error(offset + start, format!("unclosed `{}`", &tag[..2])))?;
+            let inner = tag[2..end].trim();
+            rest = &tag[end + 2..];
+            let at = offset + start;
+
+            match &tag[..2] {
+                "{{" => {
+                    let (expr, raw) = match inner.split_once('|') {
+                        Some((expr, filter)) if filter.trim() == "raw" => (expr.trim(), true),
+                        Some((_, filter)) => {
+                            return Err(error(at, format!("unknown filter `{}`", filter.trim())))
+                        }
+                        None => (inner, false),
+                    };
+                    let path = parse_path(expr).ok_or_else(|| error(at, bad_name(expr)))?;
+                    nodes.push(Node::Value { path, raw });
+                }
+                "{#" => {}
+                _ => {
+                    let words: Vec<&str> = inner.split_whitespace().collect();
+                    match words.as_slice() {
+                        ["if", "not", expr] | ["if", expr] => {
+                            let path = parse_path(expr).ok_or_else(|| error(at, bad_name(expr)))?;
+                            let open = Open::If {
+                                path,
+                                negated: words.len() == 3,
+                                then: None,
+                            };
+                            stack.push((open, std::mem::take(&mut nodes), at));
+                        }
+                        ["for", name, "in", expr] => {
+                            let path = parse_path(expr).ok_or_else(|| error(at, bad_name(expr)))?;
+                            if parse_path(name).is_none_or(|p| p.len() != 1) {
+                                return Err(error(at, bad_name(name)));
+                            }
+                            let open = Open::For {
+                                name: name.to_string(),
+                                path,
+                                body: None,
+                            };
+                            stack.push((open, std::mem::take(&mut nodes), at));
+                        }
+                        ["else"] => match stack.last_mut() {
+                            Some((Open::If { then, .. }, _, _)) if then.is_none() => {
+                                *then = Some(std::mem::take(&mut nodes));
+                            }
+                            Some((Open::For { body, .. }, _, _)) if body.is_none() => {
+                                *body = Some(std::mem::take(&mut nodes));
+                            }
+                            _ => return Err(error(at, String::from("unexpected `else`"))),
+                        },
+                        ["endif"] => match stack.pop() {
+                            Some((
+                                Open::If {
+                                    path,
+                                    negated,
+                                    then,
+                                },
+                                outer,
+                                _,
+                            )) => {
+                                let block = std::mem::replace(&mut nodes, outer);
+                                let (then, otherwise) = match then {
+                                    Some(then) => (then, block),
+                                    None => (block, Vec::new()),
+                                };
+                                nodes.push(Node::If {
+                                    path,
+                                    negated,
+                                    then,
+                                    otherwise,
+                                });
+                            }
+                            _ => return Err(error(at, String::from("unexpected `endif`"))),
+                        },
+                        ["endfor"] => match stack.pop() {
+                            Some((Open::For { name, path, body }, outer, _)) => {
+                                let block = std::mem::replace(&mut nodes, outer);
+                                let (body, otherwise) = match body {
+                                    Some(body) => (body, block),
+                                    None => (block, Vec::new()),
+                                };
+                                nodes.push(Node::For {
+                                    name,
+                                    path,
+                                    body,
+                                    otherwise,
+                                });
+                            }
+                            _ => return Err(error(at, String::from("unexpected `endfor`"))),
+                        },
+                        ["include", file] => {
+                            let file = file
+                                .strip_prefix('"')
+                                .and_then(|f| f.strip_suffix('"'))
+                                .ok_or_else(|| {
+                                    error(at, String::from("include needs a quoted name"))
+                                })?;
+                            nodes.push(Node::Include(file.to_string()));
+                        }
+                        _ => return Err(error(at, format!("unknown tag `{}`", inner))),
+                    }
+                }
+            }
+        }
+
+        match stack.pop() {
+            Some((_, _, at)) => Err(error(at, String::from("block is never closed"))),
+            None => Ok(Template { nodes }),
+        }
+    }
+}
+
+fn parse_path(expr: &str) -> Option<Vec<String>> {
+    let path: Vec<String> = expr.trim().split('.').map(str::to_string).collect();
+    let valid = path.iter().all(|part| {
+        !part.is_empty()
+            && part
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+    });
+    Some(path).filter(|_| valid)
+}
+
+fn bad_name(expr: &str) -> String {
+    format!("`{}` is not a valid name", expr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(files: &[(&str, &str)], context: &str) -> Result<String, TemplateError> {
+        let root =
+            std::env::temp_dir().join(format!("templates-{}-{}", std::process::id(), files[0].0));
+        fs::create_dir_all(&root).unwrap();
+        for (name, source) in files {
+            fs::write(root.join(name), source).unwrap();
+        }
+        let result = Templates::new(&root).render(files[0].0, &Json::parse(context).unwrap());
+        fs::remove_dir_all(&root).unwrap();
+        result
+    }
+
+    #[test]
+    fn renders_values_conditions_loops_and_includes() {
+        let page = render(
+            &[
+                (
+                    "page.html",
+                    "<h1>{{ title }}</h1>{# note #}\
+                     {% if user.admin %}admin{% else %}guest{% endif %}\
+                     <ul>{% for post in posts %}<li>{{ loop.index }}:{{ post }}</li>\
+                     {% else %}none{% endfor %}</ul>{{ html | raw }}{% include \"foot.html\" %}",
+                ),
+                ("foot.html", "<p>{{ title }}</p>"),
+            ],
+            r#"{"title": "<Rust>", "user": {"admin": false}, "posts": ["a", "b"], "html": "<br>"}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            "<h1>&lt;Rust&gt;</h1>guest<ul><li>1:a</li><li>2:b</li></ul><br><p>&lt;Rust&gt;</p>",
+            page
+        );
+    }
+
+    #[test]
+    fn reports_syntax_errors_with_line() {
+        let err = render(&[("bad.html", "line\n{% if x %}\nno end")], "{}").unwrap_err();
+        assert_eq!("bad.html:2: block is never closed", err.to_string());
+
+        let err = render(&[("loop.html", "{% include \"loop.html\" %}")], "{}").unwrap_err();
+        assert!(matches!(err, TemplateError::IncludeDepth(_)));
+    }
+}
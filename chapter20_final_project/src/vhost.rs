@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::http::Request;
+use crate::router::Router;
+
+/// Picks the site that answers a request by its `Host` header.
+///
+/// Each site is a `Router` with its own routes, document root and error pages. Names match
+/// case-insensitively and without the port; a name starting with `*.` matches any subdomain.
+/// Requests for unknown hosts, and HTTP/1.0 requests without a `Host` header, go to the
+/// default site.
+#[derive(Clone)]
+pub struct VirtualHosts {
+    hosts: HashMap<String, Arc<Router>>,
+    default: Arc<Router>,
+}
+
+impl VirtualHosts {
+    pub fn new(default: Router) -> VirtualHosts {
+        VirtualHosts {
+            hosts: HashMap::new(),
+            default: Arc::new(default),
+        }
+    }
+
+    /// Serve `name` with `site`. Give several names to serve the same site under all of them.
+    pub fn host(mut self, names: &[&str], site: Router) -> VirtualHosts {
+        let site = Arc::new(site);
+        for name in names {
+            let name = name.trim_end_matches('.').to_ascii_lowercase();
+            self.hosts.insert(name, Arc::clone(&site));
+        }
+        self
+    }
+
+    pub fn default_site(&self) -> &Router {
+        &self.default
+    }
+
+    /// The site for `request`.
+    pub fn select(&self, request: &Request) -> &Router {
+        let host = match request.host() {
+            Some(host) => host.to_ascii_lowercase(),
+            None => return &self.default,
+        };
+        if let Some(site) = self.hosts.get(&host) {
+            return site;
+        }
+
+        // Try `*.example.com`, then `*.com`, so the most specific wildcard wins.
+        let mut rest = host.as_str();
+        while let Some((_, parent)) = rest.split_once('.') {
+            if let Some(site) = self.hosts.get(&format!("*.{}", parent)) {
+                return site;
+            }
+            rest = parent;
+        }
+        &self.default
+    }
+}
+
+impl Default for VirtualHosts {
+    fn default() -> VirtualHosts {
+        VirtualHosts::new(Router::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Response;
+
+    fn site(status: u16) -> Router {
+        let mut router = Router::new();
+        router.get("/", move |_| Response::new(status));
+        router
+    }
+
+    #[test]
+    fn selects_site_by_host() {
+        let hosts = VirtualHosts::new(site(200))
+            .host(&["docs.internal", "docs"], site(201))
+            .host(&["*.wiki.internal"], site(202));
+
+        let status = |host: Option<&str>| {
+            let mut request = Request::new("GET", "/");
+            if let Some(host) = host {
+                request = request.with_header("Host", host);
+            }
+            let handler = hosts.select(&request).handler(&request).unwrap();
+            handler(&request).status
+        };
+
+        assert_eq!(201, status(Some("DOCS.internal:8080")));
+        assert_eq!(201, status(Some("docs")));
+        assert_eq!(202, status(Some("team.wiki.internal")));
+        assert_eq!(200, status(Some("wiki.internal")));
+        assert_eq!(200, status(Some("elsewhere")));
+        assert_eq!(200, status(None));
+    }
+}
@@ -0,0 +1,407 @@
+use std::io;
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+use crate::base64;
+use crate::digest;
+use crate::http::{Request, Response};
+
+/// Fixed GUID that RFC 6455 mixes into the handshake key.
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest message, after reassembling fragments, that `recv` accepts.
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Status codes for the close frame, see RFC 6455 section 7.4.1.
+pub mod close_code {
+    pub const NORMAL: u16 = 1000;
+    pub const GOING_AWAY: u16 = 1001;
+    pub const PROTOCOL_ERROR: u16 = 1002;
+    pub const UNSUPPORTED_DATA: u16 = 1003;
+    pub const INVALID_PAYLOAD: u16 = 1007;
+    pub const POLICY_VIOLATION: u16 = 1008;
+    pub const MESSAGE_TOO_BIG: u16 = 1009;
+    pub const INTERNAL_ERROR: u16 = 1011;
+}
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+#[derive(Debug, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Checks whether `request` asks to be upgraded to a WebSocket.
+pub fn is_upgrade(request: &Request) -> bool {
+    request
+        .header("Upgrade")
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+}
+
+/// Validate the opening handshake and build the `101 Switching Protocols` answer.
+///
+/// On failure the returned response is the error to send instead.
+pub fn handshake(request: &Request) -> Result<Response, Response> {
+    let connection_upgrade = request.header("Connection").is_some_and(|v| {
+        v.split(',')
+            .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+    });
+    if request.method != "GET" || !is_upgrade(request) || !connection_upgrade {
+        return Err(Response::new(400));
+    }
+    if request.header("Sec-WebSocket-Version") != Some("13") {
+        return Err(Response::new(426).with_header("Sec-WebSocket-Version", "13"));
+    }
+    let key = match request.header("Sec-WebSocket-Key") {
+        Some(key) if base64::decode(key).is_some_and(|k| k.len() == 16) => key,
+        _ => return Err(Response::new(400)),
+    };
+
+    Ok(Response::new(101)
+        .with_header("Upgrade", "websocket")
+        .with_header("Connection", "Upgrade")
+        .with_header("Sec-WebSocket-Accept", &accept_key(key)))
+}
+
+fn accept_key(key: &str) -> String {
+    base64::encode(&digest::sha1(
+        format!("{}{}", key, HANDSHAKE_GUID).as_bytes(),
+    ))
+}
+
+/// The server side of an established WebSocket connection.
+///
+/// `recv` answers pings and close frames on its own, so handlers only see data messages. It
+/// also pings a peer that has been silent for the idle timeout, and gives up on the connection
+/// if that ping goes unanswered for as long again.
+pub struct WebSocket {
+    stream: TcpStream,
+    /// Frames the client sent right behind the handshake, read ahead by the HTTP parser.
+    pending: io::Cursor<Vec<u8>>,
+    closed: bool,
+    idle_timeout: Duration,
+    read_timeout: Option<Duration>,
+    last_heard: Instant,
+    ping_sent: bool,
+}
+
+impl WebSocket {
+    pub(crate) fn new(
+        stream: TcpStream,
+        pending: Vec<u8>,
+        idle_timeout: Duration,
+    ) -> io::Result<WebSocket> {
+        Ok(WebSocket {
+            stream,
+            pending: io::Cursor::new(pending),
+            closed: false,
+            idle_timeout,
+            read_timeout: None,
+            last_heard: Instant::now(),
+            ping_sent: false,
+        })
+    }
+
+    /// Make `recv` fail with `TimedOut` if no message arrives within `timeout`. The default,
+    /// `None`, waits for as long as the peer keeps answering pings.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.read_timeout = timeout;
+        Ok(())
+    }
+
+    /// Wait for the next complete message.
+    ///
+    /// Returns `Ok(None)` once the peer has closed the connection. Protocol violations are
+    /// answered with the matching close code before the error is returned.
+    pub fn recv(&mut self) -> io::Result<Option<Message>> {
+        let mut message: Option<(u8, Vec<u8>)> = None;
+        let started = Instant::now();
+
+        loop {
+            if self.closed {
+                return Ok(None);
+            }
+            self.wait_for_frame(started)?;
+            let frame = match read_frame(&mut (&mut self.pending).chain(&mut self.stream), true) {
+                Ok(frame) => frame,
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    let _ = self.close(close_code::PROTOCOL_ERROR, "");
+                    return Err(e);
+                }
+                Err(e) => return Err(e),
+            };
+            self.last_heard = Instant::now();
+            self.ping_sent = false;
+
+            match frame.opcode {
+                OP_PING => write_frame(&mut self.stream, OP_PONG, &frame.payload)?,
+                OP_PONG => {}
+                OP_CLOSE => {
+                    // Echo the status code back, as the RFC asks, and stop.
+                    let code = match frame.payload.as_slice() {
+                        [] => close_code::NORMAL,
+                        [_] => {
+                            return self.fail(close_code::PROTOCOL_ERROR, "truncated close code")
+                        }
+                        [high, low, reason @ ..] => {
+                            if std::str::from_utf8(reason).is_err() {
+                                return self
+                                    .fail(close_code::INVALID_PAYLOAD, "reason is not UTF-8");
+                            }
+                            u16::from_be_bytes([*high, *low])
+                        }
+                    };
+                    if !may_be_sent(code) {
+                        return self.fail(close_code::PROTOCOL_ERROR, "invalid close code");
+                    }
+                    self.close(code, "")?;
+                    return Ok(None);
+                }
+                OP_TEXT | OP_BINARY if message.is_some() => {
+                    return self.fail(close_code::PROTOCOL_ERROR, "expected continuation frame");
+                }
+                OP_TEXT | OP_BINARY => message = Some((frame.opcode, frame.payload)),
+                OP_CONTINUATION => match message.as_mut() {
+                    Some((_, buf)) => buf.extend_from_slice(&frame.payload),
+                    None => {
+                        return self.fail(close_code::PROTOCOL_ERROR, "unexpected continuation")
+                    }
+                },
+                _ => return self.fail(close_code::PROTOCOL_ERROR, "unknown opcode"),
+            }
+
+            if message
+                .as_ref()
+                .is_some_and(|(_, buf)| buf.len() > MAX_MESSAGE_SIZE)
+            {
+                return self.fail(close_code::MESSAGE_TOO_BIG, "message too big");
+            }
+
+            if frame.fin && frame.opcode <= OP_BINARY {
+                if let Some((opcode, payload)) = message.take() {
+                    if opcode == OP_BINARY {
+                        return Ok(Some(Message::Binary(payload)));
+                    }
+                    return match String::from_utf8(payload) {
+                        Ok(text) => Ok(Some(Message::Text(text))),
+                        Err(_) => self.fail(close_code::INVALID_PAYLOAD, "text is not UTF-8"),
+                    };
+                }
+            }
+        }
+    }
+
+    pub fn send(&mut self, message: &Message) -> io::Result<()> {
+        match message {
+            Message::Text(text) => write_frame(&mut self.stream, OP_TEXT, text.as_bytes()),
+            Message::Binary(data) => write_frame(&mut self.stream, OP_BINARY, data),
+        }
+    }
+
+    pub fn send_text(&mut self, text: &str) -> io::Result<()> {
+        write_frame(&mut self.stream, OP_TEXT, text.as_bytes())
+    }
+
+    pub fn ping(&mut self, payload: &[u8]) -> io::Result<()> {
+        write_frame(&mut self.stream, OP_PING, payload)
+    }
+
+    /// Send a close frame. Calling it again after the connection is closed does nothing.
+    pub fn close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        payload.truncate(125);
+        write_frame(&mut self.stream, OP_CLOSE, &payload)
+    }
+
+    /// Wait until a frame starts to arrive, pinging the peer once it has been silent for the
+    /// idle timeout. Only whole frames are ever waited for this way, so a timeout never leaves
+    /// one half read.
+    fn wait_for_frame(&mut self, started: Instant) -> io::Result<()> {
+        loop {
+            if self.pending.position() < self.pending.get_ref().len() as u64 {
+                return Ok(());
+            }
+            let silence = if self.ping_sent { 2 } else { 1 };
+            let idle_deadline = self.last_heard + self.idle_timeout * silence;
+            let deadline = match self.read_timeout {
+                Some(timeout) => idle_deadline.min(started + timeout),
+                None => idle_deadline,
+            };
+
+            let now = Instant::now();
+            if now >= idle_deadline {
+                if self.ping_sent {
+                    let _ = self.close(close_code::GOING_AWAY, "ping timeout");
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "peer stopped answering pings",
+                    ));
+                }
+                self.ping(&[])?;
+                self.ping_sent = true;
+                continue;
+            }
+            if now >= deadline {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "no message in time",
+                ));
+            }
+
+            self.stream.set_read_timeout(Some(deadline - now))?;
+            match self.stream.peek(&mut [0]) {
+                Ok(_) => {
+                    // The rest of the frame should follow promptly.
+                    self.stream.set_read_timeout(Some(self.idle_timeout))?;
+                    return Ok(());
+                }
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn fail<T>(&mut self, code: u16, why: &'static str) -> io::Result<T> {
+        self.close(code, why)?;
+        Err(io::Error::new(io::ErrorKind::InvalidData, why))
+    }
+}
+
+impl Drop for WebSocket {
+    fn drop(&mut self) {
+        // A handler that just returns still says goodbye properly.
+        let _ = self.close(close_code::NORMAL, "");
+    }
+}
+
+/// Whether `code` may appear in a close frame. 1005, 1006 and 1015 only describe closes that
+/// happened without one, and codes below 3000 are reserved for the RFC and its extensions.
+fn may_be_sent(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+/// Read one frame. Clients must mask their frames, servers must not, hence `expect_masked`.
+fn read_frame<R: Read>(r: &mut R, expect_masked: bool) -> io::Result<Frame> {
+    let protocol_error = |why| io::Error::new(io::ErrorKind::InvalidData, why);
+
+    let mut head = [0; 2];
+    r.read_exact(&mut head)?;
+    let fin = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0F;
+    let masked = head[1] & 0x80 != 0;
+
+    if head[0] & 0x70 != 0 {
+        return Err(protocol_error("reserved bits set"));
+    }
+    if masked != expect_masked {
+        return Err(protocol_error("wrong masking"));
+    }
+
+    let len = match head[1] & 0x7F {
+        126 => {
+            let mut len = [0; 2];
+            r.read_exact(&mut len)?;
+            u16::from_be_bytes(len) as u64
+        }
+        127 => {
+            let mut len = [0; 8];
+            r.read_exact(&mut len)?;
+            u64::from_be_bytes(len)
+        }
+        len => len as u64,
+    };
+
+    if opcode >= OP_CLOSE && (!fin || len > 125) {
+        return Err(protocol_error(
+            "control frames must be short and unfragmented",
+        ));
+    }
+    if len > MAX_MESSAGE_SIZE as u64 {
+        return Err(protocol_error("frame too big"));
+    }
+
+    let mut mask = [0; 4];
+    if masked {
+        r.read_exact(&mut mask)?;
+    }
+    let mut payload = vec![0; len as usize];
+    r.read_exact(&mut payload)?;
+    if masked {
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+    }
+
+    Ok(Frame {
+        fin,
+        opcode,
+        payload,
+    })
+}
+
+/// Write a single unmasked, final frame.
+fn write_frame<W: Write>(w: &mut W, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xFFFF => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+
+    w.write_all(&frame)?;
+    w.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_key_matches_rfc_example() {
+        assert_eq!(
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=",
+            accept_key("dGhlIHNhbXBsZSBub25jZQ==")
+        );
+    }
+
+    #[test]
+    fn reads_masked_frame() {
+        // "Hello" as sent by a client, taken from RFC 6455 section 5.7.
+        let bytes = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        let frame = read_frame(&mut &bytes[..], true).unwrap();
+
+        assert!(frame.fin);
+        assert_eq!(OP_TEXT, frame.opcode);
+        assert_eq!(b"Hello".to_vec(), frame.payload);
+    }
+}
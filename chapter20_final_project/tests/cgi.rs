@@ -0,0 +1,61 @@
+#![cfg(unix)]
+
+use chapter20_final_project::http::Request;
+use chapter20_final_project::Cgi;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+fn script(name: &str, source: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("chapter20-cgi-{}-{}", std::process::id(), name));
+    fs::write(&path, source).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    path
+}
+
+#[test]
+fn passes_request_through_environment_and_stdin() {
+    let program = script(
+        "echo",
+        "#!/bin/sh\n\
+         printf 'Content-Type: text/plain\\n\\n'\n\
+         echo \"$REQUEST_METHOD $SCRIPT_NAME $PATH_INFO $QUERY_STRING $HTTP_X_TOKEN $CONTENT_LENGTH\"\n\
+         cat\n",
+    );
+    let cgi = Cgi::new("/cgi-bin/echo", &program);
+
+    let request = Request::new("POST", "/cgi-bin/echo/extra/path?a=1&b=2")
+        .with_header("X-Token", "abc")
+        .with_body("hello");
+    let response = cgi.run(&request);
+
+    assert_eq!(200, response.status);
+    assert_eq!(Some("text/plain"), response.header("Content-Type"));
+    assert_eq!(
+        "POST /cgi-bin/echo /extra/path a=1&b=2 abc 5\nhello",
+        String::from_utf8(response.body).unwrap()
+    );
+    fs::remove_file(program).unwrap();
+}
+
+#[test]
+fn kills_programs_that_run_too_long() {
+    let program = script("sleep", "#!/bin/sh\nsleep 5\n");
+    let cgi = Cgi::new("/slow", &program).with_timeout(Duration::from_millis(100));
+
+    assert_eq!(504, cgi.run(&Request::new("GET", "/slow")).status);
+    fs::remove_file(program).unwrap();
+}
+
+#[test]
+fn refuses_oversized_output() {
+    let program = script(
+        "yes",
+        "#!/bin/sh\nprintf 'Content-Type: text/plain\\n\\n'\nexec yes\n",
+    );
+    let cgi = Cgi::new("/yes", &program).with_max_output_size(1000);
+
+    assert_eq!(502, cgi.run(&Request::new("GET", "/yes")).status);
+    fs::remove_file(program).unwrap();
+}
@@ -0,0 +1,101 @@
+use chapter20_final_project::{Client, Server, ServerConfig};
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+fn spawn_server() -> SocketAddr {
+    let server = Server::bind("127.0.0.1:0", 2, ServerConfig::default()).unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in server.listener().incoming() {
+            server.dispatch(stream.unwrap());
+        }
+    });
+    addr
+}
+
+#[test]
+fn fetches_pages_from_the_server() {
+    let addr = spawn_server();
+    let client = Client::new();
+
+    let response = client.get(&format!("http://{}/", addr)).unwrap();
+    assert_eq!(200, response.status);
+    assert!(String::from_utf8(response.body)
+        .unwrap()
+        .contains("Hi From Rust"));
+
+    let response = client.get(&format!("http://{}/missing", addr)).unwrap();
+    assert_eq!(404, response.status);
+}
+
+#[test]
+fn reuses_connection_and_decodes_chunked_bodies() {
+    // A stand-in backend that accepts a single connection and answers two requests on it.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buf = [0; 1024];
+        for _ in 0..2 {
+            assert!(stream.read(&mut buf).unwrap() > 0);
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                      5\r\nHello\r\n7;ext=1\r\n, world\r\n0\r\n\r\n",
+                )
+                .unwrap();
+        }
+    });
+
+    let client = Client::new();
+    for _ in 0..2 {
+        let response = client.get(&format!("http://{}/", addr)).unwrap();
+        assert_eq!(b"Hello, world".to_vec(), response.body);
+    }
+}
+
+#[test]
+fn retries_on_a_fresh_connection_only_when_the_request_was_not_answered() {
+    // A backend that closes its first connection after one response without saying so, and
+    // that answers the first request on its second connection but not the next one.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&connections);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+            thread::spawn(move || {
+                let mut buf = [0; 1024];
+                assert!(stream.read(&mut buf).unwrap() > 0);
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                    .unwrap();
+                if n == 2 {
+                    assert!(stream.read(&mut buf).unwrap() > 0);
+                    thread::sleep(Duration::from_secs(2));
+                }
+            });
+        }
+    });
+
+    let client = Client::new().with_timeout(Duration::from_millis(300));
+    let url = format!("http://{}/", addr);
+    assert_eq!(200, client.post(&url, "text/plain", b"1").unwrap().status);
+    thread::sleep(Duration::from_millis(50));
+    // The pooled connection was closed before any response, so this is sent again on a
+    // second connection.
+    assert_eq!(200, client.post(&url, "text/plain", b"2").unwrap().status);
+    assert_eq!(2, connections.load(Ordering::SeqCst));
+
+    // The backend may have acted on a request it did not answer in time, so it must not be
+    // sent a second time.
+    assert!(client.post(&url, "text/plain", b"3").is_err());
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(2, connections.load(Ordering::SeqCst));
+}
@@ -0,0 +1,148 @@
+#![cfg(target_os = "linux")]
+
+use chapter20_final_project::http::{Connection, Response};
+use chapter20_final_project::{PollingServer, Router, ServerConfig};
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn serve(config: ServerConfig) -> SocketAddr {
+    let mut router = Router::new();
+    router.get("/hello", |_| Response::new(200).with_body("hello"));
+    router.get("/slow", |_| {
+        thread::sleep(Duration::from_millis(500));
+        Response::new(200).with_body("finally")
+    });
+    router.websocket("/echo", |mut ws| {
+        while let Ok(Some(message)) = ws.recv() {
+            ws.send(&message).unwrap();
+        }
+    });
+
+    let server = PollingServer::bind("127.0.0.1:0", 1, 2, config)
+        .unwrap()
+        .with_router(router);
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run().unwrap());
+    addr
+}
+
+fn connect(addr: SocketAddr) -> Connection {
+    let stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    Connection::new(stream)
+}
+
+#[test]
+fn keeps_connections_alive_and_answers_pipelined_requests() {
+    let mut conn = connect(serve(ServerConfig::default()));
+
+    conn.stream()
+        .write_all(
+            b"GET /hello HTTP/1.1\r\nHost: a\r\n\r\n\
+              HEAD / HTTP/1.1\r\nHost: a\r\n\r\n",
+        )
+        .unwrap();
+    let first = conn
+        .read_response("GET", Duration::from_secs(5), usize::MAX)
+        .unwrap();
+    let second = conn
+        .read_response("HEAD", Duration::from_secs(5), usize::MAX)
+        .unwrap();
+    assert_eq!(b"hello".to_vec(), first.body);
+    assert_eq!(None, first.header("Connection"));
+    let page = std::fs::read("hello.html").unwrap();
+    assert_eq!(
+        Some(page.len().to_string().as_str()),
+        second.header("Content-Length")
+    );
+    assert!(second.body.is_empty());
+
+    // The connection is still good after both.
+    conn.stream()
+        .write_all(b"GET /hello HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let third = conn
+        .read_response("GET", Duration::from_secs(5), usize::MAX)
+        .unwrap();
+    assert_eq!(b"hello".to_vec(), third.body);
+    assert_eq!(Some("close"), third.header("Connection"));
+}
+
+#[test]
+fn idle_connections_do_not_slow_down_others() {
+    let config = ServerConfig {
+        max_connections_per_ip: 1000,
+        ..ServerConfig::default()
+    };
+    let addr = serve(config);
+    let _idle: Vec<TcpStream> = (0..500)
+        .map(|_| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(b"GET /hello HTTP/1.1\r\n").unwrap();
+            stream
+        })
+        .collect();
+
+    let started = Instant::now();
+    let mut conn = connect(addr);
+    conn.stream()
+        .write_all(b"GET /hello HTTP/1.1\r\nHost: a\r\n\r\n")
+        .unwrap();
+    let response = conn
+        .read_response("GET", Duration::from_secs(5), usize::MAX)
+        .unwrap();
+    assert_eq!(200, response.status);
+    assert!(started.elapsed() < Duration::from_secs(1));
+}
+
+#[test]
+fn answers_handlers_that_take_too_long_with_503() {
+    let config = ServerConfig {
+        handler_timeout: Duration::from_millis(100),
+        ..ServerConfig::default()
+    };
+    let mut conn = connect(serve(config));
+
+    conn.stream()
+        .write_all(b"GET /slow HTTP/1.1\r\nHost: a\r\n\r\n")
+        .unwrap();
+    let response = conn
+        .read_response("GET", Duration::from_secs(5), usize::MAX)
+        .unwrap();
+    assert_eq!(503, response.status);
+    assert_eq!(Some("close"), response.header("Connection"));
+}
+
+#[test]
+fn hands_websockets_to_a_worker() {
+    let mut conn = connect(serve(ServerConfig::default()));
+    conn.stream()
+        .write_all(
+            b"GET /echo HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+              Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+              Sec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .unwrap();
+    let (mut stream, _) = conn.into_parts();
+
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0];
+        stream.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+    assert!(head.starts_with(b"HTTP/1.1 101"));
+
+    // A masked text frame saying "hi".
+    let mask = [1, 2, 3, 4];
+    stream
+        .write_all(&[0x81, 0x82, 1, 2, 3, 4, b'h' ^ mask[0], b'i' ^ mask[1]])
+        .unwrap();
+    let mut echo = [0; 4];
+    stream.read_exact(&mut echo).unwrap();
+    assert_eq!([0x81, 2, b'h', b'i'], echo);
+}
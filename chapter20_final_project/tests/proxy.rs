@@ -0,0 +1,146 @@
+use chapter20_final_project::http::{Request, Response};
+use chapter20_final_project::{Client, Proxy, Router, Server, ServerConfig};
+use std::net::{SocketAddr, TcpListener};
+use std::thread;
+use std::time::Duration;
+
+fn spawn(router: Router) -> SocketAddr {
+    let server = Server::bind("127.0.0.1:0", 2, ServerConfig::default())
+        .unwrap()
+        .with_router(router);
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in server.listener().incoming() {
+            server.dispatch(stream.unwrap());
+        }
+    });
+    addr
+}
+
+/// A backend that answers every request with its name, the path it saw and the
+/// `X-Forwarded-For` header.
+fn backend(name: &'static str) -> String {
+    let mut router = Router::new();
+    router.prefix("/", move |req| {
+        Response::new(200).with_body(format!(
+            "{} {} {}",
+            name,
+            req.target,
+            req.header("X-Forwarded-For").unwrap_or("-")
+        ))
+    });
+    spawn(router).to_string()
+}
+
+fn proxy_to(proxy: Proxy) -> SocketAddr {
+    let mut router = Router::new();
+    router.prefix("/api", move |req| proxy.forward(req));
+    spawn(router)
+}
+
+fn body(response: Response) -> String {
+    String::from_utf8(response.body).unwrap()
+}
+
+#[test]
+fn balances_round_robin_and_rewrites_path() {
+    let (a, b) = (backend("a"), backend("b"));
+    let front = proxy_to(Proxy::new(&[&a, &b]).strip_prefix("/api"));
+
+    let client = Client::new();
+    let bodies: Vec<String> = (0..4)
+        .map(|_| {
+            body(
+                client
+                    .get(&format!("http://{}/api/users?x=1", front))
+                    .unwrap(),
+            )
+        })
+        .collect();
+
+    assert_eq!(
+        vec![
+            "a /users?x=1 127.0.0.1",
+            "b /users?x=1 127.0.0.1",
+            "a /users?x=1 127.0.0.1",
+            "b /users?x=1 127.0.0.1",
+        ],
+        bodies
+    );
+}
+
+#[test]
+fn skips_dead_backends() {
+    // Reserve a port and release it again so nothing is listening there.
+    let dead = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let alive = backend("alive");
+    let front =
+        proxy_to(Proxy::new(&[&dead, &alive]).with_health_check(1, Duration::from_secs(60)));
+
+    let client = Client::new();
+    for _ in 0..3 {
+        let response = client.get(&format!("http://{}/api", front)).unwrap();
+        assert!(body(response).starts_with("alive"));
+    }
+
+    let front = proxy_to(Proxy::new(&[&dead]));
+    assert_eq!(
+        502,
+        client.get(&format!("http://{}/api", front)).unwrap().status
+    );
+}
+
+#[test]
+fn times_out_slow_backends() {
+    // Accepts connections but never answers.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let slow = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        let _held: Vec<_> = listener.incoming().collect();
+    });
+
+    let front = proxy_to(Proxy::new(&[&slow]).with_timeout(Duration::from_millis(100)));
+    let response = Client::new().get(&format!("http://{}/api", front)).unwrap();
+    assert_eq!(504, response.status);
+}
+
+#[test]
+fn fails_over_when_connecting_fails_and_keeps_head_lengths() {
+    // The name does not resolve, which fails like any other connect error.
+    let alive = backend("alive");
+    let front = proxy_to(
+        Proxy::new(&["backend.invalid:80", &alive])
+            .with_timeout(Duration::from_millis(200))
+            .with_health_check(5, Duration::from_secs(60)),
+    );
+
+    let client = Client::new();
+    let get = client.get(&format!("http://{}/api", front)).unwrap();
+    assert_eq!("alive /api 127.0.0.1", body(get));
+
+    let head = client
+        .send(&front.to_string(), Request::new("HEAD", "/api"))
+        .unwrap();
+    assert_eq!(200, head.status);
+    assert_eq!(Some("20"), head.header("Content-Length"));
+    assert!(head.body.is_empty());
+}
+
+#[test]
+fn refuses_oversized_responses() {
+    let mut router = Router::new();
+    router.get("/api/big", |_| {
+        Response::new(200).with_body(vec![b'x'; 2048])
+    });
+    let big = spawn(router).to_string();
+    let front = proxy_to(Proxy::new(&[&big]).with_max_body_size(1024));
+
+    let response = Client::new()
+        .get(&format!("http://{}/api/big", front))
+        .unwrap();
+    assert_eq!(502, response.status);
+}
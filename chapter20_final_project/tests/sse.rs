@@ -0,0 +1,151 @@
+use chapter20_final_project::http::Response;
+use chapter20_final_project::sse::{Broadcaster, Event, EventSink};
+use chapter20_final_project::{Router, Server, ServerConfig};
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A server with a single worker, so a stream that held on to it would block everything else.
+fn event_server(broadcaster: Arc<Broadcaster>) -> SocketAddr {
+    let mut router = Router::new();
+    router.events("/events", move |_, sink| broadcaster.subscribe(sink));
+    serve(router, ServerConfig::default())
+}
+
+fn serve(mut router: Router, config: ServerConfig) -> SocketAddr {
+    router.get("/ping", |_| Response::new(200).with_body("pong"));
+
+    let server = Server::bind("127.0.0.1:0", 1, config)
+        .unwrap()
+        .with_router(router);
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in server.listener().incoming() {
+            server.dispatch(stream.unwrap());
+        }
+    });
+    addr
+}
+
+fn subscribe(addr: SocketAddr, last_event_id: Option<&str>) -> BufReader<TcpStream> {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut request = String::from("GET /events HTTP/1.1\r\nHost: localhost\r\n");
+    if let Some(id) = last_event_id {
+        request.push_str(&format!("Last-Event-ID: {}\r\n", id));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).unwrap();
+
+    let mut reader = BufReader::new(stream);
+    let head = read_block(&mut reader);
+    assert!(head.starts_with("HTTP/1.1 200 OK"));
+    assert!(head.contains("Content-Type: text/event-stream"));
+    reader
+}
+
+/// Read up to and including the next blank line.
+fn read_block(reader: &mut BufReader<TcpStream>) -> String {
+    let mut block = String::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let end = line.trim_end().is_empty();
+        block.push_str(&line);
+        if end {
+            return block;
+        }
+    }
+}
+
+#[test]
+fn streams_events_without_holding_a_worker() {
+    let broadcaster = Arc::new(Broadcaster::new(8));
+    let addr = event_server(Arc::clone(&broadcaster));
+
+    let mut first = subscribe(addr, None);
+
+    // The only worker must be free for ordinary requests.
+    let mut ping = TcpStream::connect(addr).unwrap();
+    ping.write_all(b"GET /ping HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    ping.read_to_string(&mut response).unwrap();
+    assert!(response.ends_with("pong"));
+
+    while broadcaster.subscribers() < 1 {
+        thread::sleep(Duration::from_millis(10));
+    }
+    broadcaster.send(Event::new("one").id("1").name("tick"));
+    broadcaster.send(Event::new("two").id("2").name("tick"));
+    assert_eq!("id: 1\nevent: tick\ndata: one\n\n", read_block(&mut first));
+    assert_eq!("id: 2\nevent: tick\ndata: two\n\n", read_block(&mut first));
+
+    // A client reconnecting after event 1 gets event 2 replayed.
+    let mut second = subscribe(addr, Some("1"));
+    assert_eq!("id: 2\nevent: tick\ndata: two\n\n", read_block(&mut second));
+}
+
+fn ping(addr: SocketAddr) -> String {
+    let mut ping = TcpStream::connect(addr).unwrap();
+    ping.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let _ = ping.write_all(b"GET /ping HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    let mut response = String::new();
+    let _ = ping.read_to_string(&mut response);
+    response
+}
+
+#[test]
+fn drops_clients_that_stop_reading() {
+    let broadcaster = Arc::new(Broadcaster::new(0));
+    let addr = event_server(Arc::clone(&broadcaster));
+    let (_stalled, mut reading) = (subscribe(addr, None), subscribe(addr, None));
+    while broadcaster.subscribers() < 2 {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    // Far more than the socket buffers hold; only one client reads it.
+    let reader = thread::spawn(move || {
+        for _ in 0..400 {
+            read_block(&mut reading);
+        }
+    });
+    let started = Instant::now();
+    for _ in 0..400 {
+        broadcaster.send(Event::new(&"x".repeat(50_000)));
+        thread::sleep(Duration::from_millis(1));
+    }
+    reader.join().unwrap();
+
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert_eq!(1, broadcaster.subscribers());
+}
+
+#[test]
+fn streams_keep_their_connection_slot() {
+    let held: Arc<Mutex<Option<EventSink>>> = Arc::new(Mutex::new(None));
+    let mut router = Router::new();
+    let keep = Arc::clone(&held);
+    router.events("/events", move |_, sink| *keep.lock().unwrap() = Some(sink));
+    let addr = serve(
+        router,
+        ServerConfig {
+            max_connections_per_ip: 1,
+            ..ServerConfig::default()
+        },
+    );
+
+    let _stream = subscribe(addr, None);
+    while held.lock().unwrap().is_none() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!("", ping(addr));
+
+    held.lock().unwrap().take();
+    assert!(ping(addr).ends_with("pong"));
+}
@@ -0,0 +1,162 @@
+use chapter20_final_project::http::Response;
+use chapter20_final_project::{BasicAuth, Router, Server, ServerConfig};
+use std::fs;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+/// A server whose ordinary request bodies are limited to 1 KiB, with an upload route that
+/// reports what it received.
+fn upload_server() -> SocketAddr {
+    upload_server_with(ServerConfig {
+        max_body_size: 1024,
+        ..ServerConfig::default()
+    })
+}
+
+fn upload_server_with(config: ServerConfig) -> SocketAddr {
+    let mut router = Router::new();
+    router.upload("/photos", |_, form| {
+        let file = &form.files[0];
+        let contents = fs::read(file.path()).unwrap();
+        let intact = contents.iter().enumerate().all(|(i, &b)| b == i as u8);
+        Response::new(200).with_body(format!(
+            "{} {} {} {}",
+            form.fields.get("title").unwrap_or(""),
+            file.filename,
+            file.size,
+            intact
+        ))
+    });
+    router.upload("/private/photos", |_, _| Response::new(200));
+    router.get("/ping", |_| Response::new(200).with_body("pong"));
+    BasicAuth::new("photos")
+        .protect("/private")
+        .install(&mut router);
+
+    let server = Server::bind("127.0.0.1:0", 2, config)
+        .unwrap()
+        .with_router(router);
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in server.listener().incoming() {
+            server.dispatch(stream.unwrap());
+        }
+    });
+    addr
+}
+
+/// Read one response, returning its head and body.
+fn read_response(reader: &mut BufReader<TcpStream>) -> (String, String) {
+    let mut head = String::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        head.push_str(&line);
+        if line == "\r\n" {
+            break;
+        }
+    }
+    let length = head
+        .lines()
+        .find_map(|line| line.strip_prefix("Content-Length: "))
+        .map_or(0, |len| len.parse().unwrap());
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).unwrap();
+    (head, String::from_utf8(body).unwrap())
+}
+
+#[test]
+fn streams_uploads_larger_than_the_body_limit() {
+    let file: Vec<u8> = (0..4 * 1024 * 1024).map(|i| i as u8).collect();
+    let mut body = Vec::new();
+    body.extend_from_slice(
+        b"--B\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nBeach\r\n\
+          --B\r\nContent-Disposition: form-data; name=\"photo\"; filename=\"beach.raw\"\r\n\r\n",
+    );
+    body.extend_from_slice(&file);
+    body.extend_from_slice(b"\r\n--B--\r\nepilogue");
+
+    let stream = TcpStream::connect(upload_server()).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    writer
+        .write_all(
+            format!(
+                "POST /photos HTTP/1.1\r\nHost: localhost\r\n\
+                 Content-Type: multipart/form-data; boundary=B\r\nContent-Length: {}\r\n\r\n",
+                body.len()
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+    writer.write_all(&body).unwrap();
+
+    let (head, reply) = read_response(&mut reader);
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+    assert_eq!(format!("Beach beach.raw {} true", file.len()), reply);
+
+    // The whole body was consumed, epilogue included, so the connection is still usable.
+    writer
+        .write_all(b"GET /ping HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let (head, reply) = read_response(&mut reader);
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+    assert_eq!("pong", reply);
+}
+
+#[test]
+fn filters_answer_before_the_upload_is_sent() {
+    let stream = TcpStream::connect(upload_server()).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    // Announce a large body but send none of it: the 401 must not wait for it.
+    writer
+        .write_all(
+            b"POST /private/photos HTTP/1.1\r\nHost: localhost\r\n\
+              Content-Type: multipart/form-data; boundary=B\r\nContent-Length: 100000000\r\n\r\n",
+        )
+        .unwrap();
+
+    let (head, _) = read_response(&mut reader);
+    assert!(head.starts_with("HTTP/1.1 401"), "{}", head);
+    assert!(head.contains("Connection: close"));
+}
+
+#[test]
+fn times_out_uploads_that_trickle_in() {
+    let addr = upload_server_with(ServerConfig {
+        body_read_timeout: Duration::from_millis(200),
+        min_upload_rate: 1000,
+        ..ServerConfig::default()
+    });
+    let stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    writer
+        .write_all(
+            b"POST /photos HTTP/1.1\r\nHost: localhost\r\n\
+              Content-Type: multipart/form-data; boundary=B\r\nContent-Length: 100000\r\n\r\n",
+        )
+        .unwrap();
+    // One byte every 50ms never trips the timeout of a single read, but is far too slow.
+    thread::spawn(move || {
+        while writer.write_all(b"x").is_ok() {
+            thread::sleep(Duration::from_millis(50));
+        }
+    });
+
+    let (head, _) = read_response(&mut reader);
+    assert!(head.starts_with("HTTP/1.1 408"), "{}", head);
+}
@@ -0,0 +1,215 @@
+use chapter20_final_project::http::Response;
+use chapter20_final_project::{Router, Server, ServerConfig};
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+fn echo_server() -> std::net::SocketAddr {
+    echo_server_with(2, ServerConfig::default())
+}
+
+fn echo_server_with(workers: usize, config: ServerConfig) -> std::net::SocketAddr {
+    let mut router = Router::new();
+    router.websocket("/echo", |mut ws| {
+        while let Ok(Some(message)) = ws.recv() {
+            ws.send(&message).unwrap();
+        }
+    });
+    router.get("/", |_| Response::new(200).with_body("alive"));
+
+    let server = Server::bind("127.0.0.1:0", workers, config)
+        .unwrap()
+        .with_router(router);
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in server.listener().incoming() {
+            server.dispatch(stream.unwrap());
+        }
+    });
+    addr
+}
+
+/// Open `/echo` and read up to the end of the response head, which is returned.
+fn upgrade(addr: std::net::SocketAddr) -> (TcpStream, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream
+        .write_all(
+            b"GET /echo HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+              Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+              Sec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .unwrap();
+
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0];
+        stream.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+    (stream, String::from_utf8(head).unwrap())
+}
+
+fn masked_frame(opcode: u8, fin: bool, payload: &[u8]) -> Vec<u8> {
+    let mask = [1, 2, 3, 4];
+    let mut frame = vec![
+        (if fin { 0x80 } else { 0 }) | opcode,
+        0x80 | payload.len() as u8,
+    ];
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    frame
+}
+
+#[test]
+fn echoes_fragmented_text_and_answers_ping_and_close() {
+    let mut stream = TcpStream::connect(echo_server()).unwrap();
+    stream
+        .write_all(
+            b"GET /echo HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+              Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+              Sec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .unwrap();
+
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0];
+        stream.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8(head).unwrap();
+    assert!(head.starts_with("HTTP/1.1 101"));
+    assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+
+    stream.write_all(&masked_frame(0x1, false, b"Hel")).unwrap();
+    stream.write_all(&masked_frame(0x9, true, b"hi")).unwrap();
+    stream.write_all(&masked_frame(0x0, true, b"lo")).unwrap();
+
+    let mut pong = [0; 4];
+    stream.read_exact(&mut pong).unwrap();
+    assert_eq!([0x8A, 2, b'h', b'i'], pong);
+
+    let mut echo = [0; 7];
+    stream.read_exact(&mut echo).unwrap();
+    assert_eq!([0x81, 5, b'H', b'e', b'l', b'l', b'o'], echo);
+
+    stream
+        .write_all(&masked_frame(0x8, true, &1000u16.to_be_bytes()))
+        .unwrap();
+    let mut close = [0; 4];
+    stream.read_exact(&mut close).unwrap();
+    assert_eq!([0x88, 2, 0x03, 0xE8], close);
+}
+
+#[test]
+fn panicking_handler_leaves_the_worker_running() {
+    let mut router = Router::new();
+    router.websocket("/boom", |_| panic!("handler bug"));
+    router.get("/", |_| Response::new(200).with_body("alive"));
+
+    // A single worker, so it has to survive the panic to answer the second request.
+    let server = Server::bind("127.0.0.1:0", 1, ServerConfig::default())
+        .unwrap()
+        .with_router(router);
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in server.listener().incoming() {
+            server.dispatch(stream.unwrap());
+        }
+    });
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(
+            b"GET /boom HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+              Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+              Sec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .unwrap();
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).unwrap();
+    assert!(rest.starts_with(b"HTTP/1.1 101"));
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"));
+    assert!(response.ends_with("alive"));
+}
+
+#[test]
+fn rejects_truncated_and_reserved_close_codes() {
+    let addr = echo_server();
+    for payload in [&[0x03][..], &1005u16.to_be_bytes(), &1015u16.to_be_bytes()] {
+        let (mut stream, head) = upgrade(addr);
+        assert!(head.starts_with("HTTP/1.1 101"));
+
+        stream.write_all(&masked_frame(0x8, true, payload)).unwrap();
+        let mut head = [0; 2];
+        stream.read_exact(&mut head).unwrap();
+        assert_eq!(0x88, head[0]);
+        let mut body = vec![0; head[1] as usize];
+        stream.read_exact(&mut body).unwrap();
+        assert_eq!([0x03, 0xEA], body[..2], "payload {:?}", payload);
+    }
+}
+
+#[test]
+fn pings_idle_peers_and_gives_up_on_silent_ones() {
+    let config = ServerConfig {
+        websocket_idle_timeout: Duration::from_millis(100),
+        ..ServerConfig::default()
+    };
+    let (mut stream, _) = upgrade(echo_server_with(2, config));
+
+    let mut ping = [0; 2];
+    stream.read_exact(&mut ping).unwrap();
+    assert_eq!([0x89, 0], ping);
+
+    // Never answer it.
+    let mut head = [0; 2];
+    stream.read_exact(&mut head).unwrap();
+    assert_eq!(0x88, head[0]);
+    let mut body = vec![0; head[1] as usize];
+    stream.read_exact(&mut body).unwrap();
+    assert_eq!(1001u16.to_be_bytes(), body[..2]);
+}
+
+#[test]
+fn open_sockets_do_not_hold_workers() {
+    let addr = echo_server_with(1, ServerConfig::default());
+    let (_socket, head) = upgrade(addr);
+    assert!(head.starts_with("HTTP/1.1 101"));
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.ends_with("alive"));
+}
+
+#[test]
+fn refuses_upgrades_beyond_the_limit() {
+    let config = ServerConfig {
+        max_websockets: 1,
+        ..ServerConfig::default()
+    };
+    let addr = echo_server_with(2, config);
+    let (_first, head) = upgrade(addr);
+    assert!(head.starts_with("HTTP/1.1 101"));
+
+    let (_second, head) = upgrade(addr);
+    assert!(head.starts_with("HTTP/1.1 503"));
+}